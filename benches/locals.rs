@@ -0,0 +1,67 @@
+// Compares running a script with variables resolved to slots against looking every
+// variable up by name in the environment.
+//
+// Run with 'cargo bench --bench locals'.
+
+#![allow(clippy::needless_return)]
+
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use copper::codegen::CopperGen;
+use copper::vm::VM;
+
+const SCRIPT: &str = "
+var total = 0;
+
+func add_up(count: int): int {
+    var sum = 0;
+    for i: int = 0; i < count; i += 1 {
+        var a = i;
+        var b = a * 2;
+        var c = b - a;
+        sum += a + b + c;
+    }
+    return sum;
+}
+
+for i: int = 0; i < 40; i += 1 {
+    var x = 1;
+    var y = 2;
+    var z = 3;
+
+    for j: int = 0; j < 40; j += 1 {
+        var w = x + y + z;
+        total = total + w + j;
+    }
+
+    total = total + add_up(20);
+}
+";
+
+const RUNS: u32 = 10;
+
+fn time_script(resolve_variables: bool) -> Duration {
+    let mut gen = CopperGen::new();
+    gen.resolve_variables = resolve_variables;
+    gen.add_content(SCRIPT);
+    let chunk = Rc::new(gen.generate_chunk(Vec::new()));
+
+    let start = Instant::now();
+
+    for _ in 0..RUNS {
+        let mut vm = VM::new(Rc::clone(&chunk));
+        vm.interpret().unwrap();
+    }
+
+    return start.elapsed() / RUNS;
+}
+
+fn main() {
+    let by_name = time_script(false);
+    let by_slot = time_script(true);
+
+    println!("looked up by name: {:?} per run", by_name);
+    println!("resolved to slots: {:?} per run", by_slot);
+    println!("speedup: {:.2}x", by_name.as_secs_f64() / by_slot.as_secs_f64());
+}
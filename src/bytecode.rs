@@ -0,0 +1,377 @@
+use crate::chunk::{Chunk, OpCode, NO_FILE};
+use crate::value::ClassType;
+
+// The byte every instruction starts with, its operands follow in little endian.
+// Names, constants, paths and types are u32 indices into the chunk's pools, slots are u16
+// and positions are u32 offsets into the bytecode.
+pub const RETURN: u8 = 0;
+pub const END_SCRIPT: u8 = 1;
+pub const PUSH: u8 = 2;
+pub const POP: u8 = 3;
+pub const ADD: u8 = 4;
+pub const SUB: u8 = 5;
+pub const MUL: u8 = 6;
+pub const DIV: u8 = 7;
+pub const CALL: u8 = 8;
+pub const TRANSFORM_TO_TYPE: u8 = 9;
+pub const CMP_LESS: u8 = 10;
+pub const CMP_LESS_EQUAL: u8 = 11;
+pub const CMP_GREATER: u8 = 12;
+pub const CMP_GREATER_EQUAL: u8 = 13;
+pub const CMP_EQUAL: u8 = 14;
+pub const CMP_NOT_EQUAL: u8 = 15;
+pub const CMP_AND: u8 = 16;
+pub const CMP_OR: u8 = 17;
+pub const JMP: u8 = 18;
+pub const JMP_IF_FALSE: u8 = 19;
+pub const NEGATE: u8 = 20;
+pub const NOT: u8 = 21;
+pub const ARGUMENT_STORE: u8 = 22;
+pub const STORE: u8 = 23;
+pub const INFER_STORE: u8 = 24;
+pub const LOAD: u8 = 25;
+pub const ASSIGN: u8 = 26;
+pub const NEW_STRUCT: u8 = 27;
+pub const STRUCT_GET: u8 = 28;
+pub const STRUCT_SET: u8 = 29;
+pub const STRUCT_SET_BY_INDEX: u8 = 30;
+pub const ARGUMENT_LOCAL: u8 = 31;
+pub const DEFINE_LOCAL: u8 = 32;
+pub const INFER_LOCAL: u8 = 33;
+pub const LOAD_LOCAL: u8 = 34;
+pub const STORE_LOCAL: u8 = 35;
+pub const DEFINE_GLOBAL: u8 = 36;
+pub const INFER_GLOBAL: u8 = 37;
+pub const LOAD_GLOBAL: u8 = 38;
+pub const STORE_GLOBAL: u8 = 39;
+pub const POP_TO_CALL: u8 = 40;
+pub const START_SCOPE: u8 = 41;
+pub const END_SCOPE: u8 = 42;
+pub const TRY_START: u8 = 43;
+pub const TRY_END: u8 = 44;
+pub const THROW: u8 = 45;
+pub const YIELD: u8 = 46;
+pub const MAKE_COROUTINE: u8 = 47;
+pub const RESUME: u8 = 48;
+pub const YIELD_VALUE: u8 = 49;
+pub const FOR_ITER: u8 = 50;
+pub const IS_FINISHED: u8 = 51;
+
+pub fn read_u16(bytes: &[u8], offset: usize) -> u16 {
+    return u16::from_le_bytes([bytes[offset], bytes[offset+1]]);
+}
+
+pub fn read_u32(bytes: &[u8], offset: usize) -> u32 {
+    return u32::from_le_bytes([bytes[offset], bytes[offset+1], bytes[offset+2], bytes[offset+3]]);
+}
+
+impl Chunk {
+    pub fn add_type(&mut self, ctype: &ClassType) -> u32 {
+        if let Some(index) = self.types.iter().position(|existing| existing == ctype) {
+            return index as u32;
+        }
+
+        self.types.push(ctype.clone());
+        return (self.types.len() - 1) as u32;
+    }
+
+    // Encodes the ops into the bytecode the vm runs. Positions in the ops are indices of
+    // other ops, so every op's offset is worked out first to turn them into byte offsets.
+    pub fn encode(&mut self) {
+        let code = std::mem::take(&mut self.code);
+
+        let mut offsets = Vec::with_capacity(code.len() + 1);
+        let mut scratch = Vec::new();
+        let mut length = 0;
+
+        for op in &code {
+            offsets.push(length);
+            scratch.clear();
+            self.encode_op(op, &[], &mut scratch);
+            length += scratch.len();
+        }
+
+        // Jumping to the end of the code is allowed, so it gets an offset as well.
+        offsets.push(length);
+
+        let mut bytecode = Vec::with_capacity(length);
+        for op in &code {
+            self.encode_op(op, &offsets, &mut bytecode);
+        }
+
+        self.bytecode = bytecode;
+        self.offsets = offsets;
+        self.code = code;
+    }
+
+    // Positions are left as 0 when there aren't any offsets yet, only the size matters then.
+    fn encode_op(&mut self, op: &OpCode, offsets: &[usize], bytes: &mut Vec<u8>) {
+        let position = |at: usize| -> u32 {
+            return match offsets.get(at) {
+                Some(offset) => *offset as u32,
+                None => 0,
+            }
+        };
+
+        match op {
+            OpCode::Return => bytes.push(RETURN),
+            OpCode::EndScript => bytes.push(END_SCRIPT),
+            OpCode::Push(index) => {
+                bytes.push(PUSH);
+                bytes.extend_from_slice(&index.to_le_bytes());
+            },
+            OpCode::Pop => bytes.push(POP),
+            OpCode::Add => bytes.push(ADD),
+            OpCode::Sub => bytes.push(SUB),
+            OpCode::Mul => bytes.push(MUL),
+            OpCode::Div => bytes.push(DIV),
+            OpCode::Call(index) => {
+                bytes.push(CALL);
+                bytes.extend_from_slice(&index.to_le_bytes());
+            },
+            OpCode::TransformToType(ctype) => {
+                bytes.push(TRANSFORM_TO_TYPE);
+                bytes.extend_from_slice(&self.add_type(ctype).to_le_bytes());
+            },
+            OpCode::CmpLess => bytes.push(CMP_LESS),
+            OpCode::CmpLessEqual => bytes.push(CMP_LESS_EQUAL),
+            OpCode::CmpGreater => bytes.push(CMP_GREATER),
+            OpCode::CmpGreaterEqual => bytes.push(CMP_GREATER_EQUAL),
+            OpCode::CmpEqual => bytes.push(CMP_EQUAL),
+            OpCode::CmpNotEqual => bytes.push(CMP_NOT_EQUAL),
+            OpCode::CmpAnd => bytes.push(CMP_AND),
+            OpCode::CmpOr => bytes.push(CMP_OR),
+            OpCode::Jmp(at) => {
+                bytes.push(JMP);
+                bytes.extend_from_slice(&position(*at).to_le_bytes());
+            },
+            OpCode::JmpIfFalse(at) => {
+                bytes.push(JMP_IF_FALSE);
+                bytes.extend_from_slice(&position(*at).to_le_bytes());
+            },
+            OpCode::Negate => bytes.push(NEGATE),
+            OpCode::Not => bytes.push(NOT),
+            OpCode::ArgumentStore(name, ctype) => {
+                bytes.push(ARGUMENT_STORE);
+                bytes.extend_from_slice(&name.to_le_bytes());
+                bytes.extend_from_slice(&self.add_type(ctype).to_le_bytes());
+            },
+            OpCode::Store(name, ctype) => {
+                bytes.push(STORE);
+                bytes.extend_from_slice(&name.to_le_bytes());
+                bytes.extend_from_slice(&self.add_type(ctype).to_le_bytes());
+            },
+            OpCode::InferStore(name) => {
+                bytes.push(INFER_STORE);
+                bytes.extend_from_slice(&name.to_le_bytes());
+            },
+            OpCode::Load(name) => {
+                bytes.push(LOAD);
+                bytes.extend_from_slice(&name.to_le_bytes());
+            },
+            OpCode::Assign(name) => {
+                bytes.push(ASSIGN);
+                bytes.extend_from_slice(&name.to_le_bytes());
+            },
+            OpCode::NewStruct(name) => {
+                bytes.push(NEW_STRUCT);
+                bytes.extend_from_slice(&name.to_le_bytes());
+            },
+            OpCode::StructGet(name) => {
+                bytes.push(STRUCT_GET);
+                bytes.extend_from_slice(&name.to_le_bytes());
+            },
+            OpCode::StructSet(path) => {
+                bytes.push(STRUCT_SET);
+                bytes.extend_from_slice(&path.to_le_bytes());
+            },
+            OpCode::StructSetByIndex(index) => {
+                bytes.push(STRUCT_SET_BY_INDEX);
+                bytes.extend_from_slice(&(*index as u32).to_le_bytes());
+            },
+            OpCode::ArgumentLocal(slot, name, ctype) => {
+                bytes.push(ARGUMENT_LOCAL);
+                bytes.extend_from_slice(&slot.to_le_bytes());
+                bytes.extend_from_slice(&name.to_le_bytes());
+                bytes.extend_from_slice(&self.add_type(ctype).to_le_bytes());
+            },
+            OpCode::DefineLocal(slot, name, ctype) => {
+                bytes.push(DEFINE_LOCAL);
+                bytes.extend_from_slice(&slot.to_le_bytes());
+                bytes.extend_from_slice(&name.to_le_bytes());
+                bytes.extend_from_slice(&self.add_type(ctype).to_le_bytes());
+            },
+            OpCode::InferLocal(slot, name) => {
+                bytes.push(INFER_LOCAL);
+                bytes.extend_from_slice(&slot.to_le_bytes());
+                bytes.extend_from_slice(&name.to_le_bytes());
+            },
+            OpCode::LoadLocal(slot) => {
+                bytes.push(LOAD_LOCAL);
+                bytes.extend_from_slice(&slot.to_le_bytes());
+            },
+            OpCode::StoreLocal(slot) => {
+                bytes.push(STORE_LOCAL);
+                bytes.extend_from_slice(&slot.to_le_bytes());
+            },
+            OpCode::DefineGlobal(index, ctype) => {
+                bytes.push(DEFINE_GLOBAL);
+                bytes.extend_from_slice(&index.to_le_bytes());
+                bytes.extend_from_slice(&self.add_type(ctype).to_le_bytes());
+            },
+            OpCode::InferGlobal(index) => {
+                bytes.push(INFER_GLOBAL);
+                bytes.extend_from_slice(&index.to_le_bytes());
+            },
+            OpCode::LoadGlobal(index) => {
+                bytes.push(LOAD_GLOBAL);
+                bytes.extend_from_slice(&index.to_le_bytes());
+            },
+            OpCode::StoreGlobal(index) => {
+                bytes.push(STORE_GLOBAL);
+                bytes.extend_from_slice(&index.to_le_bytes());
+            },
+            OpCode::PopToCall => bytes.push(POP_TO_CALL),
+            OpCode::StartScope => bytes.push(START_SCOPE),
+            OpCode::EndScope => bytes.push(END_SCOPE),
+            OpCode::TryStart(at) => {
+                bytes.push(TRY_START);
+                bytes.extend_from_slice(&position(*at).to_le_bytes());
+            },
+            OpCode::TryEnd => bytes.push(TRY_END),
+            OpCode::Throw => bytes.push(THROW),
+            OpCode::Yield => bytes.push(YIELD),
+            OpCode::MakeCoroutine => bytes.push(MAKE_COROUTINE),
+            OpCode::Resume => bytes.push(RESUME),
+            OpCode::YieldValue => bytes.push(YIELD_VALUE),
+            OpCode::ForIter(at) => {
+                bytes.push(FOR_ITER);
+                bytes.extend_from_slice(&position(*at).to_le_bytes());
+            },
+            OpCode::IsFinished => bytes.push(IS_FINISHED),
+        }
+    }
+
+    // Decodes the instruction at the offset, giving it back with the offset of the next one.
+    // Positions in the decoded op are byte offsets rather than indices of ops.
+    pub fn decode(&self, offset: usize) -> (OpCode, usize) {
+        return match self.try_decode(offset) {
+            Ok(decoded) => decoded,
+            Err(message) => panic!("{}", message),
+        }
+    }
+
+    // Same as 'decode', but for bytecode that can't be trusted, like bytecode loaded from a file.
+    pub fn try_decode(&self, offset: usize) -> Result<(OpCode, usize), String> {
+        let bytes = &self.bytecode;
+        let operand = |at: usize, size: usize| -> Result<usize, String> {
+            if offset + at + size > bytes.len() {
+                return Err(format!("The instruction at offset {} is cut off", offset));
+            }
+
+            return Ok(offset + at);
+        };
+        let u16_at = |at: usize| -> Result<u16, String> { Ok(read_u16(bytes, operand(at, 2)?)) };
+        let u32_at = |at: usize| -> Result<u32, String> { Ok(read_u32(bytes, operand(at, 4)?)) };
+        let type_at = |at: usize| -> Result<ClassType, String> {
+            let index = u32_at(at)? as usize;
+            return match self.types.get(index) {
+                Some(ctype) => Ok(ctype.clone()),
+                None => Err(format!("The instruction at offset {} uses type {} which doesn't exist", offset, index)),
+            }
+        };
+
+        let byte = match bytes.get(offset) {
+            Some(byte) => *byte,
+            None => return Err(format!("There isn't an instruction at offset {}", offset)),
+        };
+
+        let (op, size) = match byte {
+            RETURN => (OpCode::Return, 1),
+            END_SCRIPT => (OpCode::EndScript, 1),
+            PUSH => (OpCode::Push(u32_at(1)?), 5),
+            POP => (OpCode::Pop, 1),
+            ADD => (OpCode::Add, 1),
+            SUB => (OpCode::Sub, 1),
+            MUL => (OpCode::Mul, 1),
+            DIV => (OpCode::Div, 1),
+            CALL => (OpCode::Call(u32_at(1)?), 5),
+            TRANSFORM_TO_TYPE => (OpCode::TransformToType(type_at(1)?), 5),
+            CMP_LESS => (OpCode::CmpLess, 1),
+            CMP_LESS_EQUAL => (OpCode::CmpLessEqual, 1),
+            CMP_GREATER => (OpCode::CmpGreater, 1),
+            CMP_GREATER_EQUAL => (OpCode::CmpGreaterEqual, 1),
+            CMP_EQUAL => (OpCode::CmpEqual, 1),
+            CMP_NOT_EQUAL => (OpCode::CmpNotEqual, 1),
+            CMP_AND => (OpCode::CmpAnd, 1),
+            CMP_OR => (OpCode::CmpOr, 1),
+            JMP => (OpCode::Jmp(u32_at(1)? as usize), 5),
+            JMP_IF_FALSE => (OpCode::JmpIfFalse(u32_at(1)? as usize), 5),
+            NEGATE => (OpCode::Negate, 1),
+            NOT => (OpCode::Not, 1),
+            ARGUMENT_STORE => (OpCode::ArgumentStore(u32_at(1)?, type_at(5)?), 9),
+            STORE => (OpCode::Store(u32_at(1)?, type_at(5)?), 9),
+            INFER_STORE => (OpCode::InferStore(u32_at(1)?), 5),
+            LOAD => (OpCode::Load(u32_at(1)?), 5),
+            ASSIGN => (OpCode::Assign(u32_at(1)?), 5),
+            NEW_STRUCT => (OpCode::NewStruct(u32_at(1)?), 5),
+            STRUCT_GET => (OpCode::StructGet(u32_at(1)?), 5),
+            STRUCT_SET => (OpCode::StructSet(u32_at(1)?), 5),
+            STRUCT_SET_BY_INDEX => (OpCode::StructSetByIndex(u32_at(1)? as usize), 5),
+            ARGUMENT_LOCAL => (OpCode::ArgumentLocal(u16_at(1)?, u32_at(3)?, type_at(7)?), 11),
+            DEFINE_LOCAL => (OpCode::DefineLocal(u16_at(1)?, u32_at(3)?, type_at(7)?), 11),
+            INFER_LOCAL => (OpCode::InferLocal(u16_at(1)?, u32_at(3)?), 7),
+            LOAD_LOCAL => (OpCode::LoadLocal(u16_at(1)?), 3),
+            STORE_LOCAL => (OpCode::StoreLocal(u16_at(1)?), 3),
+            DEFINE_GLOBAL => (OpCode::DefineGlobal(u32_at(1)?, type_at(5)?), 9),
+            INFER_GLOBAL => (OpCode::InferGlobal(u32_at(1)?), 5),
+            LOAD_GLOBAL => (OpCode::LoadGlobal(u32_at(1)?), 5),
+            STORE_GLOBAL => (OpCode::StoreGlobal(u32_at(1)?), 5),
+            POP_TO_CALL => (OpCode::PopToCall, 1),
+            START_SCOPE => (OpCode::StartScope, 1),
+            END_SCOPE => (OpCode::EndScope, 1),
+            TRY_START => (OpCode::TryStart(u32_at(1)? as usize), 5),
+            TRY_END => (OpCode::TryEnd, 1),
+            THROW => (OpCode::Throw, 1),
+            YIELD => (OpCode::Yield, 1),
+            MAKE_COROUTINE => (OpCode::MakeCoroutine, 1),
+            RESUME => (OpCode::Resume, 1),
+            YIELD_VALUE => (OpCode::YieldValue, 1),
+            FOR_ITER => (OpCode::ForIter(u32_at(1)? as usize), 5),
+            IS_FINISHED => (OpCode::IsFinished, 1),
+            byte => return Err(format!("Unknown opcode byte '{}' at offset {}", byte, offset)),
+        };
+
+        return Ok((op, offset + size));
+    }
+
+    // Gives back the index of the instruction that the byte at the offset belongs to.
+    fn op_at(&self, offset: usize) -> usize {
+        return match self.offsets.binary_search(&offset) {
+            Ok(index) => index,
+            Err(index) => index - 1,
+        };
+    }
+
+    // Gives back the line of the instruction that the byte at the offset belongs to.
+    pub fn line_at(&self, offset: usize) -> usize {
+        return match self.lines.get(self.op_at(offset)) {
+            Some(line) => *line,
+            None => 0,
+        }
+    }
+
+    // Gives back the file of the instruction that the byte at the offset belongs to, as an
+    // index into 'sources'.
+    pub fn file_index_at(&self, offset: usize) -> u32 {
+        return match self.files.get(self.op_at(offset)) {
+            Some(file) => *file,
+            None => NO_FILE,
+        }
+    }
+
+    // Gives back the name of the file of the instruction, if the chunk was generated from files.
+    pub fn file_at(&self, offset: usize) -> Option<&str> {
+        return self.sources.get(self.file_index_at(offset) as usize).map(|source| source.as_str());
+    }
+}
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+
 use crate::{environment::Environment, value::{Value, ClassType}};
 
 #[derive(Debug, Clone)]
@@ -5,14 +7,16 @@ pub enum OpCode {
     Return,
     EndScript,
 
-    Push(Value),
+    // Pushes a value from the constant pool.
+    Push(u32),
     Pop,
     Add,
     Sub,
     Mul,
     Div,
 
-    CallFunc(String),
+    // Calls the function at the index in the chunk's function table.
+    Call(u32),
     TransformToType(ClassType),
 
     CmpLess,
@@ -30,65 +34,259 @@ pub enum OpCode {
     Negate,
     Not,
 
+    // Names are indices into the chunk's interned names.
     // Used to store arguments from call stack.
-    ArgumentStore(String, ClassType),
-    Store(String, ClassType),
-    InferStore(String),
-    Load(String),
-    Assign(String),
-    NewStruct(String),
-    StructGet(String),
-    StructSet(String, Vec<String>),
+    ArgumentStore(u32, ClassType),
+    Store(u32, ClassType),
+    InferStore(u32),
+    Load(u32),
+    Assign(u32),
+    NewStruct(u32),
+    StructGet(u32),
+    // Sets the field at the interned path on the struct at the top of the stack to the value below it.
+    StructSet(u32),
     StructSetByIndex(usize),
 
+    // Variables resolved when generating code, locals use slots relative to the function
+    // they are in and globals index the chunk's global names.
+    ArgumentLocal(u16, u32, ClassType),
+    DefineLocal(u16, u32, ClassType),
+    InferLocal(u16, u32),
+    LoadLocal(u16),
+    StoreLocal(u16),
+    DefineGlobal(u32, ClassType),
+    InferGlobal(u32),
+    LoadGlobal(u32),
+    StoreGlobal(u32),
+
     PopToCall,
 
     StartScope,
     EndScope,
+
+    // Registers a handler that jumps to the given position when something is thrown.
+    TryStart(usize),
+    TryEnd,
+    Throw,
+
+    // Stops running and hands control back to the host.
+    Yield,
+
+    // Starts a generator's body, turning the call into a coroutine that's handed back instead.
+    MakeCoroutine,
+    // Switches to the coroutine on top of the stack until it yields or finishes.
+    Resume,
+    // Switches back to whatever resumed the coroutine, handing it the value on top of the stack.
+    YieldValue,
+    // Resumes the coroutine on top of the stack for the next value of a 'for' loop, jumping to
+    // the position once it's finished.
+    ForIter(usize),
+    IsFinished,
 }
 
+// An entry in the function table, calls go straight to it by index.
+#[derive(Clone)]
+pub enum Function {
+    Script(String, ClassType, usize, usize),
+    // Natives that suspend hand control back to the host after they return.
+    Native(String, usize, &'static dyn Fn(Vec<Value>) -> Value, bool),
+    // Called before anything was bound to the name, filled in once it is.
+    Unbound(String),
+}
+
+impl Function {
+    pub fn name(&self) -> &str {
+        return match self {
+            Function::Script(name, _, _, _) => name,
+            Function::Native(name, _, _, _) => name,
+            Function::Unbound(name) => name,
+        }
+    }
+}
+
+// The file of ops that weren't generated from a file.
+pub const NO_FILE: u32 = u32::MAX;
+
 #[derive(Clone)]
 pub struct Chunk {
+    // The ops code is generated into, 'encode' turns them into the bytecode the vm runs.
     pub code: Vec<OpCode>,
     pub lines: Vec<usize>,
-    pub functions: Environment,
+    // The file each op was generated from, as an index into 'sources'.
+    pub files: Vec<u32>,
+    // The names of the files the chunk was generated from, empty when it was only given source.
+    pub sources: Vec<String>,
+    // The file ops are being written for, 'NO_FILE' until one is started.
+    pub current_file: u32,
+    pub bytecode: Vec<u8>,
+    // Byte offset of each op in the bytecode, with one more for the end.
+    pub offsets: Vec<usize>,
+    pub structs: Environment,
+    pub functions: Vec<Function>,
+    pub globals: Vec<String>,
+
+    pub constants: Vec<Value>,
+    pub names: Vec<String>,
+    pub paths: Vec<Vec<String>>,
+    pub types: Vec<ClassType>,
+    name_indices: HashMap<String, u32>,
+    function_indices: HashMap<String, u32>,
 }
 
 impl Chunk {
     pub fn new() -> Chunk {
-        Chunk { code: Vec::new(), lines: Vec::new(), functions: Environment::new() }
+        Chunk { 
+            code: Vec::new(), 
+            lines: Vec::new(), 
+            files: Vec::new(),
+            sources: Vec::new(),
+            current_file: NO_FILE,
+            bytecode: Vec::new(),
+            offsets: Vec::new(),
+            structs: Environment::new(), 
+            functions: Vec::new(),
+            globals: Vec::new(),
+            constants: Vec::new(),
+            names: Vec::new(),
+            paths: Vec::new(),
+            types: Vec::new(),
+            name_indices: HashMap::new(),
+            function_indices: HashMap::new(),
+        }
     }
 
     pub fn erase(&mut self) {
-        self.code = Vec::new();
-        self.lines = Vec::new();
-        self.functions = Environment::new();
+        *self = Chunk::new();
+    }
+
+    // Gives back the index of the name, adding it if it hasn't been seen before.
+    pub fn intern(&mut self, name: &str) -> u32 {
+        if let Some(index) = self.name_indices.get(name) {
+            return *index;
+        }
+
+        let index = self.names.len() as u32;
+        self.names.push(name.to_string());
+        self.name_indices.insert(name.to_string(), index);
+
+        return index;
+    }
+
+    pub fn add_constant(&mut self, val: Value) -> u32 {
+        if let Some(index) = self.constants.iter().position(|constant| *constant == val) {
+            return index as u32;
+        }
+
+        self.constants.push(val);
+        return (self.constants.len() - 1) as u32;
+    }
+
+    pub fn add_path(&mut self, path: Vec<String>) -> u32 {
+        if let Some(index) = self.paths.iter().position(|existing| *existing == path) {
+            return index as u32;
+        }
+
+        self.paths.push(path);
+        return (self.paths.len() - 1) as u32;
+    }
+
+    pub fn name(&self, index: u32) -> &str {
+        return &self.names[index as usize];
+    }
+
+    // Gives back where the function is in the table, leaving it unbound if it hasn't been seen before.
+    pub fn function_index(&mut self, name: &str) -> u32 {
+        if let Some(index) = self.function_indices.get(name) {
+            return *index;
+        }
+
+        let index = self.functions.len() as u32;
+        self.functions.push(Function::Unbound(name.to_string()));
+        self.function_indices.insert(name.to_string(), index);
+
+        return index;
+    }
+
+    // Looks up a function without adding it to the table when it isn't there.
+    pub fn find_function(&self, name: &str) -> Option<u32> {
+        return self.function_indices.get(name).copied();
+    }
+
+    pub fn unbound_functions(&self) -> Vec<&str> {
+        return self.functions.iter()
+            .filter(|function| matches!(function, Function::Unbound(_)))
+            .map(|function| function.name())
+            .collect();
+    }
+
+    fn bind(&mut self, function: Function) {
+        let index = self.function_index(function.name()) as usize;
+
+        if !matches!(self.functions[index], Function::Unbound(_)) {
+            panic!("Cannot assign a function with name '{}' as one already exists.", function.name());
+        }
+
+        self.functions[index] = function;
+    }
+
+    pub fn source_index(&self, file: &str) -> Option<u32> {
+        return self.sources.iter().position(|source| source == file).map(|index| index as u32);
+    }
+
+    // Ops written from now on are from the file.
+    pub fn start_file(&mut self, file: &str) {
+        self.current_file = match self.source_index(file) {
+            Some(index) => index,
+            None => {
+                self.sources.push(file.to_string());
+                (self.sources.len() - 1) as u32
+            }
+        };
+    }
+
+    pub fn global_index(&self, name: &str) -> Option<usize> {
+        return self.globals.iter().position(|global| global == name);
+    }
+
+    // The type the script declares a global with, none when its type is inferred from its value.
+    pub fn global_type(&self, index: usize) -> Option<&ClassType> {
+        return self.code.iter().find_map(|op| match op {
+            OpCode::DefineGlobal(global, ctype) if *global as usize == index => Some(Some(ctype)),
+            OpCode::InferGlobal(global) if *global as usize == index => Some(None),
+            _ => None,
+        }).flatten();
     }
 
     // Base function that every opcode can implement from.
     pub fn write(&mut self, byte: OpCode, line: usize) {
         self.code.push(byte);
         self.lines.push(line);
+        self.files.push(self.current_file);
     }
 
     pub fn write_store(&mut self, name: String, ctype: ClassType, line: usize) {
+        let name = self.intern(&name);
         self.write(OpCode::Store(name, ctype), line);
     }
 
     pub fn write_store_infer(&mut self, name: String, line: usize) {
+        let name = self.intern(&name);
         self.write(OpCode::InferStore(name), line);
     }
 
     pub fn write_argument_store(&mut self, name: String, ctype: ClassType, line: usize) {
+        let name = self.intern(&name);
         self.write(OpCode::ArgumentStore(name, ctype), line);
     }
 
     pub fn write_load(&mut self, name: String, line: usize) {
+        let name = self.intern(&name);
         self.write(OpCode::Load(name), line);
     }
 
     pub fn write_constant(&mut self, val: Value, line: usize) {
-        self.write(OpCode::Push(val.clone()), line);
+        let index = self.add_constant(val);
+        self.write(OpCode::Push(index), line);
     }
 
     pub fn write_jmp(&mut self, jmp_to_point: usize, line: usize) {
@@ -100,7 +298,8 @@ impl Chunk {
     }
 
     pub fn write_call(&mut self, name: String, line: usize) {
-        self.write(OpCode::CallFunc(name), line);
+        let index = self.function_index(&name);
+        self.write(OpCode::Call(index), line);
     }
 
     pub fn transform_to_type(&mut self, ctype: ClassType, line: usize) {
@@ -108,80 +307,109 @@ impl Chunk {
     } 
 
     pub fn bind_function(&mut self, name: String,  ctype: ClassType, arg_count: usize, bytecode_position: usize) {
-        self.functions.add_function(name, ctype, arg_count, bytecode_position);
+        self.bind(Function::Script(name, ctype, arg_count, bytecode_position));
     }
 
     pub fn bind_native_function(&mut self, name: String, arg_count: usize, func: &'static dyn Fn(Vec<Value>) -> Value) {
-        self.functions.add_native_function(name, arg_count, func);
+        self.bind(Function::Native(name, arg_count, func, false));
+    }
+
+    pub fn bind_suspending_native_function(&mut self, name: String, arg_count: usize, func: &'static dyn Fn(Vec<Value>) -> Value) {
+        self.bind(Function::Native(name, arg_count, func, true));
     }
 }
 
 
 // Sets up disassembling of instructions in a more understandable manner.
 impl Chunk {
-    fn match_print(&mut self, op: &OpCode) {
-        match op {
-            OpCode::Return => print!("return"),
-            OpCode::EndScript => print!("end script"),
-            OpCode::Push(value) => print!("push '{}'   {}", value.string_s(), value.type_to_string()),
-            OpCode::Pop => print!("pop"),
-            OpCode::Add => print!("add"),
-            OpCode::Sub => print!("sub"),
-            OpCode::Mul => print!("mul"),
-            OpCode::Div => print!("div"),
-            OpCode::CallFunc(func_name) => print!("call function '{}'", func_name),
-            OpCode::CmpLess => print!("[<]"),
-            OpCode::CmpLessEqual => print!("[<=]"),
-            OpCode::CmpGreater => print!("[>]"),
-            OpCode::CmpGreaterEqual => print!("[>=]"),
-            OpCode::CmpEqual => print!("[==]"),
-            OpCode::CmpNotEqual => print!("[!=]"),
-            OpCode::CmpAnd => print!("[&&]"),
-            OpCode::CmpOr => print!("[||]"),
-            OpCode::Jmp(at) => print!("jump at '{}'", at),
-            OpCode::JmpIfFalse(at) => print!("jump if false at '{}'", at),
-            OpCode::Negate => print!("negate"),
-            OpCode::ArgumentStore(name, ctype) => print!("argument store '{}'   {:?}", name, ctype),
-            OpCode::Store(name, ctype) => print!("store '{}'   {:?}", name, ctype),
-            OpCode::InferStore(x) => print!("infer store '{}'", x),
-            OpCode::Load(name) => print!("load '{}'", name),
-            OpCode::Assign(name) => print!("assign '{}'", name),
-            OpCode::PopToCall => print!("pop from stack to call stack"),
-            OpCode::StartScope => print!("start scope"),
-            OpCode::Not => print!("not"),
-            OpCode::EndScope => print!("end scope"),
-            OpCode::TransformToType(c) => print!("transform to {:?}", c),
-            OpCode::StructGet(name) => print!("struct get {}", name),
-            OpCode::StructSet(name, sets) => print!("struct set {}, {:?}", name, sets),
-            OpCode::StructSetByIndex(index) => print!("set {} index of struct", index),
-            OpCode::NewStruct(name) => print!("new set of structure {}", name),
-            // _ => print!("[Unknown opcode]"),
+    // Describes the op the way it's shown when disassembling.
+    pub fn describe(&self, op: &OpCode) -> String {
+        return match op {
+            OpCode::Return => "return".to_string(),
+            OpCode::EndScript => "end script".to_string(),
+            OpCode::Push(index) => {
+                let value = &self.constants[*index as usize];
+                format!("push '{}'   {}", value.string_s(), value.type_to_string())
+            },
+            OpCode::Pop => "pop".to_string(),
+            OpCode::Add => "add".to_string(),
+            OpCode::Sub => "sub".to_string(),
+            OpCode::Mul => "mul".to_string(),
+            OpCode::Div => "div".to_string(),
+            OpCode::Call(index) => format!("call function '{}'", self.functions[*index as usize].name()),
+            OpCode::CmpLess => "[<]".to_string(),
+            OpCode::CmpLessEqual => "[<=]".to_string(),
+            OpCode::CmpGreater => "[>]".to_string(),
+            OpCode::CmpGreaterEqual => "[>=]".to_string(),
+            OpCode::CmpEqual => "[==]".to_string(),
+            OpCode::CmpNotEqual => "[!=]".to_string(),
+            OpCode::CmpAnd => "[&&]".to_string(),
+            OpCode::CmpOr => "[||]".to_string(),
+            OpCode::Jmp(at) => format!("jump at '{}'", at),
+            OpCode::JmpIfFalse(at) => format!("jump if false at '{}'", at),
+            OpCode::Negate => "negate".to_string(),
+            OpCode::ArgumentStore(name, ctype) => format!("argument store '{}'   {:?}", self.name(*name), ctype),
+            OpCode::Store(name, ctype) => format!("store '{}'   {:?}", self.name(*name), ctype),
+            OpCode::InferStore(x) => format!("infer store '{}'", self.name(*x)),
+            OpCode::Load(name) => format!("load '{}'", self.name(*name)),
+            OpCode::Assign(name) => format!("assign '{}'", self.name(*name)),
+            OpCode::PopToCall => "pop from stack to call stack".to_string(),
+            OpCode::StartScope => "start scope".to_string(),
+            OpCode::Not => "not".to_string(),
+            OpCode::EndScope => "end scope".to_string(),
+            OpCode::TransformToType(c) => format!("transform to {:?}", c),
+            OpCode::StructGet(name) => format!("struct get {}", self.name(*name)),
+            OpCode::StructSet(sets) => format!("struct set {:?}", self.paths[*sets as usize]),
+            OpCode::StructSetByIndex(index) => format!("set {} index of struct", index),
+            OpCode::NewStruct(name) => format!("new set of structure {}", self.name(*name)),
+            OpCode::ArgumentLocal(slot, name, ctype) => format!("argument local [{}] '{}'   {:?}", slot, self.name(*name), ctype),
+            OpCode::DefineLocal(slot, name, ctype) => format!("define local [{}] '{}'   {:?}", slot, self.name(*name), ctype),
+            OpCode::InferLocal(slot, name) => format!("infer local [{}] '{}'", slot, self.name(*name)),
+            OpCode::LoadLocal(slot) => format!("load local [{}]", slot),
+            OpCode::StoreLocal(slot) => format!("store local [{}]", slot),
+            OpCode::DefineGlobal(index, ctype) => format!("define global '{}'   {:?}", self.globals[*index as usize], ctype),
+            OpCode::InferGlobal(index) => format!("infer global '{}'", self.globals[*index as usize]),
+            OpCode::LoadGlobal(index) => format!("load global '{}'", self.globals[*index as usize]),
+            OpCode::StoreGlobal(index) => format!("store global '{}'", self.globals[*index as usize]),
+            OpCode::TryStart(at) => format!("try, catch at '{}'", at),
+            OpCode::TryEnd => "end try".to_string(),
+            OpCode::Throw => "throw".to_string(),
+            OpCode::Yield => "yield".to_string(),
+            OpCode::MakeCoroutine => "make coroutine".to_string(),
+            OpCode::Resume => "resume".to_string(),
+            OpCode::YieldValue => "yield value".to_string(),
+            OpCode::ForIter(at) => format!("for iter, exit at '{}'", at),
+            OpCode::IsFinished => "is finished".to_string(),
+            // _ => "[Unknown opcode]".to_string(),
         }
     }
 
-    fn simple_instruction(&mut self, offset: usize) -> usize {
-        self.match_print(&self.code[offset].clone());
-        println!();
-        
-        return offset + 1;
-    }
-
-    fn disassemble_instruction(&mut self, offset: usize) -> usize {
+    // Prints the op at the offset, giving back the offset of the op after it.
+    pub fn disassemble_instruction(&self, offset: usize) -> usize {
         print!("{:04} ", offset);
 
-        if offset > 0 && self.lines[offset] == self.lines[offset-1] {
+        let line = self.line_at(offset);
+        if offset > 0 && line == self.line_at(offset-1) {
             print!("   | ");
         } else {
-            print!("{:4} ", self.lines[offset]);
+            print!("{:4} ", line);
         }
 
-        return self.simple_instruction(offset);
+        let (op, next) = self.decode(offset);
+        println!("{}", self.describe(&op));
+
+        return next;
     } 
 
-    pub fn disassemble(&mut self) {
-        let mut offset: usize = 0;
-        while offset < self.code.len() {
+    pub fn disassemble(&self) {
+        self.disassemble_from(0);
+    }
+
+    // Disassembles everything after the offset, like the code added by the last 'generate_more'.
+    pub fn disassemble_from(&self, offset: usize) {
+        let mut offset = offset;
+        while offset < self.bytecode.len() {
             offset = self.disassemble_instruction(offset);
         }
     }
-}
\ No newline at end of file
+}
@@ -1,22 +1,81 @@
+use std::collections::HashMap;
+
 use crate::environment::CopperStruct;
 use crate::mini_macros::tokenizer::MacroExpander;
 use crate::parser::{AstStmt, AstExpr};
 use crate::tokens::Token;
 use crate::value::{ClassType, Value};
-use crate::{CopperParser, chunk::{Chunk, OpCode}};
+use crate::parser::CopperParser;
+use crate::chunk::{Chunk, OpCode};
+use crate::error::CompileError;
+
+// Holds the coroutine a 'for' loop takes its values from, the space keeps scripts from naming it.
+const FOR_ITERATOR: &str = "for iterator";
+
+// Keeps track of a 'try' statement that code is being generated inside of, so
+// returning out of it can drop its handler and run its 'finally' block.
+#[derive(Clone)]
+struct TryContext {
+    finally: Option<AstExpr>,
+    handler_active: bool,
+}
+
+// A block used as a value, like '{ return 5; }'. It's generated in place, and 'return' inside
+// of it jumps out to the end of the block with what's returned.
+struct BlockContext {
+    // Scope depth and number of 'try' statements from outside of the block.
+    scope_depth: usize,
+    try_depth: usize,
+    exits: Vec<usize>,
+}
+
+// What a function being generated puts aside from whatever it's declared in.
+struct FunctionState {
+    locals: Vec<LocalSlot>,
+    scope_depth: usize,
+    try_contexts: Vec<TryContext>,
+    block_contexts: Vec<BlockContext>,
+    in_generator: bool,
+}
+
+// A variable declared in the function being generated, its slot is where it is in the list.
+struct LocalSlot {
+    name: String,
+    depth: usize,
+}
 
 pub struct CopperGen {
     pub parser: CopperParser,
     pub current_line: usize,
     pub chunk: Chunk,
-    block_increment: usize,
+    // When false every variable is stored and looked up by name at runtime.
+    pub resolve_variables: bool,
+    // When false the chunk is left exactly as it was generated, which can help when reading disassembly.
+    pub optimize: bool,
+    // Set when something like an undefined function was found while generating.
+    pub had_error: bool,
+    // Every error found, in the order they were found.
+    pub errors: Vec<CompileError>,
+    // When false errors are only kept in 'errors' instead of also being printed.
+    pub print_errors: bool,
     files: Vec<String>,
     file_path: String,
     macro_expander: MacroExpander,
+    try_contexts: Vec<TryContext>,
+    block_contexts: Vec<BlockContext>,
+    locals: Vec<LocalSlot>,
+    scope_depth: usize,
+    function_depth: usize,
+    // Set while generating a generator's body, where 'yield' hands a value to whatever resumed it.
+    in_generator: bool,
+    // Functions that will be bound to the chunk after it is generated.
+    late_bound: Vec<String>,
+    // Where each function was first called, for reporting ones that are never defined.
+    call_lines: HashMap<String, usize>,
 }
 
 impl CopperGen {
-    fn recursive_get_names(&self, left: Box<AstExpr>, right: Box<AstExpr>) -> Vec<String> {
+    fn recursive_get_names(&self, left: AstExpr, right: AstExpr) -> Vec<String> {
         let mut stack = Vec::new();
 
         for i in self.recursive_get_name(left) {
@@ -30,18 +89,18 @@ impl CopperGen {
         return stack;
     }
 
-    fn recursive_get_name(&self, left: Box<AstExpr>) -> Vec<String> {
-        match *left {
+    fn recursive_get_name(&self, left: AstExpr) -> Vec<String> {
+        match left {
             AstExpr::Variable(name) => {
                 return vec![name];
             }
             AstExpr::StructCall(left, right) => {
                 let mut stack = Vec::new();
-                for i in self.recursive_get_name(left) {
+                for i in self.recursive_get_name(*left) {
                     stack.push(i);
                 }
 
-                for i in self.recursive_get_name(right) {
+                for i in self.recursive_get_name(*right) {
                     stack.push(i);
                 }
 
@@ -53,6 +112,147 @@ impl CopperGen {
         return Vec::new();
     }
 
+    fn begin_scope(&mut self) {
+        self.chunk.write(OpCode::StartScope, self.current_line);
+        self.scope_depth += 1;
+    }
+
+    fn end_scope(&mut self) {
+        self.chunk.write(OpCode::EndScope, self.current_line);
+
+        while self.locals.last().is_some_and(|local| local.depth >= self.scope_depth) {
+            self.locals.pop();
+        }
+
+        self.scope_depth -= 1;
+    }
+
+    // Functions get their own slots, so the locals of whatever they're declared in are put aside.
+    fn begin_function(&mut self) -> FunctionState {
+        self.function_depth += 1;
+        let state = FunctionState {
+            locals: std::mem::take(&mut self.locals),
+            scope_depth: self.scope_depth,
+            try_contexts: std::mem::take(&mut self.try_contexts),
+            block_contexts: std::mem::take(&mut self.block_contexts),
+            in_generator: self.in_generator,
+        };
+        self.scope_depth = 0;
+
+        return state;
+    }
+
+    fn end_function(&mut self, state: FunctionState) {
+        self.function_depth -= 1;
+        self.locals = state.locals;
+        self.scope_depth = state.scope_depth;
+        self.try_contexts = state.try_contexts;
+        self.block_contexts = state.block_contexts;
+        self.in_generator = state.in_generator;
+    }
+
+    fn resolve_local(&self, name: &str) -> Option<u16> {
+        return self.locals.iter().rposition(|local| local.name == name).map(|slot| slot as u16);
+    }
+
+    fn declare_local(&mut self, name: String) -> u16 {
+        // Declaring a variable again in the same scope reuses its slot.
+        if let Some(slot) = self.locals.iter().rposition(|local| local.name == name && local.depth == self.scope_depth) {
+            return slot as u16;
+        }
+
+        if self.locals.len() > u16::MAX as usize {
+            panic!("Cannot have more than {} locals in a function", u16::MAX as usize + 1);
+        }
+
+        self.locals.push(LocalSlot { name, depth: self.scope_depth });
+        return (self.locals.len() - 1) as u16;
+    }
+
+    fn declare_global(&mut self, name: String) -> u32 {
+        return match self.chunk.global_index(&name) {
+            Some(index) => index as u32,
+            None => {
+                self.chunk.globals.push(name);
+                (self.chunk.globals.len() - 1) as u32
+            }
+        }
+    }
+
+    // Stores the value on top of the stack into a new variable, a type of 'None' means it's inferred.
+    fn generate_declaration(&mut self, name: String, ctype: Option<ClassType>) {
+        if !self.resolve_variables {
+            match ctype {
+                Some(ctype) => self.chunk.write_store(name, ctype, self.current_line),
+                None => self.chunk.write_store_infer(name, self.current_line),
+            }
+        } else if self.function_depth == 0 && self.scope_depth == 0 {
+            let index = self.declare_global(name);
+
+            match ctype {
+                Some(ctype) => self.chunk.write(OpCode::DefineGlobal(index, ctype), self.current_line),
+                None => self.chunk.write(OpCode::InferGlobal(index), self.current_line),
+            }
+        } else {
+            let slot = self.declare_local(name.clone());
+            let name = self.chunk.intern(&name);
+
+            match ctype {
+                Some(ctype) => self.chunk.write(OpCode::DefineLocal(slot, name, ctype), self.current_line),
+                None => self.chunk.write(OpCode::InferLocal(slot, name), self.current_line),
+            }
+        }
+    }
+
+    fn generate_load(&mut self, name: String) {
+        if self.resolve_variables {
+            if let Some(slot) = self.resolve_local(&name) {
+                self.chunk.write(OpCode::LoadLocal(slot), self.current_line);
+                return;
+            }
+
+            if let Some(index) = self.chunk.global_index(&name) {
+                self.chunk.write(OpCode::LoadGlobal(index as u32), self.current_line);
+                return;
+            }
+        }
+
+        // Left to be found by name when it runs, like a global declared further down.
+        self.chunk.write_load(name, self.current_line);
+    }
+
+    fn generate_assign(&mut self, name: String) {
+        if self.resolve_variables {
+            if let Some(slot) = self.resolve_local(&name) {
+                self.chunk.write(OpCode::StoreLocal(slot), self.current_line);
+                return;
+            }
+
+            if let Some(index) = self.chunk.global_index(&name) {
+                self.chunk.write(OpCode::StoreGlobal(index as u32), self.current_line);
+                return;
+            }
+        }
+
+        let name = self.chunk.intern(&name);
+        self.chunk.write(OpCode::Assign(name), self.current_line);
+    }
+
+    // Assigns the value on top of the stack to a variable or a field of a struct.
+    fn generate_assign_target(&mut self, target: AstExpr) {
+        if let AstExpr::Variable(name) = target {
+            self.generate_assign(name);
+        } else if let AstExpr::StructCall(left, right) = target {
+            let mut stack = self.recursive_get_names(*left, *right);
+            let name = stack.remove(0);
+
+            self.generate_load(name.clone());
+            let path = self.chunk.add_path(stack);
+            self.chunk.write(OpCode::StructSet(path), self.current_line);
+            self.generate_assign(name);
+        }
+    }
+
     fn generate_expr(&mut self, expr: AstExpr) {
         match expr {
             AstExpr::Nothing => {},
@@ -61,6 +261,7 @@ impl CopperGen {
                     self.generate_expr(i);
                 }
 
+                let name = self.chunk.intern(&name);
                 self.chunk.write(OpCode::NewStruct(name), self.current_line);
 
                 for i in 0..arguments.len() {
@@ -77,9 +278,11 @@ impl CopperGen {
                     panic!("Expected a variable expr");
                 };
 
+                let value = self.chunk.intern(&value);
                 self.chunk.write(OpCode::StructGet(value), self.current_line);
             }
             AstExpr::New(name) => {
+                let name = self.chunk.intern(&name);
                 self.chunk.write(OpCode::NewStruct(name), self.current_line);
             }
             AstExpr::TypeCall(ctype, expr) => {
@@ -127,31 +330,25 @@ impl CopperGen {
                     _ => self.chunk.write(OpCode::Pop, self.current_line),
                 }
             },
-            AstExpr::Variable(name) => self.chunk.write(OpCode::Load(name), self.current_line),
+            AstExpr::Variable(name) => self.generate_load(name),
             AstExpr::Assign(name, expr) => {
                 if let AstExpr::Block(_) = *expr {
-                    self.generate_block_function(*expr);
+                    self.generate_block_value(*expr);
                 } else {
                     self.generate_expr(*expr);
                 }
 
-                if let AstExpr::Variable(name) = *name {
-                    self.chunk.write(OpCode::Assign(name), self.current_line);
-                } else if let AstExpr::StructCall(left, right) = *name {
-                    let mut stack = self.recursive_get_names(left, right);
-                    let name = stack.remove(0);
-                    self.chunk.write(OpCode::StructSet(name, stack), self.current_line);
-                }
+                self.generate_assign_target(*name);
             },
             AstExpr::AssignByOp(name, op, expr) => {
                 if let AstExpr::Variable(name) = *name.clone() {
-                    self.chunk.write_load(name, self.current_line);
+                    self.generate_load(name);
                 } else if let AstExpr::StructCall(_, _) = *name {
                     self.generate_expr(*name.clone());
                 }
 
                 if let AstExpr::Block(_) = *expr {
-                    self.generate_block_function(*expr);
+                    self.generate_block_value(*expr);
                 } else {
                     self.generate_expr(*expr);
                 }
@@ -164,21 +361,15 @@ impl CopperGen {
                     _ => panic!("Expected an operator for assigning"),
                 }
                 
-                if let AstExpr::Variable(name) = *name {
-                    self.chunk.write(OpCode::Assign(name), self.current_line);
-                } else if let AstExpr::StructCall(left, right) = *name {
-                    let mut stack = self.recursive_get_names(left, right);
-                    let name = stack.remove(0);
-                    self.chunk.write(OpCode::StructSet(name, stack), self.current_line);
-                }
+                self.generate_assign_target(*name);
             }
             AstExpr::Call(name, arguments) => self.generate_call_expr(name, arguments),
             AstExpr::Block(stmts) => {
-                self.chunk.write(OpCode::StartScope, self.current_line);
+                self.begin_scope();
                 for s in stmts {
                     self.generate_stmt(s);
                 }
-                self.chunk.write(OpCode::EndScope, self.current_line);
+                self.end_scope();
             },
         }
     }
@@ -202,28 +393,47 @@ impl CopperGen {
         }
     }
 
-    fn generate_block_function(&mut self, block: AstExpr) {
-        let jmp_over = self.generate_patch_jmp();
-        self.chunk.bind_function(format!("@block_func:{}", self.block_increment), ClassType::Any, 0, self.chunk.code.len());
-        
-        self.chunk.write(OpCode::StartScope, self.current_line);
+    // Leaves what the block returns on the stack, or none if it doesn't return anything.
+    fn generate_block_value(&mut self, block: AstExpr) {
+        self.block_contexts.push(BlockContext {
+            scope_depth: self.scope_depth,
+            try_depth: self.try_contexts.len(),
+            exits: Vec::new(),
+        });
 
         self.generate_expr(block);
-
         self.chunk.write_constant(Value::None, self.current_line);
-        self.chunk.write(OpCode::Return, self.current_line);
-        
-        self.chunk.write(OpCode::EndScope, self.current_line);
 
-        self.patch_jmp(self.chunk.code.len(), jmp_over);
-        self.generate_call_expr(format!("@block_func:{}", self.block_increment), vec![]);
-        self.block_increment += 1;
+        let context = self.block_contexts.pop().unwrap();
+        for exit in context.exits {
+            self.patch_jmp(self.chunk.code.len(), exit);
+        }
     }
 
     fn generate_call_expr(&mut self, name: String, arguments: Vec<AstExpr>) {
+        // Coroutines are worked with by the vm itself, rather than through functions.
+        let intrinsic = match name.as_str() {
+            "resume" => Some(OpCode::Resume),
+            "finished" => Some(OpCode::IsFinished),
+            _ => None,
+        };
+
+        if let (Some(op), 1) = (intrinsic, arguments.len()) {
+            let argument = arguments.into_iter().next().unwrap();
+
+            if let AstExpr::Block(_) = argument {
+                self.generate_block_value(argument);
+            } else {
+                self.generate_expr(argument);
+            }
+
+            self.chunk.write(op, self.current_line);
+            return;
+        }
+
         for i in arguments.clone() {
             if let AstExpr::Block(_) = i {
-                self.generate_block_function(i);
+                self.generate_block_value(i);
             } else {
                 self.generate_expr(i);
             }
@@ -233,6 +443,7 @@ impl CopperGen {
             self.chunk.write(OpCode::PopToCall, self.current_line);
         }
 
+        self.call_lines.entry(name.clone()).or_insert(self.current_line);
         self.chunk.write_call(name, self.current_line);
     }
 
@@ -292,9 +503,18 @@ impl CopperGen {
                     structure.insert(s, Value::None);
                 }
 
-                self.chunk.functions.add_struct(structure);
+                self.chunk.structs.add_struct(structure);
             }
+            // Handlers and scopes are left without running any 'finally' blocks.
             AstStmt::Quit => {
+                for _ in 0..self.try_contexts.len() {
+                    self.chunk.write(OpCode::TryEnd, self.current_line);
+                }
+
+                for _ in 0..self.scope_depth {
+                    self.chunk.write(OpCode::EndScope, self.current_line);
+                }
+
                 self.chunk.write(OpCode::EndScript, self.current_line);
             }
             AstStmt::Import(expr) => {
@@ -312,10 +532,7 @@ impl CopperGen {
                             let mut new_string = self.file_path.clone();
 
                             for i in val.split("/") {
-                                if i.contains("..") {
-                                    new_string.push_str(i);
-                                    new_string.push_str("/");
-                                } else if !i.contains(".") {
+                                if i.contains("..") || !i.contains(".") {
                                     new_string.push_str(i);
                                     new_string.push_str("/");
                                 }
@@ -324,34 +541,40 @@ impl CopperGen {
                         };
 
                         //std::fs::write(format!("{}_file.txt", val), source.clone());
+                        let previous_file = self.chunk.current_file;
+                        self.chunk.start_file(&(previous_file_path.clone() + val.as_str()));
+
                         self.parser = CopperParser::new(source);
                         self.generate_loop();
 
+                        self.chunk.current_file = previous_file;
                         self.file_path = previous_file_path;
                         self.parser = current_parser;
                     }
                 } else {
-                    println!("Expected a literal expression for import stmt");
+                    let message = "Expected a literal expression for import stmt".to_string();
+                    self.report_error(CompileError { line: self.current_line, at: Some("import".to_string()), message });
                     return;
                 }
             }
+            AstStmt::Line(line) => self.current_line = line,
             AstStmt::Expr(expr) => self.blacklist_expr(expr),
             AstStmt::Declaration(name, ctype, expr) => {
                 if let AstExpr::Block(_) = expr {
-                    self.generate_block_function(expr);
+                    self.generate_block_value(expr);
                 } else {
                     self.generate_expr(expr);
                 }
                 
-                self.chunk.write_store(name, ctype, self.current_line);
+                self.generate_declaration(name, Some(ctype));
             },
             AstStmt::InferDeclaration(name, expr) => {
                 if let AstExpr::Block(_) = expr {
-                    self.generate_block_function(expr);
+                    self.generate_block_value(expr);
                 } else {
                     self.generate_expr(expr);
                 }
-                self.chunk.write_store_infer(name, self.current_line);
+                self.generate_declaration(name, None);
             },
             AstStmt::If(condition, then_branch, else_branch) => {
                 self.generate_expr(condition);
@@ -376,22 +599,57 @@ impl CopperGen {
                 self.chunk.write_jmp(beginning, self.current_line);
                 self.patch_if_false_jmp(self.chunk.code.len(), while_loop);
             },
-            AstStmt::Function(name, ctype, arg_names, arg_types, body) => {
+            AstStmt::ForIn(name, iterable, body) => {
+                // The coroutine is kept in a variable scripts can't name, so nothing is left on
+                // the stack while the body runs.
+                self.begin_scope();
+                self.generate_expr(iterable);
+                self.generate_declaration(FOR_ITERATOR.to_string(), None);
+
+                let beginning = self.chunk.code.len();
+                self.generate_load(FOR_ITERATOR.to_string());
+                let for_iter = self.chunk.code.len();
+                self.chunk.write(OpCode::ForIter(0), self.current_line);
+
+                self.begin_scope();
+                self.generate_declaration(name, None);
+                self.generate_expr(body);
+                self.end_scope();
+
+                self.chunk.write_jmp(beginning, self.current_line);
+                self.chunk.code[for_iter] = OpCode::ForIter(self.chunk.code.len());
+                self.end_scope();
+            },
+            AstStmt::Function(name, ctype, arg_names, arg_types, body, generator) => {
                 let jmp_over = self.generate_patch_jmp();
                 let bytecode_pos = self.chunk.code.len();
 
-                self.chunk.write(OpCode::StartScope, self.current_line);
+                let state = self.begin_function();
+                self.in_generator = generator;
+
+                if generator {
+                    self.chunk.write(OpCode::MakeCoroutine, self.current_line);
+                }
+
+                self.begin_scope();
 
                 for i in 0..arg_names.len() {
-                    self.chunk.write_argument_store(arg_names[i].clone(), arg_types[i].clone(), self.current_line);
+                    if self.resolve_variables {
+                        let slot = self.declare_local(arg_names[i].clone());
+                        let name = self.chunk.intern(&arg_names[i]);
+                        self.chunk.write(OpCode::ArgumentLocal(slot, name, arg_types[i].clone()), self.current_line);
+                    } else {
+                        self.chunk.write_argument_store(arg_names[i].clone(), arg_types[i].clone(), self.current_line);
+                    }
                 }
 
                 self.generate_expr(body);
 
                 self.chunk.write_constant(Value::None, self.current_line);
+                self.end_scope();
                 self.chunk.write(OpCode::Return, self.current_line);
 
-                self.chunk.write(OpCode::EndScope, self.current_line);
+                self.end_function(state);
 
                 self.chunk.bind_function(name, ctype, arg_names.len(), bytecode_pos);
 
@@ -404,16 +662,177 @@ impl CopperGen {
                     self.chunk.write_constant(Value::None, self.current_line);
                 }
 
-                self.chunk.write(OpCode::Return, self.current_line);
+                // Returning inside of a block used as a value only leaves the block.
+                if let Some(context) = self.block_contexts.last() {
+                    let (scope_depth, try_depth) = (context.scope_depth, context.try_depth);
+                    self.generate_try_unwind(try_depth);
+
+                    for _ in scope_depth..self.scope_depth {
+                        self.chunk.write(OpCode::EndScope, self.current_line);
+                    }
+
+                    self.chunk.write_jmp(0, self.current_line);
+                    let exit = self.chunk.code.len() - 1;
+                    self.block_contexts.last_mut().unwrap().exits.push(exit);
+                } else {
+                    self.generate_try_unwind(0);
+
+                    for _ in 0..self.scope_depth {
+                        self.chunk.write(OpCode::EndScope, self.current_line);
+                    }
+
+                    self.chunk.write(OpCode::Return, self.current_line);
+                }
+            },
+            AstStmt::Throw(expr) => {
+                self.generate_expr(expr);
+                self.chunk.write(OpCode::Throw, self.current_line);
+            },
+            AstStmt::Yield(value) => {
+                if self.in_generator {
+                    match value {
+                        Some(AstExpr::Block(stmts)) => self.generate_block_value(AstExpr::Block(stmts)),
+                        Some(expr) => self.generate_expr(expr),
+                        None => self.chunk.write_constant(Value::None, self.current_line),
+                    }
+
+                    self.chunk.write(OpCode::YieldValue, self.current_line);
+                } else if value.is_some() {
+                    let message = "Can only yield a value inside of a generator.".to_string();
+                    self.report_error(CompileError { line: self.current_line, at: Some("yield".to_string()), message });
+                } else {
+                    self.chunk.write(OpCode::Yield, self.current_line);
+                }
+            },
+            AstStmt::Try(body, catch_name, catch_body, finally_body) => {
+                let handler = self.chunk.code.len();
+                self.chunk.write(OpCode::TryStart(0), self.current_line);
+
+                self.try_contexts.push(TryContext { finally: finally_body.clone(), handler_active: true });
+                self.generate_expr(body);
+                self.try_contexts.pop();
+
+                self.chunk.write(OpCode::TryEnd, self.current_line);
+                if let Some(finally_body) = &finally_body {
+                    self.generate_expr(finally_body.clone());
+                }
+
+                let mut jmp_to_end = vec![self.generate_patch_jmp()];
+
+                // The thrown value is on top of the stack when the handler is jumped to.
+                self.chunk.code[handler] = OpCode::TryStart(self.chunk.code.len());
+
+                if let Some(catch_body) = catch_body {
+                    // With a 'finally' block, anything thrown inside of 'catch' is caught again
+                    // so the 'finally' block runs before it continues on.
+                    let rethrow = self.chunk.code.len();
+                    if finally_body.is_some() {
+                        self.chunk.write(OpCode::TryStart(0), self.current_line);
+                        self.try_contexts.push(TryContext { finally: finally_body.clone(), handler_active: true });
+                    }
+
+                    self.begin_scope();
+
+                    match catch_name {
+                        Some(name) => self.generate_declaration(name, Some(ClassType::Any)),
+                        None => self.chunk.write(OpCode::Pop, self.current_line),
+                    }
+
+                    self.generate_expr(catch_body);
+                    self.end_scope();
+
+                    if let Some(finally_body) = &finally_body {
+                        self.try_contexts.pop();
+                        self.chunk.write(OpCode::TryEnd, self.current_line);
+                        self.generate_expr(finally_body.clone());
+                        jmp_to_end.push(self.generate_patch_jmp());
+
+                        self.chunk.code[rethrow] = OpCode::TryStart(self.chunk.code.len());
+                        self.generate_expr(finally_body.clone());
+                        self.chunk.write(OpCode::Throw, self.current_line);
+                    }
+                } else if let Some(finally_body) = finally_body {
+                    self.generate_expr(finally_body);
+                    self.chunk.write(OpCode::Throw, self.current_line);
+                }
+
+                for jmp in jmp_to_end {
+                    self.patch_jmp(self.chunk.code.len(), jmp);
+                }
             },
         }
     }
 
+    // Leaves every 'try' statement past the first 'down_to', running their 'finally' blocks.
+    fn generate_try_unwind(&mut self, down_to: usize) {
+        for i in (down_to..self.try_contexts.len()).rev() {
+            let context = self.try_contexts[i].clone();
+
+            if context.handler_active {
+                self.chunk.write(OpCode::TryEnd, self.current_line);
+            }
+
+            if let Some(finally_body) = context.finally {
+                let inner_contexts = self.try_contexts.split_off(i);
+                self.generate_expr(finally_body);
+                self.try_contexts.extend(inner_contexts);
+            }
+        }
+    }
+
+    fn report_error(&mut self, error: CompileError) {
+        if self.print_errors {
+            println!("{}", error);
+        }
+
+        self.errors.push(error);
+        self.had_error = true;
+    }
+
+    fn report_parse_errors(&mut self) {
+        for error in std::mem::take(&mut self.parser.errors) {
+            self.report_error(error);
+        }
+    }
+
     fn generate_loop(&mut self) {
         while let Some(stmt) = self.parser.parse() {
-            self.current_line = self.parser.current_line;
+            self.report_parse_errors();
+            self.current_line = self.parser.statement_line;
             self.generate_stmt(stmt);
         }
+
+        self.report_parse_errors();
+    }
+
+    fn report_undefined_functions(&mut self) {
+        let undefined: Vec<String> = self.chunk.unbound_functions().iter()
+            .filter(|name| !self.late_bound.iter().any(|late| late == *name))
+            .map(|name| name.to_string())
+            .collect();
+
+        for name in undefined {
+            let line = self.call_lines.get(&name).copied().unwrap_or(self.current_line);
+            let message = format!("Cannot find a function by the name of '{}'.", name);
+            self.report_error(CompileError { line, at: Some(name), message });
+        }
+
+        self.call_lines.clear();
+    }
+
+    // Natives bound before generating are called by index like any other function.
+    pub fn bind_native_function(&mut self, name: String, arg_count: usize, func: &'static dyn Fn(Vec<Value>) -> Value) {
+        self.chunk.bind_native_function(name, arg_count, func);
+    }
+
+    // Binds a native that hands control back to the host after it returns, like a 'wait'.
+    pub fn bind_suspending_native_function(&mut self, name: String, arg_count: usize, func: &'static dyn Fn(Vec<Value>) -> Value) {
+        self.chunk.bind_suspending_native_function(name, arg_count, func);
+    }
+
+    // Lets scripts call a function that will only be bound to the chunk after it's generated.
+    pub fn declare_function(&mut self, name: String) {
+        self.late_bound.push(name);
     }
 
     pub fn add_content(&mut self, source: &str) {
@@ -421,6 +840,30 @@ impl CopperGen {
         self.generate_loop();
     }
 
+    // Generates more source onto the chunk being built up, giving back everything generated so
+    // far. Unlike 'generate_chunk' the chunk isn't erased, so the source can use the globals,
+    // functions and structures from earlier pieces. Code that was already handed out is never
+    // moved, which is also why it isn't optimized, so a vm part way through running it can
+    // carry on into the new code. Source with errors is thrown away and gives back nothing.
+    pub fn generate_more(&mut self, source: &str) -> Option<Chunk> {
+        let previous = self.chunk.clone();
+        self.had_error = false;
+        self.errors.clear();
+
+        self.add_content(source);
+        self.report_undefined_functions();
+
+        if self.had_error {
+            self.chunk = previous;
+            return None;
+        }
+
+        self.chunk.write(OpCode::EndScript, self.current_line);
+        self.chunk.encode();
+
+        return Some(self.chunk.clone());
+    }
+
     pub fn generate_chunk(&mut self, files: Vec<String>) -> Chunk {
         for i in files {
             if !self.files.contains(&i) {
@@ -428,10 +871,7 @@ impl CopperGen {
                     let mut new_string = String::with_capacity(i.capacity());
                     let mut paths: Vec<&str> = Vec::new();
                     for j in i.split("/") {
-                        if j.to_string().contains("..") {
-                            paths.push(j);
-                        }
-                        else if !j.to_string().contains(".") {
+                        if j.contains("..") || !j.contains(".") {
                             paths.push(j);
                         }
                     }
@@ -447,13 +887,21 @@ impl CopperGen {
                 self.macro_expander = MacroExpander::new(vec![i.clone()]);
                 let source = self.macro_expander.compile();
                         
+                self.chunk.start_file(&i);
                 self.parser = CopperParser::new(source);
                 self.generate_loop();
                 self.files.push(i);
             } 
         }
         
+        self.report_undefined_functions();
         self.chunk.write(OpCode::EndScript, self.current_line);
+
+        if self.optimize {
+            self.chunk.optimize();
+        }
+
+        self.chunk.encode();
         
         let final_chunk = self.chunk.clone();
 
@@ -467,10 +915,22 @@ impl CopperGen {
             parser: CopperParser::new("".to_string()),
             current_line: 0,
             chunk: Chunk::new(),
-            block_increment: 0,
             files: Vec::new(),
             file_path: String::new(),
             macro_expander: MacroExpander::new(Vec::new()),
+            try_contexts: Vec::new(),
+            block_contexts: Vec::new(),
+            resolve_variables: true,
+            optimize: true,
+            locals: Vec::new(),
+            scope_depth: 0,
+            function_depth: 0,
+            in_generator: false,
+            had_error: false,
+            errors: Vec::new(),
+            print_errors: true,
+            late_bound: Vec::new(),
+            call_lines: HashMap::new(),
         }
     }
 }
\ No newline at end of file
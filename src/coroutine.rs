@@ -0,0 +1,60 @@
+use std::{cell::RefCell, cmp::Ordering, fmt, rc::Rc};
+
+use crate::vm::Context;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CoroutineState {
+    // Waiting to be resumed, which is where it starts off before it's first run.
+    Suspended,
+    Running,
+    Finished,
+}
+
+// A call to a generator that can stop part way through with 'yield' and carry on when it's
+// resumed. Everything it needs to carry on is kept in its context while it isn't running.
+pub struct Coroutine {
+    pub name: String,
+    pub state: CoroutineState,
+    pub context: Context,
+}
+
+// Coroutines are shared, every copy of the value resumes the same call.
+#[derive(Clone)]
+pub struct CoroutineRef(pub Rc<RefCell<Coroutine>>);
+
+impl CoroutineRef {
+    pub fn new(coroutine: Coroutine) -> Self {
+        return Self(Rc::new(RefCell::new(coroutine)));
+    }
+
+    pub fn name(&self) -> String {
+        return self.0.borrow().name.clone();
+    }
+
+    pub fn state(&self) -> CoroutineState {
+        return self.0.borrow().state;
+    }
+}
+
+// Two coroutines are only the same if they're the same call.
+impl PartialEq for CoroutineRef {
+    fn eq(&self, other: &Self) -> bool {
+        return Rc::ptr_eq(&self.0, &other.0);
+    }
+}
+
+impl PartialOrd for CoroutineRef {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        if self == other {
+            return Some(Ordering::Equal);
+        }
+
+        return None;
+    }
+}
+
+impl fmt::Debug for CoroutineRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Coroutine('{}', {:?})", self.name(), self.state())
+    }
+}
@@ -0,0 +1,528 @@
+use std::io::{BufRead, Write};
+use std::path::Path;
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::chunk::Chunk;
+use crate::codegen::CopperGen;
+use crate::debugger::{Debugger, Step};
+use crate::environment::CopperStruct;
+use crate::error::RuntimeError;
+use crate::json::{Json, read_message, write_message};
+use crate::value::Value;
+use crate::vm::{RunState, VM};
+
+// Copper only runs one line of execution, so it's the only thread editors are told about.
+const THREAD_ID: i64 = 1;
+
+// How many ops are run before checking for requests, like one to pause the script.
+const RUN_SLICE: u64 = 10_000;
+
+// What a variables reference handed to the editor points at. They're only good until the
+// script carries on, since the values could change.
+enum Handle {
+    Locals(usize),
+    Globals,
+    Struct(CopperStruct),
+}
+
+// Speaks the debug adapter protocol, so editors can run scripts under the debugger.
+pub struct DapServer<W: Write> {
+    // Messages are read on their own thread, so they can be checked for while the script runs.
+    messages: Receiver<Json>,
+    output: W,
+    seq: i64,
+    // Taken once the program is launched.
+    gen: Option<CopperGen>,
+    vm: Option<VM>,
+    // What the program printed since this was last called, sent to the editor as output. Only
+    // needed when the natives don't print to stdout themselves, which is where the protocol goes.
+    pub take_output: Option<fn() -> String>,
+    // Lines asked for before the program was generated, with the file they're in.
+    breakpoint_lines: Vec<(String, Vec<usize>)>,
+    stop_on_entry: bool,
+    configured: bool,
+    started: bool,
+    // Set once the script stops with an error, it can be looked at but not carried on.
+    failed: bool,
+    // Set when the editor disconnects while the script is running.
+    ended: bool,
+    handles: Vec<Handle>,
+}
+
+impl<W: Write> DapServer<W> {
+    // Natives should be bound to the generator before it's given to the server.
+    pub fn new<R: BufRead + Send + 'static>(gen: CopperGen, mut input: R, output: W) -> DapServer<W> {
+        let (sender, messages) = mpsc::channel();
+
+        thread::spawn(move || {
+            while let Some(message) = read_message(&mut input) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+
+        return DapServer {
+            messages,
+            output,
+            seq: 1,
+            gen: Some(gen),
+            vm: None,
+            take_output: None,
+            breakpoint_lines: Vec::new(),
+            stop_on_entry: false,
+            configured: false,
+            started: false,
+            failed: false,
+            ended: false,
+            handles: Vec::new(),
+        };
+    }
+
+    // Handles requests until the editor disconnects or the input ends.
+    pub fn run(&mut self) {
+        while let Ok(message) = self.messages.recv() {
+            if message.get("type").and_then(Json::as_str) != Some("request") {
+                continue;
+            }
+
+            if !self.handle(&message) || self.ended {
+                break;
+            }
+        }
+    }
+
+    fn send(&mut self, message: Json) {
+        let mut fields = vec![("seq".to_string(), Json::from(self.seq))];
+        if let Json::Object(rest) = message {
+            fields.extend(rest);
+        }
+        self.seq += 1;
+
+        write_message(&mut self.output, &Json::Object(fields));
+    }
+
+    fn respond(&mut self, request: &Json, body: Json) {
+        let mut fields = vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(true)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+        ];
+
+        if body != Json::Null {
+            fields.push(("body", body));
+        }
+
+        self.send(Json::object(fields));
+    }
+
+    fn fail(&mut self, request: &Json, message: String) {
+        self.send(Json::object(vec![
+            ("type", Json::from("response")),
+            ("request_seq", request.get("seq").cloned().unwrap_or(Json::Null)),
+            ("success", Json::from(false)),
+            ("command", request.get("command").cloned().unwrap_or(Json::Null)),
+            ("message", Json::from(message)),
+        ]));
+    }
+
+    fn event(&mut self, name: &str, body: Json) {
+        self.send(Json::object(vec![("type", Json::from("event")), ("event", Json::from(name)), ("body", body)]));
+    }
+
+    fn output_event(&mut self, category: &str, text: String) {
+        self.event("output", Json::object(vec![("category", Json::from(category)), ("output", Json::from(text))]));
+    }
+
+    fn send_program_output(&mut self) {
+        if let Some(take_output) = self.take_output {
+            let text = take_output();
+
+            if !text.is_empty() {
+                self.output_event("stdout", text);
+            }
+        }
+    }
+
+    // Gives back false once the session is over.
+    fn handle(&mut self, request: &Json) -> bool {
+        let empty = Json::Object(Vec::new());
+        let arguments = request.get("arguments").unwrap_or(&empty);
+        let command = request.get("command").and_then(Json::as_str).unwrap_or("");
+
+        match command {
+            "initialize" => {
+                self.respond(request, Json::object(vec![
+                    ("supportsConfigurationDoneRequest", Json::from(true)),
+                    ("supportsEvaluateForHovers", Json::from(true)),
+                    ("supportsTerminateRequest", Json::from(true)),
+                ]));
+                self.event("initialized", Json::Object(Vec::new()));
+            },
+            "launch" => self.launch(request, arguments),
+            "setBreakpoints" => self.set_breakpoints(request, arguments),
+            "configurationDone" => {
+                self.configured = true;
+                self.respond(request, Json::Null);
+                self.start();
+            },
+            "threads" => self.respond(request, Json::object(vec![
+                ("threads", Json::from(vec![Json::object(vec![("id", Json::from(THREAD_ID)), ("name", Json::from("main"))])])),
+            ])),
+            "stackTrace" => self.stack_trace(request),
+            "scopes" => {
+                let frame = arguments.get("frameId").and_then(Json::as_usize).unwrap_or(0);
+                let locals = self.add_handle(Handle::Locals(frame));
+                let globals = self.add_handle(Handle::Globals);
+
+                self.respond(request, Json::object(vec![("scopes", Json::from(vec![
+                    Json::object(vec![("name", Json::from("Locals")), ("presentationHint", Json::from("locals")), ("variablesReference", Json::from(locals)), ("expensive", Json::from(false))]),
+                    Json::object(vec![("name", Json::from("Globals")), ("variablesReference", Json::from(globals)), ("expensive", Json::from(false))]),
+                ]))]));
+            },
+            "variables" => self.variables(request, arguments),
+            "evaluate" => self.evaluate(request, arguments),
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                let step = match command {
+                    "next" => Some(Step::Over),
+                    "stepIn" => Some(Step::In),
+                    "stepOut" => Some(Step::Out),
+                    _ => None,
+                };
+
+                if command == "continue" {
+                    self.respond(request, Json::object(vec![("allThreadsContinued", Json::from(true))]));
+                } else {
+                    self.respond(request, Json::Null);
+                }
+
+                self.carry_on(step);
+            },
+            // Pausing a running script is handled while it runs, so here it's already stopped.
+            "pause" => self.respond(request, Json::Null),
+            "disconnect" => {
+                self.respond(request, Json::Null);
+                return false;
+            },
+            "terminate" => {
+                self.respond(request, Json::Null);
+                self.event("terminated", Json::Object(Vec::new()));
+                return false;
+            },
+            _ => self.fail(request, format!("Unsupported request '{}'.", command)),
+        }
+
+        return true;
+    }
+
+    fn launch(&mut self, request: &Json, arguments: &Json) {
+        let program = match arguments.get("program").and_then(Json::as_str) {
+            Some(program) => program.to_string(),
+            None => return self.fail(request, "Launching needs a 'program' to run.".to_string()),
+        };
+
+        if let Err(err) = std::fs::metadata(&program) {
+            return self.fail(request, format!("Couldn't read '{}': {}", program, err));
+        }
+
+        let mut gen = match self.gen.take() {
+            Some(gen) => gen,
+            None => return self.fail(request, "A program has already been launched.".to_string()),
+        };
+
+        gen.print_errors = false;
+        let chunk = gen.generate_chunk(vec![program.clone()]);
+
+        if gen.had_error {
+            for error in &gen.errors {
+                self.output_event("stderr", format!("{}\n", error));
+            }
+
+            self.fail(request, format!("'{}' has errors in it.", program));
+            self.event("terminated", Json::Object(Vec::new()));
+            return;
+        }
+
+        self.stop_on_entry = arguments.get("stopOnEntry").and_then(Json::as_bool).unwrap_or(false);
+
+        let mut debugger = Debugger::new();
+        if !self.stop_on_entry {
+            debugger.resume();
+        }
+
+        for (path, lines) in &self.breakpoint_lines {
+            if let Some(file) = DapServer::<W>::source_index(&chunk, path) {
+                for line in lines {
+                    debugger.add_breakpoint(&chunk, file, *line);
+                }
+            }
+        }
+
+        let mut vm = VM::new(chunk);
+        vm.debugger = Some(debugger);
+
+        self.vm = Some(vm);
+        self.respond(request, Json::Null);
+
+        // Editors can finish configuring before or after launching.
+        self.start();
+    }
+
+    fn start(&mut self) {
+        if !self.configured || self.started || self.vm.is_none() {
+            return;
+        }
+
+        self.started = true;
+
+        // The debugger was set up when launching, to stop on the entry or go to the first breakpoint.
+        let reason = if self.stop_on_entry { "entry" } else { "breakpoint" };
+        self.run_script(reason);
+    }
+
+    fn same_file(a: &str, b: &str) -> bool {
+        return match (Path::new(a).canonicalize(), Path::new(b).canonicalize()) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => a == b,
+        };
+    }
+
+    // Where the file is in the chunk's sources, which can be named by a different path.
+    fn source_index(chunk: &Chunk, path: &str) -> Option<u32> {
+        let index = chunk.sources.iter().position(|source| DapServer::<W>::same_file(source, path))?;
+        return Some(index as u32);
+    }
+
+    fn set_breakpoints(&mut self, request: &Json, arguments: &Json) {
+        let path = arguments.get("source").and_then(|source| source.get("path")).and_then(Json::as_str).unwrap_or("").to_string();
+
+        let lines: Vec<usize> = arguments.get("breakpoints")
+            .and_then(Json::as_array)
+            .map(|breakpoints| breakpoints.iter().filter_map(|breakpoint| breakpoint.get("line").and_then(Json::as_usize)).collect())
+            .unwrap_or_default();
+
+        let breakpoints = match &mut self.vm {
+            Some(vm) => {
+                let chunk = vm.chunk.clone();
+
+                match DapServer::<W>::source_index(&chunk, &path) {
+                    Some(file) => {
+                        let debugger = vm.debugger.as_mut().unwrap();
+                        debugger.clear_breakpoints(file);
+
+                        lines.iter().map(|line| match debugger.add_breakpoint(&chunk, file, *line) {
+                            Some(line) => Json::object(vec![("verified", Json::from(true)), ("line", Json::from(line))]),
+                            None => Json::object(vec![("verified", Json::from(false)), ("line", Json::from(*line))]),
+                        }).collect::<Vec<Json>>()
+                    },
+                    None => lines.iter().map(|line| Json::object(vec![
+                        ("verified", Json::from(false)),
+                        ("line", Json::from(*line)),
+                        ("message", Json::from("The file isn't part of the launched program.")),
+                    ])).collect(),
+                }
+            },
+            // They're checked once the program is generated.
+            None => {
+                self.breakpoint_lines.retain(|(file, _)| !DapServer::<W>::same_file(file, &path));
+                self.breakpoint_lines.push((path, lines.clone()));
+
+                lines.iter().map(|line| Json::object(vec![("verified", Json::from(true)), ("line", Json::from(*line))])).collect()
+            },
+        };
+
+        self.respond(request, Json::object(vec![("breakpoints", Json::from(breakpoints))]));
+    }
+
+    // Runs the script until it pauses again, telling the editor why it stopped.
+    fn carry_on(&mut self, step: Option<Step>) {
+        self.handles.clear();
+
+        let vm = match &mut self.vm {
+            Some(vm) => vm,
+            None => return,
+        };
+
+        if self.failed {
+            // Same as the command line gives for a runtime error.
+            self.event("exited", Json::object(vec![("exitCode", Json::from(70i64))]));
+            self.event("terminated", Json::Object(Vec::new()));
+            return;
+        }
+
+        let depth = vm.call_depth();
+        let debugger = vm.debugger.as_mut().unwrap();
+
+        let reason = match step {
+            Some(step) => {
+                debugger.step(step, depth);
+                "step"
+            },
+            None => {
+                debugger.resume();
+                "breakpoint"
+            },
+        };
+
+        self.run_script(reason);
+    }
+
+    // Tells the editor why the script stopped, 'reason' is for when it paused. The script is run
+    // a slice at a time, with the requests sent in the meantime handled in between.
+    fn run_script(&mut self, reason: &str) {
+        let state = loop {
+            let state = match &mut self.vm {
+                Some(vm) => vm.run_for(RUN_SLICE),
+                None => return,
+            };
+
+            self.send_program_output();
+
+            if !matches!(state, Ok(RunState::Yielded)) {
+                break state;
+            }
+
+            while let Ok(request) = self.messages.try_recv() {
+                if request.get("type").and_then(Json::as_str) != Some("request") {
+                    continue;
+                }
+
+                match request.get("command").and_then(Json::as_str).unwrap_or("") {
+                    "pause" => {
+                        self.respond(&request, Json::Null);
+                        self.stopped("pause", None);
+                        return;
+                    },
+                    "continue" | "next" | "stepIn" | "stepOut" => self.fail(&request, "The script is already running.".to_string()),
+                    _ => if !self.handle(&request) {
+                        self.ended = true;
+                        return;
+                    },
+                }
+            }
+        };
+
+        match state {
+            Ok(RunState::Paused) => self.stopped(reason, None),
+            Ok(_) => {
+                self.event("exited", Json::object(vec![("exitCode", Json::from(0i64))]));
+                self.event("terminated", Json::Object(Vec::new()));
+            },
+            Err(error) => {
+                self.failed = true;
+                self.output_event("stderr", format!("{}\n", error));
+                self.stopped("exception", Some(error));
+            },
+        }
+    }
+
+    fn stopped(&mut self, reason: &str, error: Option<RuntimeError>) {
+        let mut body = vec![
+            ("reason", Json::from(reason)),
+            ("threadId", Json::from(THREAD_ID)),
+            ("allThreadsStopped", Json::from(true)),
+        ];
+
+        if let Some(error) = error {
+            body.push(("description", Json::from(error.message.clone())));
+            body.push(("text", Json::from(error.to_string())));
+        }
+
+        self.event("stopped", Json::object(body));
+    }
+
+    fn stack_trace(&mut self, request: &Json) {
+        let frames = match &self.vm {
+            Some(vm) => vm.stack_frames(),
+            None => Vec::new(),
+        };
+
+        let stack_frames: Vec<Json> = frames.iter().enumerate().map(|(i, frame)| {
+            let mut stack_frame = vec![
+                ("id", Json::from(i)),
+                ("name", Json::from(frame.name.clone())),
+                ("line", Json::from(frame.line)),
+                ("column", Json::from(1usize)),
+            ];
+
+            if let Some(file) = &frame.file {
+                let name = Path::new(file).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                stack_frame.push(("source", Json::object(vec![("name", Json::from(name)), ("path", Json::from(file.clone()))])));
+            }
+
+            return Json::object(stack_frame);
+        }).collect();
+
+        let total = stack_frames.len();
+        self.respond(request, Json::object(vec![("stackFrames", Json::from(stack_frames)), ("totalFrames", Json::from(total))]));
+    }
+
+    // Gives back the reference the editor uses for the handle.
+    fn add_handle(&mut self, handle: Handle) -> usize {
+        self.handles.push(handle);
+        return self.handles.len();
+    }
+
+    fn describe(value: &Value) -> String {
+        return match value {
+            Value::Str(string) => format!("\"{}\"", string),
+            Value::Struct(structure) => structure.name.clone(),
+            value => value.string_s(),
+        };
+    }
+
+    // Structures can be opened up in the editor to see their fields.
+    fn variable(&mut self, name: &str, value: Value) -> Json {
+        let reference = match &value {
+            Value::Struct(structure) => self.add_handle(Handle::Struct(structure.clone())),
+            _ => 0,
+        };
+
+        return Json::object(vec![
+            ("name", Json::from(name)),
+            ("value", Json::from(DapServer::<W>::describe(&value))),
+            ("type", Json::from(value.type_to_string())),
+            ("variablesReference", Json::from(reference)),
+        ]);
+    }
+
+    fn variables(&mut self, request: &Json, arguments: &Json) {
+        let reference = arguments.get("variablesReference").and_then(Json::as_usize).unwrap_or(0);
+
+        let found: Vec<(String, Value)> = match (&self.vm, reference.checked_sub(1).and_then(|i| self.handles.get(i))) {
+            (Some(vm), Some(Handle::Locals(frame))) => vm.frame_locals(*frame),
+            (Some(vm), Some(Handle::Globals)) => vm.globals().map(|(name, value)| (name.to_string(), value.clone())).collect(),
+            (_, Some(Handle::Struct(structure))) => structure.field_names.iter().cloned().zip(structure.field_values.iter().cloned()).collect(),
+            _ => return self.fail(request, format!("There's nothing for the variables reference {}.", reference)),
+        };
+
+        let variables: Vec<Json> = found.into_iter().map(|(name, value)| self.variable(&name, value)).collect();
+        self.respond(request, Json::object(vec![("variables", Json::from(variables))]));
+    }
+
+    // Only variables can be looked at, copper can't run expressions while a script is paused.
+    fn evaluate(&mut self, request: &Json, arguments: &Json) {
+        let expression = arguments.get("expression").and_then(Json::as_str).unwrap_or("").trim().to_string();
+        let frame = arguments.get("frameId").and_then(Json::as_usize).unwrap_or(0);
+
+        let value = self.vm.as_ref().and_then(|vm| {
+            let local = vm.frame_locals(frame).into_iter().rev().find(|(name, _)| *name == expression);
+            local.map(|(_, value)| value).or_else(|| vm.get_global(&expression))
+        });
+
+        match value {
+            Some(value) => {
+                let variable = self.variable(&expression, value);
+
+                self.respond(request, Json::object(vec![
+                    ("result", variable.get("value").cloned().unwrap_or(Json::Null)),
+                    ("type", variable.get("type").cloned().unwrap_or(Json::Null)),
+                    ("variablesReference", variable.get("variablesReference").cloned().unwrap_or(Json::Null)),
+                ]));
+            },
+            None => self.fail(request, format!("'{}' isn't a variable that can be seen from here.", expression)),
+        }
+    }
+}
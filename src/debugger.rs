@@ -0,0 +1,338 @@
+use std::collections::BTreeSet;
+
+use crate::chunk::{Chunk, NO_FILE};
+use crate::repl::{Input, LineEditor};
+use crate::value::Value;
+use crate::vm::{RunState, VM};
+
+// How far to go before pausing again.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Step {
+    // Pauses on the next line, going into any call made.
+    In,
+    // Pauses on the next line of the same function, or the one that called it.
+    Over,
+    // Pauses once the function returns to whatever called it.
+    Out,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stepping {
+    Run,
+    In,
+    // The call depth the step started at.
+    Over(usize),
+    Out(usize),
+}
+
+// Set on the vm to pause the script at breakpoints and while stepping through it. The vm only
+// pauses at the first op of a line, so each line is paused at once every time it's run.
+pub struct Debugger {
+    // The file, as an index into the chunk's sources, and line of each breakpoint.
+    breakpoints: BTreeSet<(u32, usize)>,
+    stepping: Stepping,
+    // Where the vm last paused, so it isn't paused there again as soon as it carries on.
+    paused_at: Option<usize>,
+}
+
+impl Debugger {
+    // Starts out paused on the first line, so breakpoints can be set before anything is run.
+    pub fn new() -> Debugger {
+        return Debugger { breakpoints: BTreeSet::new(), stepping: Stepping::In, paused_at: None };
+    }
+
+    // Lines without any code have their breakpoint moved down to the next one that has some.
+    // Gives back the line the breakpoint ended up on, or none if there's no code past it.
+    pub fn add_breakpoint(&mut self, chunk: &Chunk, file: u32, line: usize) -> Option<usize> {
+        let line = chunk.lines.iter().zip(&chunk.files)
+            .filter(|(code_line, code_file)| **code_file == file && **code_line >= line)
+            .map(|(code_line, _)| *code_line)
+            .min()?;
+        self.breakpoints.insert((file, line));
+
+        return Some(line);
+    }
+
+    // Gives back whether there was a breakpoint on the line.
+    pub fn remove_breakpoint(&mut self, file: u32, line: usize) -> bool {
+        return self.breakpoints.remove(&(file, line));
+    }
+
+    // Removes the breakpoints in one file.
+    pub fn clear_breakpoints(&mut self, file: u32) {
+        self.breakpoints.retain(|(breakpoint_file, _)| *breakpoint_file != file);
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = &(u32, usize)> {
+        return self.breakpoints.iter();
+    }
+
+    // Sets how far the vm goes the next time it's run, 'call_depth' is from 'vm.call_depth()'.
+    pub fn step(&mut self, step: Step, call_depth: usize) {
+        self.stepping = match step {
+            Step::In => Stepping::In,
+            Step::Over => Stepping::Over(call_depth),
+            Step::Out => Stepping::Out(call_depth),
+        };
+    }
+
+    // Runs until a breakpoint is reached.
+    pub fn resume(&mut self) {
+        self.stepping = Stepping::Run;
+    }
+
+    // Checked by the vm before every op.
+    pub fn should_pause(&mut self, chunk: &Chunk, offset: usize, call_depth: usize) -> bool {
+        if self.paused_at.take() == Some(offset) {
+            return false;
+        }
+
+        let index = match chunk.offsets.binary_search(&offset) {
+            Ok(index) => index,
+            Err(_) => return false,
+        };
+
+        let line = chunk.lines[index];
+        let file = chunk.files.get(index).copied().unwrap_or(NO_FILE);
+        if index > 0 && chunk.lines[index - 1] == line && chunk.files.get(index - 1) == Some(&file) {
+            return false;
+        }
+
+        let stepped = match self.stepping {
+            Stepping::Run => false,
+            Stepping::In => true,
+            Stepping::Over(depth) => call_depth <= depth,
+            Stepping::Out(depth) => call_depth < depth,
+        };
+
+        if !stepped && !self.breakpoints.contains(&(file, line)) {
+            return false;
+        }
+
+        self.stepping = Stepping::Run;
+        self.paused_at = Some(offset);
+        return true;
+    }
+}
+
+const HELP: &str = "  c, continue              run until the next breakpoint
+  s, step                  go to the next line, going into calls
+  n, next                  go to the next line, going over calls
+  o, out                   go until the function returns
+  b, break [file:]<line>   set a breakpoint, in the current file by default
+  d, delete [file:]<line>  remove a breakpoint
+  bl, breakpoints          list the breakpoints
+  bt, frames               list the functions being run
+  l, locals [frame]        show the variables of a frame, the innermost one by default
+  p, print <name>          show a variable from the innermost frame or the globals
+  g, globals               show the globals
+  list                     show the source around the current line
+  q, quit                  stop debugging";
+
+// Runs a script under the debugger, taking commands from the terminal whenever it pauses.
+pub struct DebugConsole {
+    pub vm: VM,
+    // The lines of each of the chunk's sources, for showing where the vm is.
+    sources: Vec<Vec<String>>,
+    editor: LineEditor,
+}
+
+impl DebugConsole {
+    // Files that can't be read anymore just aren't shown.
+    pub fn new(chunk: Chunk) -> DebugConsole {
+        let sources = chunk.sources.iter()
+            .map(|file| std::fs::read_to_string(file).unwrap_or_default().lines().map(|line| line.to_string()).collect())
+            .collect();
+
+        let mut vm = VM::new(chunk);
+        vm.debugger = Some(Debugger::new());
+
+        return DebugConsole { vm, sources, editor: LineEditor::new() };
+    }
+
+    // Gives back false if the script stopped with an error.
+    pub fn run(&mut self) -> bool {
+        println!("Debugging, type 'help' for the commands.");
+
+        // Gets the vm to the first line.
+        let mut state = self.vm.continue_running();
+
+        loop {
+            match state {
+                Ok(RunState::Paused) => self.show_line(),
+                Ok(_) => {
+                    println!("The script finished.");
+                    return true;
+                },
+                Err(ref error) => {
+                    println!("{}", error);
+                    println!("The script stopped, its frames can still be looked at.");
+                },
+            }
+
+            let finished = state.is_err();
+
+            loop {
+                let line = match self.editor.read_line("(debug) ") {
+                    Input::Line(line) => line,
+                    Input::Interrupt => continue,
+                    Input::End => return !finished,
+                };
+
+                let mut words = line.split_whitespace();
+                let command = words.next().unwrap_or("");
+                let argument = words.next();
+
+                let step = match command {
+                    "c" | "continue" => Some(None),
+                    "s" | "step" => Some(Some(Step::In)),
+                    "n" | "next" => Some(Some(Step::Over)),
+                    "o" | "out" => Some(Some(Step::Out)),
+                    _ => None,
+                };
+
+                if let Some(step) = step {
+                    if finished {
+                        println!("The script has stopped, it can't be carried on.");
+                        continue;
+                    }
+
+                    let depth = self.vm.call_depth();
+                    let debugger = self.vm.debugger.as_mut().unwrap();
+
+                    match step {
+                        Some(step) => debugger.step(step, depth),
+                        None => debugger.resume(),
+                    }
+
+                    break;
+                }
+
+                match command {
+                    "" => {},
+                    "b" | "break" => match argument.map(|place| self.parse_place(place)) {
+                        Some(Ok((file, line))) => {
+                            let chunk = self.vm.chunk.clone();
+                            match self.vm.debugger.as_mut().unwrap().add_breakpoint(&chunk, file, line) {
+                                Some(line) => println!("Breakpoint on {}", self.place(file, line)),
+                                None => println!("There's no code on or after {}", self.place(file, line)),
+                            }
+                        },
+                        Some(Err(err)) => println!("{}", err),
+                        None => println!("break [file:]<line>"),
+                    },
+                    "d" | "delete" => match argument.map(|place| self.parse_place(place)) {
+                        Some(Ok((file, line))) => if !self.vm.debugger.as_mut().unwrap().remove_breakpoint(file, line) {
+                            println!("There's no breakpoint on {}", self.place(file, line));
+                        },
+                        Some(Err(err)) => println!("{}", err),
+                        None => println!("delete [file:]<line>"),
+                    },
+                    "bl" | "breakpoints" => {
+                        for (file, line) in self.vm.debugger.as_ref().unwrap().breakpoints() {
+                            println!("  {}", self.place(*file, *line));
+                        }
+                    },
+                    "bt" | "frames" => {
+                        for (i, frame) in self.vm.stack_frames().iter().enumerate() {
+                            match &frame.file {
+                                Some(file) => println!("  #{} '{}' on line {} of '{}'", i, frame.name, frame.line, file),
+                                None => println!("  #{} '{}' on line {}", i, frame.name, frame.line),
+                            }
+                        }
+                    },
+                    "l" | "locals" => {
+                        let frame = argument.and_then(|frame| frame.parse::<usize>().ok()).unwrap_or(0);
+                        for (name, value) in self.vm.frame_locals(frame) {
+                            DebugConsole::show_variable(&name, &value);
+                        }
+                    },
+                    "p" | "print" => match argument {
+                        Some(name) => {
+                            let local = self.vm.frame_locals(0).into_iter().rev().find(|(local, _)| local == name);
+
+                            match local.map(|(_, value)| value).or_else(|| self.vm.get_global(name)) {
+                                Some(value) => DebugConsole::show_variable(name, &value),
+                                None => println!("There's no variable by the name of '{}'", name),
+                            }
+                        },
+                        None => println!("print <name>"),
+                    },
+                    "g" | "globals" => {
+                        for (name, value) in self.vm.globals() {
+                            DebugConsole::show_variable(name, value);
+                        }
+                    },
+                    "list" => self.show_source(5),
+                    "q" | "quit" => return !finished,
+                    "h" | "help" => println!("{}", HELP),
+                    _ => println!("Unknown command '{}', type 'help' for the commands.", command),
+                }
+            }
+
+            state = self.vm.continue_running();
+        }
+    }
+
+    fn show_variable(name: &str, value: &Value) {
+        println!("  {}: {} = {}", name, value.type_to_string(), value.string_s());
+    }
+
+    fn current_line(&self) -> usize {
+        return self.vm.chunk.line_at(self.vm.idx);
+    }
+
+    fn current_file(&self) -> u32 {
+        return self.vm.chunk.file_index_at(self.vm.idx);
+    }
+
+    // Either a line in the current file or 'file:line'.
+    fn parse_place(&self, place: &str) -> Result<(u32, usize), String> {
+        let (file, line) = match place.rsplit_once(':') {
+            Some((name, line)) => match self.vm.chunk.source_index(name) {
+                Some(file) => (file, line),
+                None => return Err(format!("There's no file '{}' in the script", name)),
+            },
+            None => (self.current_file(), place),
+        };
+
+        return match line.parse::<usize>() {
+            Ok(line) => Ok((file, line)),
+            Err(_) => Err(format!("'{}' isn't a line", line)),
+        };
+    }
+
+    fn place(&self, file: u32, line: usize) -> String {
+        return match self.vm.chunk.sources.get(file as usize) {
+            Some(name) => format!("line {} of '{}'", line, name),
+            None => format!("line {}", line),
+        };
+    }
+
+    fn current_source(&self) -> &[String] {
+        return match self.sources.get(self.current_file() as usize) {
+            Some(source) => source,
+            None => &[],
+        };
+    }
+
+    fn show_line(&self) {
+        let line = self.current_line();
+
+        match self.current_source().get(line.wrapping_sub(1)) {
+            Some(text) => println!("{:>4} | {}", line, text),
+            None => println!("Paused on {}", self.place(self.current_file(), line)),
+        }
+    }
+
+    fn show_source(&self, around: usize) {
+        let line = self.current_line();
+        let source = self.current_source();
+        let first = line.saturating_sub(around).max(1);
+
+        for number in first..=(line + around).min(source.len()) {
+            let marker = if number == line { ">" } else { " " };
+            println!("{}{:>4} | {}", marker, number, source[number - 1]);
+        }
+    }
+}
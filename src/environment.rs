@@ -1,10 +1,8 @@
 use std::{fmt};
-use crate::{value::{ Value, ClassType }};
+use crate::{error::RuntimeError, value::{ Value, ClassType }};
 
 #[derive(Clone)]
 pub enum EnvEntry {
-    Function(String, ClassType, usize, usize),
-    NativeFunction(String, usize, &'static dyn Fn(Vec<Value>) -> Value),
     Variable(String, Value, ClassType, usize),
     Structure(CopperStruct),
 }
@@ -25,48 +23,37 @@ impl CopperStruct {
         }
     }
 
-    pub fn set(&mut self, names: Vec<String>, value: Value) {
-        let mut structure = &mut self.clone();
+    // Sets the field at the end of the path, going through each nested structure on the way.
+    pub fn set(&mut self, names: &[String], value: Value) -> Result<(), RuntimeError> {
+        let (name, rest) = match names.split_first() {
+            Some(split) => split,
+            None => return Err("Cannot set a field on a structure without a name!".into()),
+        };
 
-        for i in 0..names.len()-1 {
-            let name = names[i].clone();
+        let index = match self.field_names.iter().position(|field| field == name) {
+            Some(index) => index,
+            None => return Err(format!("Cannot set fields '{:?}' on structure as it doesn't exist!", names).into()),
+        };
 
-            for s in 0..structure.field_names.len() {
-                if structure.field_names[s] == name {
-                    let value = &mut structure.field_values[s];
-                    match value {
-                        Value::Struct(cs) => {
-                            structure = cs;
-                            break;
-                        }
-                        _ => panic!("Expected to find a structure"),
-                    }
-                }
-            }
+        if rest.len() == 0 {
+            self.field_values[index] = value;
+            return Ok(());
         }
 
-        for i in 0..structure.field_names.len() {
-            if structure.field_names[i] == names[names.len()-1] {
-                structure.field_values[i] = value.clone();
-                *self = structure.clone();
-                return;
-            }
+        match &mut self.field_values[index] {
+            Value::Struct(cs) => return cs.set(rest, value),
+            _ => return Err(format!("Expected '{}' to be a structure", name).into()),
         }
-
-
-        panic!("Cannot set fields '{:?}' on structure as it doesn't exist!", names);
     }
 
-    pub fn get(&self, name: String) -> Value {
-        if self.field_names.contains(&name) {
-            for i in 0..self.field_names.len() {
-                if self.field_names[i] == name.clone() {
-                    return self.field_values[i].clone();
-                }
+    pub fn get(&self, name: &str) -> Result<Value, RuntimeError> {
+        for i in 0..self.field_names.len() {
+            if self.field_names[i] == name {
+                return Ok(self.field_values[i].clone());
             }
         }
 
-        panic!("Cannot get field '{}' on structure as it doesn't exist!", name);
+        return Err(format!("Cannot get field '{}' on structure as it doesn't exist!", name).into());
     }
 
     pub fn insert(&mut self, name: String, value: Value) {
@@ -85,75 +72,152 @@ impl CopperStruct {
 }
 
 
-// Designed to hold entries like variables, functions, and structures for 
+// A variable that had its slot or global index worked out when generating code.
+#[derive(Debug, Clone)]
+pub struct Variable {
+    pub name: String,
+    pub value: Value,
+    pub ctype: ClassType,
+    pub scope: usize,
+}
+
+// Designed to hold entries like variables and structures for 
 // the vm and chunk.
 #[derive(Clone)]
 pub struct Environment {
     pub entries: Vec<EnvEntry>,
     pub current_scope: usize,
+
+    // Locals of every function being run, each function's slots start where its locals begin.
+    pub locals: Vec<Variable>,
+    // Indexed the same as the chunk's global names, 'None' until the declaration has run.
+    pub globals: Vec<Option<Variable>>,
+    // Globals the host set before the script declared them, their declarations keep the value.
+    pub seeded: Vec<usize>,
 }
 
 impl Environment {
     pub fn new() -> Self {
-        return Self { entries: Vec::new(), current_scope: 0 };
+        return Self { entries: Vec::new(), current_scope: 0, locals: Vec::new(), globals: Vec::new(), seeded: Vec::new() };
     }
 
-    pub fn add_struct(&mut self, structure: CopperStruct) {
-        self.entries.push(EnvEntry::Structure(structure));
+    pub fn define_local(&mut self, index: usize, name: String, ctype: ClassType, val: Value) -> Result<(), RuntimeError> {
+        let variable = Variable { name, value: val.coerce(&ctype)?, ctype, scope: self.current_scope };
+
+        if index < self.locals.len() {
+            self.locals[index] = variable;
+        } else if index == self.locals.len() {
+            self.locals.push(variable);
+        } else {
+            return Err(format!("Cannot define local slot {} when there are only {} locals.", index, self.locals.len()).into());
+        }
+
+        return Ok(());
     }
-    
-    pub fn add_function(&mut self, name: String, ctype: ClassType, arg_count: usize, position: usize) {
-        for i in &self.entries {
-            match i {
-                EnvEntry::Function(func_name, _, _, _) => if name == *func_name {
-                    for i in &self.entries {
-                        println!("ENTRY: {}", i);
-                    }
-                    panic!("Cannot assign a function with name '{}' as one already exists.", name);
-                },
-                EnvEntry::NativeFunction(func_name, _, _) => if name == *func_name {
-                    panic!("Cannot assign a function with name '{}' as one already exists.", name);
-                },
-                _ => {},
+
+    pub fn infer_local(&mut self, index: usize, name: String, val: Value) -> Result<(), RuntimeError> {
+        let ctype = val.class_type();
+        return self.define_local(index, name, ctype, val);
+    }
+
+    pub fn load_local(&self, index: usize) -> Result<Value, RuntimeError> {
+        return match self.locals.get(index) {
+            Some(variable) => Ok(variable.value.clone()),
+            None => Err(format!("Local slot {} doesn't exist.", index).into()),
+        }
+    }
+
+    pub fn assign_local(&mut self, index: usize, val: Value) -> Result<(), RuntimeError> {
+        return match self.locals.get_mut(index) {
+            Some(variable) => {
+                variable.value = val.coerce(&variable.ctype)?;
+                Ok(())
             }
+            None => Err(format!("Local slot {} doesn't exist.", index).into()),
         }
+    }
 
-        self.entries.push(EnvEntry::Function(name, ctype, arg_count, position));
+    pub fn define_global(&mut self, index: usize, name: String, ctype: ClassType, val: Value) -> Result<(), RuntimeError> {
+        if index >= self.globals.len() {
+            self.globals.resize(index + 1, None);
+        }
+
+        if let Some(position) = self.seeded.iter().position(|seeded| *seeded == index) {
+            self.seeded.swap_remove(position);
+            return Ok(());
+        }
+
+        self.globals[index] = Some(Variable { name, value: val.coerce(&ctype)?, ctype, scope: 0 });
+        return Ok(());
     }
-    
-    pub fn add_native_function(&mut self, name: String, argument: usize, func: &'static dyn Fn(Vec<Value>)  -> Value) {
-        for i in &self.entries {
-            match i {
-                EnvEntry::Function(func_name, _, _, _) => if name == *func_name {
-                    panic!("Cannot assign a function with name '{}' as one already exists.", name);
-                },
-                EnvEntry::NativeFunction(func_name, _, _) => if name == *func_name {
-                    panic!("Cannot assign a function with name '{}' as one already exists.", name);
-                },
-                _ => {},
+
+    pub fn infer_global(&mut self, index: usize, name: String, val: Value) -> Result<(), RuntimeError> {
+        let ctype = val.class_type();
+        return self.define_global(index, name, ctype, val);
+    }
+
+    // Defines a global before the script declares it, which is then left with this value.
+    pub fn seed_global(&mut self, index: usize, name: String, ctype: ClassType, val: Value) -> Result<(), RuntimeError> {
+        self.define_global(index, name, ctype, val)?;
+        self.seeded.push(index);
+        return Ok(());
+    }
+
+    pub fn load_global(&self, index: usize, name: &str) -> Result<Value, RuntimeError> {
+        return match self.globals.get(index) {
+            Some(Some(variable)) => Ok(variable.value.clone()),
+            _ => Err(format!("Couldn't get a variable by the name of '{}'", name).into()),
+        }
+    }
+
+    pub fn assign_global(&mut self, index: usize, name: &str, val: Value) -> Result<(), RuntimeError> {
+        return match self.globals.get_mut(index) {
+            Some(Some(variable)) => {
+                variable.value = val.coerce(&variable.ctype)?;
+                Ok(())
+            }
+            _ => Err(format!("Couldn't assign to a variable by the name of '{}'", name).into()),
+        }
+    }
+
+    // Globals stored by name instead of by index are the variables in scope 0.
+    pub fn get_named_global(&self, name: &str) -> Option<&Value> {
+        for entry in &self.entries {
+            if let EnvEntry::Variable(var_name, value, _, 0) = entry {
+                if var_name == name {
+                    return Some(value);
+                }
             }
         }
 
-        self.entries.push(EnvEntry::NativeFunction(name, argument, func));
+        return None;
     }
 
-    pub fn get_function(&self, name: String) -> EnvEntry {
-        for i in &self.entries {
-            match i {
-                EnvEntry::Function(func_name, _, _, _) => if name == *func_name {
-                    return i.clone();
-                },
-                EnvEntry::NativeFunction(func_name, _, _) => if name == *func_name {
-                    return i.clone();
-                },
-                _ => {},
+    // Assigns to the global if it exists, otherwise it's declared with the value's type.
+    pub fn set_named_global(&mut self, name: &str, val: Value) -> Result<(), RuntimeError> {
+        for entry in &mut self.entries {
+            if let EnvEntry::Variable(var_name, value, ctype, 0) = entry {
+                if var_name == name {
+                    *value = val.coerce(ctype)?;
+                    return Ok(());
+                }
             }
         }
 
-        panic!("Couldn't find a variable by the name of '{}'.", name);
+        let ctype = val.class_type();
+        self.entries.push(EnvEntry::Variable(name.to_string(), val, ctype, 0));
+        return Ok(());
     }
 
+    pub fn add_struct(&mut self, structure: CopperStruct) {
+        self.entries.push(EnvEntry::Structure(structure));
+    }
+    
     pub fn remove_from_scope(&mut self, scope_to_remove: usize) {
+        while self.locals.last().is_some_and(|variable| variable.scope >= scope_to_remove) {
+            self.locals.pop();
+        }
+
         let mut entries_removed = 0;
 
         for i in 0..self.entries.len() {
@@ -170,7 +234,7 @@ impl Environment {
         }
     }
     
-    pub fn add_variable(&mut self, name: String, ctype: ClassType, val: Value) {
+    pub fn add_variable(&mut self, name: String, ctype: ClassType, val: Value) -> Result<(), RuntimeError> {
         for v in 0..self.entries.len() {
             let i = &self.entries[v];
             match i {
@@ -188,26 +252,27 @@ impl Environment {
         if let EnvEntry::Variable(_, value, _, _) = &mut entry {
             match ctype.clone() {
                 ClassType::Any => {},
-                ClassType::Uint => *value = Value::Uint(val.uint_s()),
-                ClassType::Int => *value = Value::Int(val.int_s()),
-                ClassType::Decimal => *value = Value::Decimal(val.decimal_s()),
+                ClassType::Uint => *value = Value::Uint(val.uint_s()?),
+                ClassType::Int => *value = Value::Int(val.int_s()?),
+                ClassType::Decimal => *value = Value::Decimal(val.decimal_s()?),
                 ClassType::Str => *value = Value::Str(val.string_s()),
-                ClassType::Bool => *value = Value::Bool(val.bool_s()),
+                ClassType::Bool => *value = Value::Bool(val.bool_s()?),
                 ClassType::Struct(name) => {
                     if let Value::Struct(x) = value {
                         if x.name == name {
                             
                         } else {
-                            panic!("Cannot convert the value of structure to another structure that is different");
+                            return Err("Cannot convert the value of structure to another structure that is different".into());
                         }
                     } else {
-                        panic!("Cannot convert a value to the struct.");
+                        return Err("Cannot convert a value to the struct.".into());
                     }
                 }
             }
         }
 
         self.entries.push(entry);
+        return Ok(());
     }
 
     pub fn add_infer_variable(&mut self, name: String, val: Value) {
@@ -231,6 +296,7 @@ impl Environment {
             Value::Bool(_) => ClassType::Bool,
             Value::Str(_) => ClassType::Str,
             Value::Struct(cs) => ClassType::Struct(cs.name.clone()),
+            Value::Coroutine(_) => ClassType::Any,
         };
 
         let entry = EnvEntry::Variable(name, val, ctype, self.current_scope);
@@ -238,65 +304,101 @@ impl Environment {
         self.entries.push(entry);
     }
 
-    pub fn get_struct(&self, name: String) -> CopperStruct {
+    pub fn get_struct(&self, name: &str) -> Result<CopperStruct, RuntimeError> {
         for i in &self.entries {
             if let EnvEntry::Structure(structure) = i {
-                if name == *structure.name {
-                    return structure.clone();
+                if name == structure.name {
+                    return Ok(structure.clone());
                 }
             }
         }
 
-        panic!("Cannot find a struct by the name of '{}'!", name);
+        return Err(format!("Cannot find a struct by the name of '{}'!", name).into());
     }
     
-    pub fn get_variable(&self, name: String) -> EnvEntry {
+    // Names that couldn't be resolved when generating code are looked up in the function being
+    // run, from its locals and the scopes from 'frame_scope' up, and then in the globals.
+    pub fn get_variable(&self, name: &str, locals_start: usize, frame_scope: usize) -> Result<EnvEntry, RuntimeError> {
+        for variable in self.locals[locals_start..].iter().rev() {
+            if variable.name == name {
+                return Ok(EnvEntry::Variable(name.to_string(), variable.value.clone(), variable.ctype.clone(), variable.scope));
+            }
+        }
+
         for r in 0..self.current_scope+1 {
             let r = self.current_scope - r;
+
+            // Scopes under the function's own belong to its callers, other than the globals in scope 0.
+            if r < frame_scope && r != 0 {
+                continue;
+            }
+
             for i in &self.entries {
                 if let EnvEntry::Variable(var_name, _, _, scope) = i {
                     if name == *var_name && r == *scope {
-                        return i.clone();
+                        return Ok(i.clone());
                     }
                 }
             }
         }
         
-        panic!("Couldn't get a variable by the name of '{}'", &name);
+        for variable in self.globals.iter().flatten() {
+            if variable.name == name {
+                return Ok(EnvEntry::Variable(name.to_string(), variable.value.clone(), variable.ctype.clone(), 0));
+            }
+        }
+        
+        return Err(format!("Couldn't get a variable by the name of '{}'", &name).into());
     }
     
-    pub fn assign_variable(&mut self, name: String, val: Value) {
+    pub fn assign_variable(&mut self, name: &str, val: Value, locals_start: usize, frame_scope: usize) -> Result<(), RuntimeError> {
+        for variable in self.locals[locals_start..].iter_mut().rev() {
+            if variable.name == name {
+                variable.value = val.coerce(&variable.ctype)?;
+                return Ok(());
+            }
+        }
+
         for r in 0..self.current_scope+1 {
             let r = self.current_scope - r;
+
+            // Scopes under the function's own belong to its callers, other than the globals in scope 0.
+            if r < frame_scope && r != 0 {
+                continue;
+            }
+
             for i in &mut self.entries {
                 if let EnvEntry::Variable(var_name, value, ctype, scope) = i {
                     if *var_name == name && *scope == r {
                         match ctype.clone() {
                             ClassType::Any => *value = val.clone(),
-                            ClassType::Uint => *value = Value::Uint(val.uint_s()),
-                            ClassType::Int => *value = Value::Int(val.int_s()),
-                            ClassType::Decimal => *value = Value::Decimal(val.decimal_s()),
+                            ClassType::Uint => *value = Value::Uint(val.uint_s()?),
+                            ClassType::Int => *value = Value::Int(val.int_s()?),
+                            ClassType::Decimal => *value = Value::Decimal(val.decimal_s()?),
                             ClassType::Str => *value = Value::Str(val.string_s()),
-                            ClassType::Bool => *value = Value::Bool(val.bool_s()),
-                            ClassType::Struct(name) => *value = Value::Struct(val.struct_s(name)),
+                            ClassType::Bool => *value = Value::Bool(val.bool_s()?),
+                            ClassType::Struct(name) => *value = Value::Struct(val.struct_s(name)?),
                         }
-                        return;
+                        return Ok(());
                     }
                 }   
             }
         }
+
+        for variable in self.globals.iter_mut().flatten() {
+            if variable.name == name {
+                variable.value = val.coerce(&variable.ctype)?;
+                return Ok(());
+            }
+        }
+
+        return Err(format!("Couldn't assign to a variable by the name of '{}'", &name).into());
     }
 }
 
 impl fmt::Display for EnvEntry {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
-            EnvEntry::Function(name, _, _, position) => {
-                write!(f, "\tFunction: name['{}'], bytecode_position[{}]\n", name, position)
-            },
-            EnvEntry::NativeFunction(name,  _, _) => {
-                write!(f, "\tNative Function: name['{}']\n", name)
-            },
             EnvEntry::Variable(name, val, ctype, scope) => {
                 write!(f, "\tVariable: name['{}'], type[{:?}], val[{:?}], scope[{}]\n", name, ctype, val, scope)
             },
@@ -320,6 +422,10 @@ impl fmt::Display for Environment {
             write!(f, "{}", i)?;
         }
 
+        for variable in self.globals.iter().flatten() {
+            write!(f, "\tGlobal: name['{}'], type[{:?}], val[{:?}]\n", variable.name, variable.ctype, variable.value)?;
+        }
+
         write!(f, "}}")
     }
 }
\ No newline at end of file
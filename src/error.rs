@@ -0,0 +1,120 @@
+use std::fmt;
+
+use crate::{environment::CopperStruct, value::Value};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum ErrorKind {
+    // Raised by the vm itself or by a native function.
+    Runtime,
+    // Raised by a 'throw' statement that no 'catch' handled.
+    Thrown(Value),
+    // Raised when calls go past the vm's limits, scripts can't catch it. Holds the calls that
+    // were being made, innermost first.
+    StackOverflow(Vec<String>),
+    // Raised when the vm's fuel runs out. The op that would have run next is left unrun, so
+    // adding more fuel and interpreting again carries on from there.
+    OutOfFuel,
+    // Raised when the vm's deadline passes, it can be carried on from like 'OutOfFuel'.
+    Timeout,
+}
+
+// An error that happened while running a chunk, scripts can catch most of these with 'try' and 'catch'.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub line: usize,
+}
+
+impl RuntimeError {
+    pub fn new(message: String) -> Self {
+        Self { kind: ErrorKind::Runtime, message, line: 0 }
+    }
+
+    pub fn thrown(value: Value) -> Self {
+        Self { message: value.string_s(), kind: ErrorKind::Thrown(value), line: 0 }
+    }
+
+    pub fn stack_overflow(message: String, backtrace: Vec<String>) -> Self {
+        Self { kind: ErrorKind::StackOverflow(backtrace), message, line: 0 }
+    }
+
+    pub fn out_of_fuel(message: String) -> Self {
+        Self { kind: ErrorKind::OutOfFuel, message, line: 0 }
+    }
+
+    pub fn timeout(message: String) -> Self {
+        Self { kind: ErrorKind::Timeout, message, line: 0 }
+    }
+
+    // Errors from the vm's limits go straight to the host, a script that could catch them
+    // could keep itself running past the limits.
+    pub fn is_catchable(&self) -> bool {
+        return matches!(self.kind, ErrorKind::Runtime | ErrorKind::Thrown(_));
+    }
+
+    // The value a 'catch' block receives, thrown values are passed through untouched
+    // while errors from the vm become an 'Error' struct with a message and line.
+    pub fn to_value(&self) -> Value {
+        match &self.kind {
+            ErrorKind::Thrown(value) => value.clone(),
+            _ => {
+                let mut structure = CopperStruct::new("Error".to_string());
+                structure.insert("message".to_string(), Value::Str(self.message.clone()));
+                structure.insert("line".to_string(), Value::Int(self.line as i64));
+
+                Value::Struct(structure)
+            }
+        }
+    }
+}
+
+impl From<String> for RuntimeError {
+    fn from(message: String) -> Self {
+        RuntimeError::new(message)
+    }
+}
+
+impl From<&str> for RuntimeError {
+    fn from(message: &str) -> Self {
+        RuntimeError::new(message.to_string())
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.kind {
+            ErrorKind::Thrown(_) => write!(f, "[Line {}] Uncaught exception: '{}'", self.line, self.message)?,
+            ErrorKind::Runtime => write!(f, "[Line {}] Runtime error: '{}'", self.line, self.message)?,
+            ErrorKind::StackOverflow(ref backtrace) => {
+                write!(f, "[Line {}] Stack overflow: '{}'", self.line, self.message)?;
+
+                for call in backtrace {
+                    write!(f, "\n    {}", call)?;
+                }
+            },
+            ErrorKind::OutOfFuel => write!(f, "[Line {}] Out of fuel: '{}'", self.line, self.message)?,
+            ErrorKind::Timeout => write!(f, "[Line {}] Timed out: '{}'", self.line, self.message)?,
+        }
+
+        return Ok(());
+    }
+}
+
+// An error found while parsing or generating a script, before anything is run.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CompileError {
+    pub line: usize,
+    // What the error was found at, none when it was the end of the source.
+    pub at: Option<String>,
+    pub message: String,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.at {
+            Some(at) => write!(f, "[Line {}] Error at '{}': '{}'", self.line, at, self.message),
+            None => write!(f, "[Line {}] Error at end: '{}'", self.line, self.message),
+        }
+    }
+}
@@ -0,0 +1,269 @@
+use crate::tokens::{Lexer, Token};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Block {
+    Code,
+    // Only has fields in it, which are kept on the lines they were written on.
+    Struct,
+}
+
+// A token along with how it was written and what came before it in the source.
+struct Piece<'a> {
+    token: Token,
+    text: &'a str,
+    // How many newlines were between it and the token before it.
+    newlines: usize,
+    // Whether anything was between it and the token before it.
+    spaced: bool,
+}
+
+// Prints scripts back out with the same layout, braces on the line they're opened on, one
+// statement to a line and blocks indented. Comments and single blank lines are kept.
+pub struct Formatter {
+    // How many spaces each block is indented by.
+    pub indent_width: usize,
+}
+
+// What's been printed so far, and what the next token needs to know about it.
+struct Printer<'a> {
+    out: String,
+    indent_width: usize,
+    blocks: Vec<Block>,
+    // Parentheses and brackets that are open.
+    parens: usize,
+    // The depth of the parentheses around a macro call, whose arguments are kept on one line.
+    macro_call: Option<usize>,
+    // Ternaries that haven't got to their ':' yet.
+    ternaries: usize,
+    // Between 'for' and the loop's body, where ';' doesn't end the line.
+    for_header: bool,
+    // Set after 'struct', for the brace that opens its fields.
+    struct_next: bool,
+    // Set when the next token goes on a new line.
+    line_ends: bool,
+    at_line_start: bool,
+    previous: Option<(Token, &'a str)>,
+    // Set when the last token was a '-' or '!' that goes with what's after it.
+    previous_unary: bool,
+    // Set when the last token was the '!' of a macro call.
+    macro_bang: bool,
+    // Set when a comment was printed since the last token, so nothing can be put after it.
+    after_comment: bool,
+}
+
+// Whether the token can end an operand, which is what makes a '-' after it a subtraction.
+fn ends_operand(token: &Token) -> bool {
+    return matches!(token,
+        Token::Identifer(_) | Token::Str(_) | Token::Int(_) | Token::Uint(_) | Token::Decimal(_)
+        | Token::CmpTrue | Token::CmpFalse | Token::RightParen | Token::RightBracket | Token::ERROR
+    );
+}
+
+fn is_type(token: &Token) -> bool {
+    return matches!(token, Token::TypeInt | Token::TypeUint | Token::TypeDecimal | Token::TypeString | Token::TypeBool | Token::TypeAny);
+}
+
+impl<'a> Printer<'a> {
+    fn new_line(&mut self, blank: bool, extra_indent: usize) {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+
+            if blank {
+                self.out.push('\n');
+            }
+        }
+
+        let indent = (self.blocks.len() + extra_indent) * self.indent_width;
+        self.out.extend(std::iter::repeat_n(' ', indent));
+        self.at_line_start = true;
+    }
+
+    fn needs_space(&self, piece: &Piece) -> bool {
+        let previous = match &self.previous {
+            Some((previous, _)) => previous,
+            None => return false,
+        };
+
+        // Anything copper can't read, like the '$' in a macro's arguments, is left as it was.
+        if *previous == Token::ERROR || piece.token == Token::ERROR {
+            return piece.spaced;
+        }
+
+        if matches!(piece.token, Token::RightParen | Token::RightBracket | Token::Comma | Token::Semicolon | Token::Dot) {
+            return false;
+        }
+
+        if matches!(previous, Token::LeftParen | Token::LeftBracket | Token::Dot) || self.previous_unary || self.macro_bang {
+            return false;
+        }
+
+        return match &piece.token {
+            // Calls, casts like 'int(x)' and indexing.
+            Token::LeftParen | Token::LeftBracket => !(matches!(previous, Token::Identifer(_) | Token::RightParen | Token::RightBracket) || is_type(previous)),
+            Token::RightBrace => *previous != Token::LeftBrace,
+            // Macro calls are written 'name!(...)'.
+            Token::Not => !(matches!(previous, Token::Identifer(_)) && !piece.spaced && piece.text == "!"),
+            Token::Colon => self.ternaries > 0,
+            // Generators are declared with 'func*'.
+            Token::Star => *previous != Token::Func,
+            _ => true,
+        };
+    }
+
+    fn print(&mut self, piece: Piece<'a>) {
+        if piece.token == Token::Comment {
+            // Comments after something on the same line stay there.
+            if self.previous.is_some() && piece.newlines == 0 && !self.at_line_start {
+                self.out.push(' ');
+            } else {
+                let blank = piece.newlines > 1 && !matches!(self.previous, Some((Token::LeftBrace, _)));
+                self.new_line(blank, 0);
+            }
+
+            self.out.push_str(piece.text.trim_end());
+            self.line_ends = true;
+            self.at_line_start = false;
+            self.after_comment = true;
+            return;
+        }
+
+        if piece.token == Token::RightBrace {
+            self.blocks.pop();
+        }
+
+        let follows_block = matches!(self.previous, Some((Token::RightBrace, _))) && !self.after_comment;
+        let in_macro_call = self.macro_call.is_some();
+        // Goes on the same line as the '}' before it, like '} else {'.
+        let joins_block = follows_block && matches!(piece.token, Token::Else | Token::Catch | Token::Finally | Token::Semicolon | Token::Comma | Token::RightParen);
+
+        let line_ends = match piece.token {
+            // Braces stay on the line they're opened on.
+            Token::LeftBrace => self.line_ends,
+            Token::RightBrace => *self.previous.as_ref().map(|(token, _)| token).unwrap_or(&Token::LeftBrace) != Token::LeftBrace,
+            _ => self.line_ends && !joins_block,
+        };
+
+        if line_ends {
+            let blank = piece.newlines > 1 && piece.token != Token::RightBrace && !matches!(self.previous, Some((Token::LeftBrace, _)));
+            self.new_line(blank, 0);
+        } else if piece.newlines > 0 && !in_macro_call && !joins_block && piece.token != Token::LeftBrace && self.previous.is_some() {
+            // Lines broken up part way through a statement are carried on one indent further in,
+            // apart from fields which are lined up with each other.
+            let in_struct = self.blocks.last() == Some(&Block::Struct);
+            self.new_line(false, if in_struct && self.parens == 0 { 0 } else { 1 });
+        } else if !self.at_line_start && self.needs_space(&piece) {
+            self.out.push(' ');
+        }
+
+        self.out.push_str(piece.text);
+        self.at_line_start = false;
+        self.line_ends = false;
+        self.after_comment = false;
+
+        let macro_bang = piece.token == Token::Not && piece.text == "!" && !piece.spaced && matches!(self.previous, Some((Token::Identifer(_), _)));
+
+        let unary = match piece.token {
+            Token::Minus => !self.previous.as_ref().is_some_and(|(token, _)| ends_operand(token)),
+            Token::Not => piece.text == "!" && !macro_bang,
+            _ => false,
+        };
+
+        match piece.token {
+            Token::LeftBrace => {
+                let block = if self.struct_next { Block::Struct } else { Block::Code };
+                self.blocks.push(block);
+                self.struct_next = false;
+                self.line_ends = true;
+
+                if self.parens == 0 {
+                    self.for_header = false;
+                }
+            },
+            Token::RightBrace => self.line_ends = true,
+            Token::LeftParen | Token::LeftBracket => {
+                if self.macro_bang && self.macro_call.is_none() {
+                    self.macro_call = Some(self.parens);
+                }
+
+                self.parens += 1;
+            },
+            Token::RightParen | Token::RightBracket => {
+                self.parens = self.parens.saturating_sub(1);
+
+                if self.macro_call == Some(self.parens) {
+                    self.macro_call = None;
+                }
+            },
+            Token::Semicolon => self.line_ends = self.parens == 0 && !self.for_header,
+            Token::For => self.for_header = true,
+            Token::Struct => self.struct_next = true,
+            Token::QuestionMark => self.ternaries += 1,
+            Token::Colon => self.ternaries = self.ternaries.saturating_sub(1),
+            _ => {},
+        }
+
+        self.previous = Some((piece.token, piece.text));
+        self.previous_unary = unary;
+        self.macro_bang = macro_bang;
+    }
+}
+
+impl Formatter {
+    pub fn new() -> Formatter {
+        return Formatter { indent_width: 4 };
+    }
+
+    // Gives back an error instead if the lexer couldn't read all of the source, so nothing
+    // is ever lost by formatting.
+    pub fn format(&self, source: &str) -> Result<String, String> {
+        let mut lexer = Lexer::with_comments(source.to_string());
+        let mut pieces = Vec::new();
+        let mut last_end = 0;
+
+        while let Some(token) = lexer.next() {
+            let (start, end) = lexer.span();
+            let end = end.min(source.len());
+            let gap = &source[last_end..start];
+
+            if !gap.trim().is_empty() {
+                return Err(format!("Couldn't read '{}' on line {}.", gap.trim(), source[..start].matches('\n').count() + 1));
+            }
+
+            pieces.push(Piece { token, text: &source[start..end], newlines: gap.matches('\n').count(), spaced: !gap.is_empty() });
+            last_end = end;
+        }
+
+        if !source[last_end..].trim().is_empty() {
+            let line = source[..last_end].matches('\n').count() + 1;
+            return Err(format!("Couldn't read past line {}.", line));
+        }
+
+        let mut printer = Printer {
+            out: String::with_capacity(source.len()),
+            indent_width: self.indent_width,
+            blocks: Vec::new(),
+            parens: 0,
+            macro_call: None,
+            ternaries: 0,
+            for_header: false,
+            struct_next: false,
+            line_ends: false,
+            at_line_start: true,
+            previous: None,
+            previous_unary: false,
+            macro_bang: false,
+            after_comment: false,
+        };
+
+        for piece in pieces {
+            printer.print(piece);
+        }
+
+        let mut out = printer.out;
+        if !out.is_empty() {
+            out.push('\n');
+        }
+
+        return Ok(out);
+    }
+}
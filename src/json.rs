@@ -0,0 +1,375 @@
+use std::fmt;
+use std::io::{BufRead, Write};
+use std::iter::Peekable;
+use std::str::Chars;
+
+// Just enough json for the tools that talk to editors, the debug adapter and the language server.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Json>),
+    // Keys are kept in the order they were written.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut chars = text.chars().peekable();
+        let value = parse_value(&mut chars)?;
+
+        skip_whitespace(&mut chars);
+        if let Some(c) = chars.next() {
+            return Err(format!("Unexpected '{}' after the json value.", c));
+        }
+
+        return Ok(value);
+    }
+
+    // Builds an object out of keys and anything that can be turned into json.
+    pub fn object(fields: Vec<(&str, Json)>) -> Json {
+        return Json::Object(fields.into_iter().map(|(key, value)| (key.to_string(), value)).collect());
+    }
+
+    // Gives back the field of an object, or none for anything that isn't one.
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        return match self {
+            Json::Object(fields) => fields.iter().find(|(name, _)| name == key).map(|(_, value)| value),
+            _ => None,
+        };
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        return match self {
+            Json::Str(string) => Some(string),
+            _ => None,
+        };
+    }
+
+    pub fn as_f64(&self) -> Option<f64> {
+        return match self {
+            Json::Number(number) => Some(*number),
+            _ => None,
+        };
+    }
+
+    pub fn as_i64(&self) -> Option<i64> {
+        return self.as_f64().map(|number| number as i64);
+    }
+
+    pub fn as_usize(&self) -> Option<usize> {
+        return self.as_f64().filter(|number| *number >= 0.0).map(|number| number as usize);
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        return match self {
+            Json::Bool(value) => Some(*value),
+            _ => None,
+        };
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        return match self {
+            Json::Array(values) => Some(values),
+            _ => None,
+        };
+    }
+}
+
+impl From<bool> for Json {
+    fn from(value: bool) -> Json {
+        return Json::Bool(value);
+    }
+}
+
+impl From<i64> for Json {
+    fn from(value: i64) -> Json {
+        return Json::Number(value as f64);
+    }
+}
+
+impl From<usize> for Json {
+    fn from(value: usize) -> Json {
+        return Json::Number(value as f64);
+    }
+}
+
+impl From<&str> for Json {
+    fn from(value: &str) -> Json {
+        return Json::Str(value.to_string());
+    }
+}
+
+impl From<String> for Json {
+    fn from(value: String) -> Json {
+        return Json::Str(value);
+    }
+}
+
+impl From<Vec<Json>> for Json {
+    fn from(values: Vec<Json>) -> Json {
+        return Json::Array(values);
+    }
+}
+
+// Quotes the string, escaping anything json doesn't allow inside of one.
+pub fn json_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+
+    for c in string.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+
+    quoted.push('"');
+    return quoted;
+}
+
+// Reads a message with a 'Content-Length' header, like the debug adapter and language server
+// protocols send them. A message written on a line of its own is read too, so a session can be
+// played back from a file. Gives back none once the input ends.
+pub fn read_message<R: BufRead>(input: &mut R) -> Option<Json> {
+    let mut length = None;
+
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+
+        let line = line.trim();
+
+        if line.starts_with('{') && length.is_none() {
+            match Json::parse(line) {
+                Ok(message) => return Some(message),
+                Err(err) => {
+                    eprintln!("Couldn't read a message: {}", err);
+                    continue;
+                },
+            }
+        }
+
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            length = value.trim().parse::<usize>().ok();
+            continue;
+        }
+
+        // The headers end with an empty line, and the message comes after.
+        if line.is_empty() {
+            if let Some(length) = length {
+                let mut body = vec![0u8; length];
+                input.read_exact(&mut body).ok()?;
+
+                match Json::parse(&String::from_utf8_lossy(&body)) {
+                    Ok(message) => return Some(message),
+                    Err(err) => eprintln!("Couldn't read a message: {}", err),
+                }
+            }
+
+            length = None;
+        }
+    }
+}
+
+// Messages are always written with a header, which is what editors expect.
+pub fn write_message<W: Write>(output: &mut W, message: &Json) {
+    let body = message.to_string();
+
+    // Whatever is on the other end has gone away if this fails, which the next read finds out.
+    let _ = write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body);
+    let _ = output.flush();
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            // Whole numbers are written without a fraction, which is what's expected for ids.
+            Json::Number(number) => if number.fract() == 0.0 && number.abs() < 1e15 {
+                write!(f, "{}", *number as i64)
+            } else {
+                write!(f, "{}", number)
+            },
+            Json::Str(string) => write!(f, "{}", json_string(string)),
+            Json::Array(values) => {
+                write!(f, "[")?;
+
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    write!(f, "{}", value)?;
+                }
+
+                write!(f, "]")
+            },
+            Json::Object(fields) => {
+                write!(f, "{{")?;
+
+                for (i, (key, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+
+                    write!(f, "{}:{}", json_string(key), value)?;
+                }
+
+                write!(f, "}}")
+            },
+        }
+    }
+}
+
+fn skip_whitespace(chars: &mut Peekable<Chars>) {
+    while chars.peek().is_some_and(|c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+fn expect_word(chars: &mut Peekable<Chars>, word: &str, value: Json) -> Result<Json, String> {
+    for expected in word.chars() {
+        if chars.next() != Some(expected) {
+            return Err(format!("Expected '{}'.", word));
+        }
+    }
+
+    return Ok(value);
+}
+
+fn parse_value(chars: &mut Peekable<Chars>) -> Result<Json, String> {
+    skip_whitespace(chars);
+
+    return match chars.peek() {
+        Some('n') => expect_word(chars, "null", Json::Null),
+        Some('t') => expect_word(chars, "true", Json::Bool(true)),
+        Some('f') => expect_word(chars, "false", Json::Bool(false)),
+        Some('"') => Ok(Json::Str(parse_string(chars)?)),
+        Some('[') => {
+            chars.next();
+            let mut values = Vec::new();
+
+            skip_whitespace(chars);
+            if chars.peek() == Some(&']') {
+                chars.next();
+                return Ok(Json::Array(values));
+            }
+
+            loop {
+                values.push(parse_value(chars)?);
+                skip_whitespace(chars);
+
+                match chars.next() {
+                    Some(',') => continue,
+                    Some(']') => return Ok(Json::Array(values)),
+                    _ => return Err("Expected ',' or ']' in an array.".to_string()),
+                }
+            }
+        },
+        Some('{') => {
+            chars.next();
+            let mut fields = Vec::new();
+
+            skip_whitespace(chars);
+            if chars.peek() == Some(&'}') {
+                chars.next();
+                return Ok(Json::Object(fields));
+            }
+
+            loop {
+                skip_whitespace(chars);
+                let key = parse_string(chars)?;
+
+                skip_whitespace(chars);
+                if chars.next() != Some(':') {
+                    return Err("Expected ':' after a key.".to_string());
+                }
+
+                fields.push((key, parse_value(chars)?));
+                skip_whitespace(chars);
+
+                match chars.next() {
+                    Some(',') => continue,
+                    Some('}') => return Ok(Json::Object(fields)),
+                    _ => return Err("Expected ',' or '}' in an object.".to_string()),
+                }
+            }
+        },
+        Some(c) if *c == '-' || c.is_ascii_digit() => {
+            let mut number = String::new();
+
+            while chars.peek().is_some_and(|c| c.is_ascii_digit() || matches!(c, '-' | '+' | '.' | 'e' | 'E')) {
+                number.push(chars.next().unwrap());
+            }
+
+            match number.parse::<f64>() {
+                Ok(number) => Ok(Json::Number(number)),
+                Err(_) => Err(format!("'{}' isn't a number.", number)),
+            }
+        },
+        Some(c) => Err(format!("Unexpected '{}'.", c)),
+        None => Err("Expected a value.".to_string()),
+    };
+}
+
+fn parse_hex(chars: &mut Peekable<Chars>) -> Result<u32, String> {
+    let mut code = 0;
+
+    for _ in 0..4 {
+        match chars.next().and_then(|c| c.to_digit(16)) {
+            Some(digit) => code = code * 16 + digit,
+            None => return Err("Expected 4 hex digits after '\\u'.".to_string()),
+        }
+    }
+
+    return Ok(code);
+}
+
+fn parse_string(chars: &mut Peekable<Chars>) -> Result<String, String> {
+    if chars.next() != Some('"') {
+        return Err("Expected a string.".to_string());
+    }
+
+    let mut string = String::new();
+
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(string),
+            Some('\\') => match chars.next() {
+                Some('"') => string.push('"'),
+                Some('\\') => string.push('\\'),
+                Some('/') => string.push('/'),
+                Some('b') => string.push('\u{8}'),
+                Some('f') => string.push('\u{c}'),
+                Some('n') => string.push('\n'),
+                Some('r') => string.push('\r'),
+                Some('t') => string.push('\t'),
+                Some('u') => {
+                    let mut code = parse_hex(chars)?;
+
+                    // Characters past the first plane are written as two halves.
+                    if (0xD800..0xDC00).contains(&code) && chars.next() == Some('\\') && chars.next() == Some('u') {
+                        let low = parse_hex(chars)?;
+                        code = 0x10000 + ((code - 0xD800) << 10) + (low.wrapping_sub(0xDC00) & 0x3FF);
+                    }
+
+                    string.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                },
+                _ => return Err("Unknown escape in a string.".to_string()),
+            },
+            Some(c) => string.push(c),
+            None => return Err("Expected the string to end.".to_string()),
+        }
+    }
+}
@@ -0,0 +1,37 @@
+// The codebase prefers explicit returns and spelled out matches and loops over the shorter forms clippy suggests.
+#![allow(
+    clippy::needless_return,
+    clippy::len_zero,
+    clippy::comparison_to_empty,
+    clippy::single_match,
+    clippy::collapsible_match,
+    clippy::collapsible_if,
+    clippy::needless_range_loop,
+    clippy::new_without_default,
+    clippy::write_with_newline,
+    clippy::single_char_add_str,
+)]
+
+pub mod mini_macros;
+pub mod tokens;
+pub mod value;
+pub mod coroutine;
+pub mod chunk;
+pub mod bytecode;
+pub mod serialize;
+pub mod verifier;
+pub mod optimizer;
+pub mod linker;
+pub mod vm;
+pub mod trace;
+pub mod json;
+pub mod debugger;
+pub mod dap;
+pub mod lsp;
+pub mod formatter;
+pub mod linter;
+pub mod environment;
+pub mod parser;
+pub mod codegen;
+pub mod repl;
+pub mod error;
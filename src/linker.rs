@@ -0,0 +1,123 @@
+use crate::chunk::{Chunk, Function, OpCode, NO_FILE};
+use crate::environment::EnvEntry;
+
+impl Chunk {
+    // Adds another chunk's code after this one's, so it's run when the vm carries on past the
+    // end of this chunk. What the other chunk refers to is moved over into this chunk's pools,
+    // and globals and functions with the same name are shared. The code that's already here
+    // keeps its offsets, so a vm that's part way through running it isn't disturbed.
+    pub fn link(&mut self, other: &Chunk) -> Result<(), String> {
+        // Everything is checked before anything changes, so a chunk that can't be linked
+        // leaves this one as it was.
+        for function in &other.functions {
+            if let Function::Script(name, _, _, _) = function {
+                if let Some(index) = self.find_function(name) {
+                    if !matches!(self.functions[index as usize], Function::Unbound(_)) {
+                        return Err(format!("Cannot link the function '{}' as one already exists.", name));
+                    }
+                }
+            }
+        }
+
+        for entry in &other.structs.entries {
+            if let EnvEntry::Structure(structure) = entry {
+                if let Ok(existing) = self.structs.get_struct(&structure.name) {
+                    if existing.field_names != structure.field_names {
+                        return Err(format!("Cannot link the structure '{}' as one with different fields already exists.", structure.name));
+                    }
+                }
+            }
+        }
+
+        let base = self.code.len();
+
+        for entry in &other.structs.entries {
+            if let EnvEntry::Structure(structure) = entry {
+                if self.structs.get_struct(&structure.name).is_err() {
+                    self.structs.add_struct(structure.clone());
+                }
+            }
+        }
+
+        let mut functions = Vec::with_capacity(other.functions.len());
+        for function in &other.functions {
+            let index = self.function_index(function.name());
+            functions.push(index);
+
+            let unbound = matches!(self.functions[index as usize], Function::Unbound(_));
+
+            match function {
+                Function::Script(name, ctype, count, position) => self.bind_function(name.clone(), ctype.clone(), *count, base + position),
+                // Natives already bound here are kept.
+                Function::Native(name, count, func, suspends) => if unbound {
+                    if *suspends {
+                        self.bind_suspending_native_function(name.clone(), *count, *func);
+                    } else {
+                        self.bind_native_function(name.clone(), *count, *func);
+                    }
+                },
+                Function::Unbound(_) => {},
+            }
+        }
+
+        let mut globals = Vec::with_capacity(other.globals.len());
+        for name in &other.globals {
+            let index = match self.global_index(name) {
+                Some(index) => index,
+                None => {
+                    self.globals.push(name.clone());
+                    self.globals.len() - 1
+                }
+            };
+
+            globals.push(index as u32);
+        }
+
+        let mut files = Vec::with_capacity(other.sources.len());
+        for source in &other.sources {
+            let index = match self.source_index(source) {
+                Some(index) => index,
+                None => {
+                    self.sources.push(source.clone());
+                    (self.sources.len() - 1) as u32
+                }
+            };
+
+            files.push(index);
+        }
+
+        for ((op, line), file) in other.code.iter().zip(&other.lines).zip(&other.files) {
+            let op = match op.clone() {
+                OpCode::Push(index) => OpCode::Push(self.add_constant(other.constants[index as usize].clone())),
+                OpCode::Call(index) => OpCode::Call(functions[index as usize]),
+                OpCode::Jmp(at) => OpCode::Jmp(base + at),
+                OpCode::JmpIfFalse(at) => OpCode::JmpIfFalse(base + at),
+                OpCode::TryStart(at) => OpCode::TryStart(base + at),
+                OpCode::ForIter(at) => OpCode::ForIter(base + at),
+                OpCode::ArgumentStore(name, ctype) => OpCode::ArgumentStore(self.intern(other.name(name)), ctype),
+                OpCode::Store(name, ctype) => OpCode::Store(self.intern(other.name(name)), ctype),
+                OpCode::InferStore(name) => OpCode::InferStore(self.intern(other.name(name))),
+                OpCode::Load(name) => OpCode::Load(self.intern(other.name(name))),
+                OpCode::Assign(name) => OpCode::Assign(self.intern(other.name(name))),
+                OpCode::NewStruct(name) => OpCode::NewStruct(self.intern(other.name(name))),
+                OpCode::StructGet(name) => OpCode::StructGet(self.intern(other.name(name))),
+                OpCode::StructSet(path) => OpCode::StructSet(self.add_path(other.paths[path as usize].clone())),
+                OpCode::ArgumentLocal(slot, name, ctype) => OpCode::ArgumentLocal(slot, self.intern(other.name(name)), ctype),
+                OpCode::DefineLocal(slot, name, ctype) => OpCode::DefineLocal(slot, self.intern(other.name(name)), ctype),
+                OpCode::InferLocal(slot, name) => OpCode::InferLocal(slot, self.intern(other.name(name))),
+                OpCode::DefineGlobal(index, ctype) => OpCode::DefineGlobal(globals[index as usize], ctype),
+                OpCode::InferGlobal(index) => OpCode::InferGlobal(globals[index as usize]),
+                OpCode::LoadGlobal(index) => OpCode::LoadGlobal(globals[index as usize]),
+                OpCode::StoreGlobal(index) => OpCode::StoreGlobal(globals[index as usize]),
+                op => op,
+            };
+
+            self.code.push(op);
+            self.lines.push(*line);
+            self.files.push(files.get(*file as usize).copied().unwrap_or(NO_FILE));
+        }
+
+        self.encode();
+        return Ok(());
+    }
+}
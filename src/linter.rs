@@ -0,0 +1,603 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::chunk::Function;
+use crate::codegen::CopperGen;
+use crate::error::CompileError;
+use crate::mini_macros::tokenizer::MacroExpander;
+use crate::parser::{AstExpr, AstStmt, CopperParser};
+use crate::tokens::Token;
+use crate::value::ClassType;
+
+// Mistakes the linter looks for. None of them stop a script from being generated, but they're
+// usually not what was meant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Lint {
+    // A variable inside of a function or block that's never read.
+    UnusedVariable,
+    // A function that's never called by any of the scripts.
+    UnusedFunction,
+    // A variable declared with the same name as one from a scope outside of it.
+    Shadowing,
+    // Statements after a 'return', 'quit' or 'throw' in the same block.
+    Unreachable,
+    // An 'if' or 'while' condition that's known to be something other than a bool.
+    NonBoolCondition,
+    // A comparison with a variable that isn't declared anywhere it could be seen from.
+    UndeclaredName,
+    // A call to a function that isn't declared by the scripts or bound as a native.
+    UndeclaredFunction,
+}
+
+impl Lint {
+    pub const ALL: [Lint; 7] = [
+        Lint::UnusedVariable, Lint::UnusedFunction, Lint::Shadowing, Lint::Unreachable,
+        Lint::NonBoolCondition, Lint::UndeclaredName, Lint::UndeclaredFunction,
+    ];
+
+    // The name it's allowed or denied by on the command line.
+    pub fn name(&self) -> &'static str {
+        return match self {
+            Lint::UnusedVariable => "unused-variable",
+            Lint::UnusedFunction => "unused-function",
+            Lint::Shadowing => "shadowing",
+            Lint::Unreachable => "unreachable",
+            Lint::NonBoolCondition => "non-bool-condition",
+            Lint::UndeclaredName => "undeclared-name",
+            Lint::UndeclaredFunction => "undeclared-function",
+        };
+    }
+
+    pub fn from_name(name: &str) -> Option<Lint> {
+        return Lint::ALL.iter().find(|lint| lint.name() == name).copied();
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Level {
+    // Not reported at all.
+    Allow,
+    Warn,
+    // Reported as an error, so 'copper lint' fails.
+    Deny,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LintWarning {
+    pub lint: Lint,
+    pub level: Level,
+    pub file: String,
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for LintWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = if self.level == Level::Deny { "Error" } else { "Warning" };
+        write!(f, "[Line {}] {} in '{}': '{}' ({})", self.line, kind, self.file, self.message, self.lint.name())
+    }
+}
+
+// Checks scripts for common mistakes without generating or running them. Every lint is a
+// warning unless its level is changed.
+pub struct Linter {
+    // Functions scripts can call without declaring them.
+    pub natives: Vec<String>,
+    levels: HashMap<Lint, Level>,
+}
+
+// A script and everything it imports, parsed before any of it is looked at so functions and
+// globals from any of them can be used in the others.
+struct Script {
+    file: String,
+    // Each top level statement and the line it starts on.
+    stmts: Vec<(usize, AstStmt)>,
+}
+
+struct Local {
+    name: String,
+    // None when it's not known, like for a variable that's given what a native returns.
+    ctype: Option<ClassType>,
+    line: usize,
+    used: bool,
+}
+
+struct DeclaredFunction {
+    // None for generators, which give back a coroutine whatever their declared type is.
+    ctype: Option<ClassType>,
+    file: usize,
+    line: usize,
+    called: bool,
+}
+
+// What's known while walking through the scripts.
+struct Walker<'a> {
+    linter: &'a Linter,
+    files: Vec<String>,
+    file: usize,
+    line: usize,
+    // The first scope is for globals, the rest are like the vm's, only the ones of the function
+    // being walked through are kept.
+    scopes: Vec<Vec<Local>>,
+    // Every global declared by the scripts, functions can use ones declared after them.
+    globals: HashSet<String>,
+    functions: HashMap<String, DeclaredFunction>,
+    warnings: Vec<(usize, LintWarning)>,
+}
+
+fn directory_of(file: &str) -> String {
+    return match file.rfind('/') {
+        Some(index) => file[..=index].to_string(),
+        None => String::new(),
+    };
+}
+
+fn type_of_token(token: &Token) -> Option<ClassType> {
+    return match token {
+        Token::TypeInt => Some(ClassType::Int),
+        Token::TypeUint => Some(ClassType::Uint),
+        Token::TypeDecimal => Some(ClassType::Decimal),
+        Token::TypeString => Some(ClassType::Str),
+        Token::TypeBool => Some(ClassType::Bool),
+        _ => None,
+    };
+}
+
+// Finds the functions and globals declared by a statement, functions can be declared anywhere.
+fn collect_declarations(stmt: &AstStmt, top_level: bool, file: usize, line: usize, walker: &mut Walker) {
+    match stmt {
+        AstStmt::Declaration(name, _, _) | AstStmt::InferDeclaration(name, _) if top_level => {
+            walker.globals.insert(name.clone());
+        },
+        AstStmt::Function(name, ctype, _, _, body, generator) => {
+            let ctype = if *generator { None } else { Some(ctype.clone()) };
+            walker.functions.entry(name.clone()).or_insert(DeclaredFunction { ctype, file, line, called: false });
+            collect_body(body, file, line, walker);
+        },
+        AstStmt::If(_, then, otherwise) => {
+            collect_declarations(then, false, file, line, walker);
+
+            if let Some(otherwise) = otherwise {
+                collect_declarations(otherwise, false, file, line, walker);
+            }
+        },
+        AstStmt::Expr(body) | AstStmt::While(_, body) | AstStmt::ForIn(_, _, body) => collect_body(body, file, line, walker),
+        AstStmt::Try(body, _, catch_body, finally_body) => {
+            collect_body(body, file, line, walker);
+
+            for body in catch_body.iter().chain(finally_body.iter()) {
+                collect_body(body, file, line, walker);
+            }
+        },
+        _ => {},
+    }
+}
+
+fn collect_body(body: &AstExpr, file: usize, mut line: usize, walker: &mut Walker) {
+    if let AstExpr::Block(stmts) = body {
+        for stmt in stmts {
+            if let AstStmt::Line(stmt_line) = stmt {
+                line = *stmt_line;
+            }
+
+            collect_declarations(stmt, false, file, line, walker);
+        }
+    }
+}
+
+// Finds the files a statement imports, they can be imported from inside of blocks like the
+// ones macros expand to.
+fn collect_imports(stmt: &AstStmt, imports: &mut Vec<String>) {
+    match stmt {
+        AstStmt::Import(AstExpr::Literal(path)) => imports.push(path.string_s()),
+        AstStmt::If(_, then, otherwise) => {
+            collect_imports(then, imports);
+
+            if let Some(otherwise) = otherwise {
+                collect_imports(otherwise, imports);
+            }
+        },
+        AstStmt::Expr(body) | AstStmt::While(_, body) | AstStmt::ForIn(_, _, body) | AstStmt::Function(_, _, _, _, body, _) => {
+            collect_body_imports(body, imports);
+        },
+        AstStmt::Try(body, _, catch_body, finally_body) => {
+            collect_body_imports(body, imports);
+
+            for body in catch_body.iter().chain(finally_body.iter()) {
+                collect_body_imports(body, imports);
+            }
+        },
+        _ => {},
+    }
+}
+
+fn collect_body_imports(body: &AstExpr, imports: &mut Vec<String>) {
+    if let AstExpr::Block(stmts) = body {
+        for stmt in stmts {
+            collect_imports(stmt, imports);
+        }
+    }
+}
+
+impl<'a> Walker<'a> {
+    fn warn(&mut self, lint: Lint, file: usize, line: usize, message: String) {
+        let level = self.linter.level(lint);
+
+        if level != Level::Allow {
+            let warning = LintWarning { lint, level, file: self.files[file].clone(), line, message };
+            self.warnings.push((file, warning));
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.scopes.push(Vec::new());
+    }
+
+    fn end_scope(&mut self) {
+        let scope = self.scopes.pop().unwrap_or_default();
+
+        // Names starting with '_' are meant to go unused.
+        for local in scope {
+            if !local.used && !local.name.starts_with('_') {
+                self.warn(Lint::UnusedVariable, self.file, local.line, format!("Variable '{}' is never used.", local.name));
+            }
+        }
+    }
+
+    fn declare(&mut self, name: &str, ctype: Option<ClassType>, used: bool) {
+        let depth = self.scopes.len() - 1;
+
+        // Declaring a variable again in the same scope reuses it, like it does when generating.
+        if let Some(local) = self.scopes[depth].iter_mut().find(|local| local.name == name) {
+            local.ctype = ctype;
+            return;
+        }
+
+        if depth > 0 && (self.globals.contains(name) || self.scopes[1..depth].iter().flatten().any(|local| local.name == name)) {
+            self.warn(Lint::Shadowing, self.file, self.line, format!("Variable '{}' shadows one declared outside of its scope.", name));
+        }
+
+        // Globals can be read by the host, so they're never counted as unused.
+        let used = used || depth == 0;
+        self.scopes[depth].push(Local { name: name.to_string(), ctype, line: self.line, used });
+    }
+
+    fn lookup(&self, name: &str) -> Option<&Local> {
+        return self.scopes.iter().rev().flatten().find(|local| local.name == name);
+    }
+
+    fn read(&mut self, name: &str) {
+        if let Some(local) = self.scopes.iter_mut().rev().flatten().find(|local| local.name == name) {
+            local.used = true;
+        }
+    }
+
+    fn is_declared(&self, name: &str) -> bool {
+        return self.lookup(name).is_some() || self.globals.contains(name);
+    }
+
+    // The type an expression is known to have, if it can be worked out without running it.
+    fn type_of(&self, expr: &AstExpr) -> Option<ClassType> {
+        return match expr {
+            AstExpr::Literal(value) => Some(value.class_type()),
+            AstExpr::Group(expr) => self.type_of(expr),
+            AstExpr::Unary(Token::Not, _) => Some(ClassType::Bool),
+            AstExpr::Unary(_, expr) => self.type_of(expr),
+            AstExpr::Binary(left, op, right) => match op {
+                Token::Plus | Token::Minus | Token::Star | Token::Slash => {
+                    let (left, right) = (self.type_of(left), self.type_of(right));
+
+                    if left == Some(ClassType::Str) || right == Some(ClassType::Str) {
+                        Some(ClassType::Str)
+                    } else {
+                        left.filter(|ctype| matches!(ctype, ClassType::Int | ClassType::Uint | ClassType::Decimal))
+                    }
+                },
+                _ => Some(ClassType::Bool),
+            },
+            AstExpr::Variable(name) => self.lookup(name).and_then(|local| local.ctype.clone()),
+            AstExpr::Call(name, _) => self.functions.get(name).and_then(|function| function.ctype.clone()),
+            AstExpr::TypeCall(token, _) => type_of_token(token),
+            AstExpr::New(name) | AstExpr::NewCall(name, _) => Some(ClassType::Struct(name.clone())),
+            _ => None,
+        };
+    }
+
+    fn check_condition(&mut self, condition: &AstExpr) {
+        match self.type_of(condition) {
+            Some(ClassType::Bool) | Some(ClassType::Any) | None => {},
+            Some(ctype) => {
+                let message = format!("The condition is '{}' instead of 'bool'.", ctype);
+                self.warn(Lint::NonBoolCondition, self.file, self.line, message);
+            },
+        }
+    }
+
+    // Walks the statements of a block or the top level of a script, starting on the given line.
+    fn walk_stmts<'s>(&mut self, stmts: impl Iterator<Item = (Option<usize>, &'s AstStmt)>) {
+        let mut ended_by = None;
+
+        for (line, stmt) in stmts {
+            if let Some(line) = line {
+                self.line = line;
+            }
+
+            if let AstStmt::Line(line) = stmt {
+                self.line = *line;
+                continue;
+            }
+
+            // Functions and structures are declared even if they're never reached.
+            if let Some(keyword) = ended_by {
+                if !matches!(stmt, AstStmt::Function(..) | AstStmt::Struct(..)) {
+                    self.warn(Lint::Unreachable, self.file, self.line, format!("This can never be run since it comes after '{}'.", keyword));
+                    ended_by = None;
+                }
+            }
+
+            self.walk_stmt(stmt);
+
+            ended_by = match stmt {
+                AstStmt::Return(_) => Some("return"),
+                AstStmt::Quit => Some("quit"),
+                AstStmt::Throw(_) => Some("throw"),
+                _ => ended_by,
+            };
+        }
+    }
+
+    fn walk_stmt(&mut self, stmt: &AstStmt) {
+        match stmt {
+            AstStmt::Expr(expr) | AstStmt::Throw(expr) => self.walk_expr(expr),
+            AstStmt::Declaration(name, ctype, expr) => {
+                self.walk_expr(expr);
+                self.declare(name, Some(ctype.clone()), false);
+            },
+            AstStmt::InferDeclaration(name, expr) => {
+                self.walk_expr(expr);
+                let ctype = self.type_of(expr);
+                self.declare(name, ctype, false);
+            },
+            AstStmt::If(condition, then, otherwise) => {
+                self.walk_expr(condition);
+                self.check_condition(condition);
+                self.walk_stmt(then);
+
+                if let Some(otherwise) = otherwise {
+                    self.walk_stmt(otherwise);
+                }
+            },
+            AstStmt::While(condition, body) => {
+                self.walk_expr(condition);
+                self.check_condition(condition);
+                self.walk_expr(body);
+            },
+            AstStmt::ForIn(name, iterable, body) => {
+                self.walk_expr(iterable);
+
+                self.begin_scope();
+                self.declare(name, None, false);
+                self.walk_expr(body);
+                self.end_scope();
+            },
+            // Functions can only see globals and their own variables.
+            AstStmt::Function(_, _, parameters, ctypes, body, _) => {
+                let outer_scopes = self.scopes.split_off(1);
+
+                self.begin_scope();
+                for (name, ctype) in parameters.iter().zip(ctypes.iter()) {
+                    self.declare(name, Some(ctype.clone()), true);
+                }
+
+                self.walk_expr(body);
+                self.end_scope();
+
+                self.scopes.extend(outer_scopes);
+            },
+            AstStmt::Return(Some(expr)) | AstStmt::Yield(Some(expr)) => self.walk_expr(expr),
+            AstStmt::Try(body, catch_name, catch_body, finally_body) => {
+                self.walk_expr(body);
+
+                if let Some(catch_body) = catch_body {
+                    self.begin_scope();
+                    if let Some(name) = catch_name {
+                        self.declare(name, Some(ClassType::Any), false);
+                    }
+
+                    self.walk_expr(catch_body);
+                    self.end_scope();
+                }
+
+                if let Some(finally_body) = finally_body {
+                    self.walk_expr(finally_body);
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn walk_expr(&mut self, expr: &AstExpr) {
+        match expr {
+            AstExpr::Block(stmts) => {
+                self.begin_scope();
+                self.walk_stmts(stmts.iter().map(|stmt| (None, stmt)));
+                self.end_scope();
+            },
+            AstExpr::Variable(name) => self.read(name),
+            // Only the structure is read, the rest are its fields.
+            AstExpr::StructCall(left, _) => self.walk_expr(left),
+            AstExpr::Assign(target, value) => {
+                if !matches!(**target, AstExpr::Variable(_)) {
+                    self.walk_expr(target);
+                }
+
+                self.walk_expr(value);
+            },
+            AstExpr::AssignByOp(target, _, value) => {
+                self.walk_expr(target);
+                self.walk_expr(value);
+            },
+            AstExpr::Binary(left, op, right) => {
+                if matches!(op, Token::EqualEqual | Token::NotEqual | Token::Greater | Token::GreaterEqual | Token::Less | Token::LessEqual) {
+                    for side in [left, right] {
+                        if let AstExpr::Variable(name) = &**side {
+                            if !self.is_declared(name) {
+                                let message = format!("Compared with '{}', which isn't declared anywhere it can be seen.", name);
+                                self.warn(Lint::UndeclaredName, self.file, self.line, message);
+                            }
+                        }
+                    }
+                }
+
+                self.walk_expr(left);
+                self.walk_expr(right);
+            },
+            AstExpr::Ternary(condition, first, second) => {
+                self.walk_expr(condition);
+                self.walk_expr(first);
+                self.walk_expr(second);
+            },
+            AstExpr::Group(expr) | AstExpr::Unary(_, expr) | AstExpr::TypeCall(_, expr) => self.walk_expr(expr),
+            AstExpr::Call(name, arguments) => {
+                match self.functions.get_mut(name) {
+                    Some(function) => function.called = true,
+                    // Coroutines are resumed by the vm itself rather than through a function.
+                    None if name == "resume" || name == "finished" || self.linter.natives.contains(name) => {},
+                    None => {
+                        let message = format!("Cannot find a function by the name of '{}'.", name);
+                        self.warn(Lint::UndeclaredFunction, self.file, self.line, message);
+                    },
+                }
+
+                for argument in arguments {
+                    self.walk_expr(argument);
+                }
+            },
+            AstExpr::NewCall(_, arguments) => {
+                for argument in arguments {
+                    self.walk_expr(argument);
+                }
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Linter {
+    // The natives bound to the generator can be called by the scripts without being declared.
+    pub fn new(gen: &CopperGen) -> Linter {
+        let natives = gen.chunk.functions.iter().filter_map(|function| match function {
+            Function::Native(name, _, _, _) => Some(name.clone()),
+            _ => None,
+        }).collect();
+
+        return Linter { natives, levels: HashMap::new() };
+    }
+
+    pub fn set_level(&mut self, lint: Lint, level: Level) {
+        self.levels.insert(lint, level);
+    }
+
+    pub fn level(&self, lint: Lint) -> Level {
+        return self.levels.get(&lint).copied().unwrap_or(Level::Warn);
+    }
+
+    // Parses the source and anything it imports, which are found next to the file it's named after.
+    fn parse(&self, file: String, source: String, scripts: &mut Vec<Script>, errors: &mut Vec<CompileError>) {
+        let mut parser = CopperParser::new(source);
+        let mut stmts = Vec::new();
+
+        while let Some(stmt) = parser.parse() {
+            stmts.push((parser.statement_line, stmt));
+        }
+
+        errors.append(&mut parser.errors);
+
+        let mut imports = Vec::new();
+        for (_, stmt) in &stmts {
+            collect_imports(stmt, &mut imports);
+        }
+
+        let imports: Vec<String> = imports.iter().map(|path| directory_of(&file) + path.as_str()).collect();
+
+        scripts.push(Script { file, stmts });
+
+        for import in imports {
+            if !scripts.iter().any(|script| script.file == import) {
+                let source = MacroExpander::new(Vec::new()).compile_with_path(import.clone(), String::new());
+                self.parse(import, source, scripts, errors);
+            }
+        }
+    }
+
+    fn check(&self, scripts: Vec<Script>) -> Vec<LintWarning> {
+        let mut walker = Walker {
+            linter: self,
+            files: scripts.iter().map(|script| script.file.clone()).collect(),
+            file: 0,
+            line: 0,
+            scopes: vec![Vec::new()],
+            globals: HashSet::new(),
+            functions: HashMap::new(),
+            warnings: Vec::new(),
+        };
+
+        for (file, script) in scripts.iter().enumerate() {
+            for (line, stmt) in &script.stmts {
+                collect_declarations(stmt, true, file, *line, &mut walker);
+            }
+        }
+
+        for (file, script) in scripts.iter().enumerate() {
+            walker.file = file;
+            walker.walk_stmts(script.stmts.iter().map(|(line, stmt)| (Some(*line), stmt)));
+        }
+
+        let mut unused: Vec<(String, usize, usize)> = walker.functions.iter()
+            .filter(|(_, function)| !function.called)
+            .map(|(name, function)| (name.clone(), function.file, function.line))
+            .collect();
+        unused.sort_by_key(|(_, file, line)| (*file, *line));
+
+        for (name, file, line) in unused {
+            walker.warn(Lint::UnusedFunction, file, line, format!("Function '{}' is never called.", name));
+        }
+
+        walker.warnings.sort_by_key(|(file, warning)| (*file, warning.line));
+        return walker.warnings.into_iter().map(|(_, warning)| warning).collect();
+    }
+
+    // Lints source that's already had its macros expanded. The name is only used for finding
+    // imports and saying where warnings are from.
+    pub fn lint(&self, name: &str, source: &str) -> Result<Vec<LintWarning>, Vec<CompileError>> {
+        let mut scripts = Vec::new();
+        let mut errors = Vec::new();
+        self.parse(name.to_string(), source.to_string(), &mut scripts, &mut errors);
+
+        if errors.len() > 0 {
+            return Err(errors);
+        }
+
+        return Ok(self.check(scripts));
+    }
+
+    // Lints the files together, like they'd be generated into one chunk. Scripts that can't be
+    // parsed give back their errors instead.
+    pub fn lint_files(&self, files: &[String]) -> Result<Vec<LintWarning>, Vec<CompileError>> {
+        let mut scripts = Vec::new();
+        let mut errors = Vec::new();
+
+        for file in files {
+            if !scripts.iter().any(|script: &Script| script.file == *file) {
+                let source = MacroExpander::new(vec![file.clone()]).compile();
+                self.parse(file.clone(), source, &mut scripts, &mut errors);
+            }
+        }
+
+        if errors.len() > 0 {
+            return Err(errors);
+        }
+
+        return Ok(self.check(scripts));
+    }
+}
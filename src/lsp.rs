@@ -0,0 +1,860 @@
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::panic;
+
+use crate::chunk::Function;
+use crate::codegen::CopperGen;
+use crate::error::CompileError;
+use crate::json::{Json, read_message, write_message};
+use crate::parser::{AstExpr, AstStmt, CopperParser};
+use crate::tokens::{Lexer, Token};
+use crate::value::ClassType;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SymbolKind {
+    Function,
+    Struct,
+    Field,
+    Macro,
+    Variable,
+    Parameter,
+}
+
+impl SymbolKind {
+    // The numbers the protocol uses for document symbols.
+    fn symbol_kind(&self) -> i64 {
+        return match self {
+            SymbolKind::Function | SymbolKind::Macro => 12,
+            SymbolKind::Struct => 23,
+            SymbolKind::Field => 8,
+            SymbolKind::Variable | SymbolKind::Parameter => 13,
+        };
+    }
+
+    // The numbers the protocol uses for completions.
+    fn completion_kind(&self) -> i64 {
+        return match self {
+            SymbolKind::Function | SymbolKind::Macro => 3,
+            SymbolKind::Struct => 22,
+            SymbolKind::Field => 5,
+            SymbolKind::Variable | SymbolKind::Parameter => 6,
+        };
+    }
+}
+
+// Something declared in a script. Spans are byte offsets into the text that was lexed.
+struct Symbol {
+    name: String,
+    kind: SymbolKind,
+    name_span: (usize, usize),
+    // The whole declaration, like a function and its body.
+    span: (usize, usize),
+    // Where the symbol can be seen from.
+    scope: (usize, usize),
+    // What's shown when hovering over it, like 'func area(rect: any): int'.
+    detail: String,
+    // Only set for fields, the structure they're in.
+    parent: Option<String>,
+}
+
+struct Lexeme {
+    token: Token,
+    start: usize,
+    end: usize,
+    // Set for anything in a macro definition or call, which the parser never sees.
+    in_macro: bool,
+}
+
+// What the parser found out about a declaration, matched up with where the lexer found it.
+enum Declared {
+    Function(String, ClassType, Vec<String>, Vec<ClassType>, bool),
+    Variable(String, Option<ClassType>),
+}
+
+// Walks the statements in the order they were written, like the lexer finds them.
+fn collect_stmt(stmt: &AstStmt, declared: &mut Vec<Declared>) {
+    match stmt {
+        AstStmt::Expr(expr) | AstStmt::Throw(expr) | AstStmt::Import(expr) => collect_expr(expr, declared),
+        AstStmt::Declaration(name, ctype, expr) => {
+            declared.push(Declared::Variable(name.clone(), Some(ctype.clone())));
+            collect_expr(expr, declared);
+        },
+        AstStmt::InferDeclaration(name, expr) => {
+            declared.push(Declared::Variable(name.clone(), None));
+            collect_expr(expr, declared);
+        },
+        AstStmt::If(condition, then, otherwise) => {
+            collect_expr(condition, declared);
+            collect_stmt(then, declared);
+
+            if let Some(otherwise) = otherwise {
+                collect_stmt(otherwise, declared);
+            }
+        },
+        AstStmt::While(condition, body) => {
+            collect_expr(condition, declared);
+            collect_expr(body, declared);
+        },
+        AstStmt::ForIn(_, iterable, body) => {
+            collect_expr(iterable, declared);
+            collect_expr(body, declared);
+        },
+        AstStmt::Function(name, ctype, parameters, ctypes, body, generator) => {
+            declared.push(Declared::Function(name.clone(), ctype.clone(), parameters.clone(), ctypes.clone(), *generator));
+            collect_expr(body, declared);
+        },
+        AstStmt::Return(Some(expr)) | AstStmt::Yield(Some(expr)) => collect_expr(expr, declared),
+        AstStmt::Try(body, _, catch_body, finally_body) => {
+            collect_expr(body, declared);
+
+            for body in catch_body.iter().chain(finally_body.iter()) {
+                collect_expr(body, declared);
+            }
+        },
+        _ => {},
+    }
+}
+
+fn collect_expr(expr: &AstExpr, declared: &mut Vec<Declared>) {
+    match expr {
+        AstExpr::Block(stmts) => {
+            for stmt in stmts {
+                collect_stmt(stmt, declared);
+            }
+        },
+        AstExpr::Binary(left, _, right) | AstExpr::StructCall(left, right) | AstExpr::Assign(left, right) | AstExpr::AssignByOp(left, _, right) => {
+            collect_expr(left, declared);
+            collect_expr(right, declared);
+        },
+        AstExpr::Ternary(condition, first, second) => {
+            collect_expr(condition, declared);
+            collect_expr(first, declared);
+            collect_expr(second, declared);
+        },
+        AstExpr::Group(expr) | AstExpr::Unary(_, expr) | AstExpr::TypeCall(_, expr) => collect_expr(expr, declared),
+        AstExpr::Call(_, arguments) | AstExpr::NewCall(_, arguments) => {
+            for argument in arguments {
+                collect_expr(argument, declared);
+            }
+        },
+        _ => {},
+    }
+}
+
+// Everything worked out about a script each time it changes.
+struct Analysis {
+    lexemes: Vec<Lexeme>,
+    symbols: Vec<Symbol>,
+    errors: Vec<CompileError>,
+}
+
+impl Analysis {
+    fn new(text: &str) -> Analysis {
+        let mut lexemes = Vec::new();
+        let mut lexer = Lexer::new(text.to_string());
+
+        while let Some(token) = lexer.next() {
+            let (start, end) = lexer.span();
+            lexemes.push(Lexeme { token, start, end, in_macro: false });
+        }
+
+        let mut analysis = Analysis { lexemes, symbols: Vec::new(), errors: Vec::new() };
+        let source = analysis.hide_macros(text);
+
+        // The parser only gets the script the way the macro expander leaves it, but with the
+        // macros blanked out rather than expanded, so every line stays where it was.
+        let mut parser = CopperParser::new(source);
+        let mut stmts = Vec::new();
+
+        while let Some(stmt) = parser.parse() {
+            stmts.push(stmt);
+        }
+
+        let mut declared = Vec::new();
+        for stmt in &stmts {
+            collect_stmt(stmt, &mut declared);
+        }
+
+        analysis.errors = parser.errors;
+        analysis.find_declarations(text.len(), &declared);
+
+        return analysis;
+    }
+
+    fn token(&self, index: usize) -> Option<&Token> {
+        return self.lexemes.get(index).map(|lexeme| &lexeme.token);
+    }
+
+    fn identifer(&self, index: usize) -> Option<String> {
+        return match self.token(index) {
+            Some(Token::Identifer(name)) => Some(name.clone()),
+            _ => None,
+        };
+    }
+
+    // Finds the closing token for the one at 'open', skipping over any nested in between.
+    fn closing(&self, open: usize, left: &Token, right: &Token) -> Option<usize> {
+        let mut depth = 0;
+
+        for (i, lexeme) in self.lexemes.iter().enumerate().skip(open) {
+            if lexeme.token == *left {
+                depth += 1;
+            } else if lexeme.token == *right {
+                depth -= 1;
+
+                if depth == 0 {
+                    return Some(i);
+                }
+            }
+        }
+
+        return None;
+    }
+
+    fn find(&self, from: usize, token: &Token) -> Option<usize> {
+        return (from..self.lexemes.len()).find(|i| self.lexemes[*i].token == *token);
+    }
+
+    // Where the block opened by the brace at 'open' ends, or the end of the text if it doesn't.
+    fn block_end(&self, open: Option<usize>, text_end: usize) -> usize {
+        return open
+            .and_then(|open| self.closing(open, &Token::LeftBrace, &Token::RightBrace))
+            .map(|close| self.lexemes[close].end)
+            .unwrap_or(text_end);
+    }
+
+    fn add(&mut self, name: String, kind: SymbolKind, name_at: usize, span: (usize, usize), scope: (usize, usize), detail: String) {
+        let name_span = (self.lexemes[name_at].start, self.lexemes[name_at].end);
+        self.symbols.push(Symbol { name, kind, name_span, span, scope, detail, parent: None });
+    }
+
+    // Blanks out macro definitions and calls, adding the definitions as symbols. Calls ending
+    // with ';' are expanded into a block, so they're left as an empty one.
+    fn hide_macros(&mut self, text: &str) -> String {
+        let mut source = text.as_bytes().to_vec();
+        let mut i = 0;
+
+        while i < self.lexemes.len() {
+            let name = match self.identifer(i) {
+                Some(name) => name,
+                None => {
+                    i += 1;
+                    continue;
+                },
+            };
+
+            let start = self.lexemes[i].start;
+            let mut last = None;
+
+            if name == "defmacro" {
+                if let Some(macro_name) = self.identifer(i + 1) {
+                    let open = self.find(i, &Token::LeftBrace);
+                    let end = self.block_end(open, text.len());
+                    let parameters = match (self.find(i, &Token::LeftParen), self.find(i, &Token::RightParen)) {
+                        (Some(left), Some(right)) if right < open.unwrap_or(usize::MAX) => &text[self.lexemes[left].start..self.lexemes[right].end],
+                        _ => "()",
+                    };
+
+                    let detail = format!("defmacro {}{}", macro_name, parameters);
+                    self.add(macro_name, SymbolKind::Macro, i + 1, (start, end), (0, text.len()), detail);
+                    last = Some(self.lexemes.iter().rposition(|lexeme| lexeme.end <= end).unwrap_or(i));
+                }
+            } else if self.token(i + 1) == Some(&Token::Not) && self.lexemes[i + 1].start == self.lexemes[i].end && self.token(i + 2) == Some(&Token::LeftParen) {
+                let close = self.closing(i + 2, &Token::LeftParen, &Token::RightParen).unwrap_or(self.lexemes.len() - 1);
+                last = Some(if self.token(close + 1) == Some(&Token::Semicolon) { close + 1 } else { close });
+            }
+
+            let last = match last {
+                Some(last) => last,
+                None => {
+                    i += 1;
+                    continue;
+                },
+            };
+
+            let end = self.lexemes[last].end.max(start);
+            for byte in source[start..end].iter_mut() {
+                if *byte != b'\n' {
+                    *byte = b' ';
+                }
+            }
+
+            if name != "defmacro" && self.token(last) == Some(&Token::Semicolon) {
+                source[start] = b'{';
+                source[end - 1] = b'}';
+            }
+
+            for lexeme in &mut self.lexemes[i..=last] {
+                lexeme.in_macro = true;
+            }
+
+            i = last + 1;
+        }
+
+        return String::from_utf8_lossy(&source).to_string();
+    }
+
+    // Finds declarations with the lexer, which knows where they are, and takes their types from
+    // what the parser made of them.
+    fn find_declarations(&mut self, text_end: usize, declared: &[Declared]) {
+        let mut functions = declared.iter().filter(|declared| matches!(declared, Declared::Function(..)));
+        let mut variables = declared.iter().filter(|declared| matches!(declared, Declared::Variable(..)));
+        // The open braces around the token being looked at.
+        let mut braces: Vec<usize> = Vec::new();
+
+        for i in 0..self.lexemes.len() {
+            if self.lexemes[i].in_macro {
+                continue;
+            }
+
+            let start = self.lexemes[i].start;
+
+            match self.lexemes[i].token {
+                Token::LeftBrace => braces.push(i),
+                Token::RightBrace => {
+                    braces.pop();
+                },
+                Token::Func => {
+                    let generator = self.token(i + 1) == Some(&Token::Star);
+                    let at = if generator { i + 2 } else { i + 1 };
+
+                    let name = match self.identifer(at) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+
+                    let open = self.find(at, &Token::LeftBrace);
+                    let end = self.block_end(open, text_end);
+                    let close_paren = self.find(at, &Token::RightParen).unwrap_or(at);
+
+                    // Parse errors can leave functions out, they're still found but without types.
+                    let found = functions.by_ref().find(|declared| matches!(declared, Declared::Function(found, ..) if *found == name));
+
+                    let detail = match found {
+                        Some(Declared::Function(_, ctype, parameters, ctypes, generator)) => {
+                            let parameters: Vec<String> = parameters.iter().zip(ctypes).map(|(name, ctype)| format!("{}: {}", name, ctype)).collect();
+                            format!("func{} {}({}): {}", if *generator { "*" } else { "" }, name, parameters.join(", "), ctype)
+                        },
+                        _ => format!("func{} {}(...)", if generator { "*" } else { "" }, name),
+                    };
+
+                    let ctypes = match found {
+                        Some(Declared::Function(_, _, _, ctypes, _)) => ctypes.clone(),
+                        _ => Vec::new(),
+                    };
+
+                    self.add(name, SymbolKind::Function, at, (start, end), (0, text_end), detail);
+
+                    let mut parameter = 0;
+                    for j in at + 1..close_paren {
+                        if let (Some(name), Some(Token::Colon)) = (self.identifer(j), self.token(j + 1)) {
+                            let detail = match ctypes.get(parameter) {
+                                Some(ctype) => format!("{}: {}", name, ctype),
+                                None => name.clone(),
+                            };
+
+                            let name_start = self.lexemes[j].start;
+                            self.add(name, SymbolKind::Parameter, j, (name_start, self.lexemes[j].end), (name_start, end), detail);
+                            parameter += 1;
+                        }
+                    }
+                },
+                Token::Struct => {
+                    let name = match self.identifer(i + 1) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+
+                    let open = self.find(i, &Token::LeftBrace);
+                    let close = open.and_then(|open| self.closing(open, &Token::LeftBrace, &Token::RightBrace)).unwrap_or(self.lexemes.len() - 1);
+                    let end = self.lexemes[close].end;
+
+                    let fields: Vec<usize> = (i + 2..close).filter(|j| self.identifer(*j).is_some()).collect();
+                    let names: Vec<String> = fields.iter().filter_map(|j| self.identifer(*j)).collect();
+
+                    self.add(name.clone(), SymbolKind::Struct, i + 1, (start, end), (0, text_end), format!("struct {} {{ {} }}", name, names.join(", ")));
+
+                    for j in fields {
+                        let field = self.identifer(j).unwrap();
+                        let detail = format!("{}.{}", name, field);
+
+                        self.add(field, SymbolKind::Field, j, (self.lexemes[j].start, self.lexemes[j].end), (0, text_end), detail);
+                        self.symbols.last_mut().unwrap().parent = Some(name.clone());
+                    }
+                },
+                // C style for loops declare their variable without 'var'.
+                Token::Var | Token::For => {
+                    let name = match self.identifer(i + 1) {
+                        Some(name) => name,
+                        None => continue,
+                    };
+
+                    let name_start = self.lexemes[i + 1].start;
+
+                    if self.lexemes[i].token == Token::For && self.token(i + 2) == Some(&Token::In) {
+                        let end = self.block_end(self.find(i, &Token::LeftBrace), text_end);
+                        self.add(name.clone(), SymbolKind::Variable, i + 1, (start, end), (name_start, end), format!("for {}", name));
+                        continue;
+                    }
+
+                    if self.lexemes[i].token == Token::For && !matches!(self.token(i + 2), Some(Token::Colon) | Some(Token::Equal) | Some(Token::Semicolon)) {
+                        continue;
+                    }
+
+                    let detail = match variables.by_ref().find(|declared| matches!(declared, Declared::Variable(found, _) if *found == name)) {
+                        Some(Declared::Variable(_, Some(ctype))) => format!("var {}: {}", name, ctype),
+                        _ => format!("var {}", name),
+                    };
+
+                    // The loop's variable is only seen in its body.
+                    let scope_end = if self.lexemes[i].token == Token::For {
+                        self.block_end(self.find(i, &Token::LeftBrace), text_end)
+                    } else {
+                        self.block_end(braces.last().copied(), text_end)
+                    };
+
+                    let end = self.find(i, &Token::Semicolon).map(|semicolon| self.lexemes[semicolon].end).unwrap_or(self.lexemes[i + 1].end);
+                    self.add(name, SymbolKind::Variable, i + 1, (start, end), (name_start, scope_end), detail);
+                },
+                Token::Catch => {
+                    if let Some(name) = self.identifer(i + 1) {
+                        let end = self.block_end(self.find(i, &Token::LeftBrace), text_end);
+                        let name_start = self.lexemes[i + 1].start;
+                        self.add(name.clone(), SymbolKind::Variable, i + 1, (start, end), (name_start, end), format!("catch {}", name));
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+
+    // The token at or just before the offset, so the end of a word being typed counts.
+    fn lexeme_at(&self, offset: usize) -> Option<usize> {
+        return self.lexemes.iter().position(|lexeme| lexeme.start <= offset && offset <= lexeme.end && matches!(lexeme.token, Token::Identifer(_)));
+    }
+
+    // Works out which declaration the word at the offset is talking about.
+    fn resolve(&self, offset: usize) -> Option<&Symbol> {
+        let at = self.lexeme_at(offset)?;
+        let name = self.identifer(at)?;
+
+        // Macros are called like 'name!(...)', and fields are read like 'thing.field'.
+        if self.token(at + 1) == Some(&Token::Not) {
+            return self.symbols.iter().find(|symbol| symbol.kind == SymbolKind::Macro && symbol.name == name);
+        }
+
+        if at > 0 && self.token(at - 1) == Some(&Token::Dot) {
+            return self.symbols.iter().find(|symbol| symbol.kind == SymbolKind::Field && symbol.name == name);
+        }
+
+        let offset = self.lexemes[at].start;
+
+        // The innermost declaration that can be seen from here wins, then the latest one.
+        let visible = self.symbols.iter()
+            .filter(|symbol| symbol.kind != SymbolKind::Field && symbol.name == name)
+            .filter(|symbol| symbol.scope.0 <= offset && offset <= symbol.scope.1)
+            .min_by_key(|symbol| (symbol.scope.1 - symbol.scope.0, usize::MAX - symbol.name_span.0));
+
+        // Functions can use globals declared after them, since they're only run later.
+        return visible.or_else(|| self.symbols.iter().find(|symbol| symbol.kind == SymbolKind::Variable && symbol.name == name));
+    }
+}
+
+// Natives are only known by how many arguments they take.
+fn native_detail(name: &str, arg_count: usize) -> String {
+    return match arg_count {
+        1 => format!("native {}(1 argument)", name),
+        _ => format!("native {}({} arguments)", name, arg_count),
+    };
+}
+
+// An open script, kept as the editor last sent it.
+struct Document {
+    lines: Vec<String>,
+    // Byte offset of each line in the text that was lexed.
+    line_starts: Vec<usize>,
+    analysis: Analysis,
+}
+
+impl Document {
+    fn new(text: &str) -> Document {
+        // The lexer works a byte at a time, so anything past ascii is swapped for a placeholder
+        // to keep it on character boundaries. Every character then takes up a single byte.
+        let ascii: String = text.chars().map(|c| if c.is_ascii() { c } else { '_' }).collect();
+
+        let mut line_starts = vec![0];
+        line_starts.extend(ascii.match_indices('\n').map(|(i, _)| i + 1));
+
+        // The lexer panics on some unfinished scripts, like a string ending in a '\'. The panic
+        // is turned into an error instead of being printed.
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let analysis = panic::catch_unwind(|| Analysis::new(&ascii));
+        panic::set_hook(hook);
+
+        let analysis = match analysis {
+            Ok(analysis) => analysis,
+            Err(_) => {
+                let message = "The script couldn't be read, check for unfinished strings.".to_string();
+                Analysis { lexemes: Vec::new(), symbols: Vec::new(), errors: vec![CompileError { line: 1, at: None, message }] }
+            },
+        };
+
+        return Document { lines: text.split('\n').map(|line| line.to_string()).collect(), line_starts, analysis };
+    }
+
+    // Positions in the protocol count lines from 0, and characters in utf-16.
+    fn position(&self, offset: usize) -> Json {
+        let line = self.line_starts.partition_point(|start| *start <= offset).saturating_sub(1);
+        let column = offset - self.line_starts[line];
+        let character: usize = self.lines[line].chars().take(column).map(|c| c.len_utf16()).sum();
+
+        return Json::object(vec![("line", Json::from(line)), ("character", Json::from(character))]);
+    }
+
+    fn range(&self, span: (usize, usize)) -> Json {
+        return Json::object(vec![("start", self.position(span.0)), ("end", self.position(span.1))]);
+    }
+
+    fn offset(&self, position: &Json) -> Option<usize> {
+        let line = position.get("line").and_then(Json::as_usize)?;
+        let character = position.get("character").and_then(Json::as_usize)?;
+        let text = self.lines.get(line)?;
+
+        let mut units = 0;
+        let mut column = 0;
+
+        for c in text.chars() {
+            if units >= character {
+                break;
+            }
+
+            units += c.len_utf16();
+            column += 1;
+        }
+
+        return Some(self.line_starts[line] + column);
+    }
+
+    fn diagnostics(&self) -> Json {
+        let diagnostics: Vec<Json> = self.analysis.errors.iter().map(|error| {
+            let line = error.line.saturating_sub(1).min(self.lines.len() - 1);
+            let line_start = self.line_starts[line];
+
+            // The error is put on what it was found at, or the whole line if it can't be found.
+            let span = match &error.at {
+                Some(at) => match self.lines[line].find(at.as_str()) {
+                    Some(byte) => {
+                        let column = self.lines[line][..byte].chars().count();
+                        (line_start + column, line_start + column + at.chars().count())
+                    },
+                    None => (line_start, line_start + self.lines[line].chars().count()),
+                },
+                // Errors at the end of the script go after the last thing written.
+                None => {
+                    let line = (0..=line).rev().find(|line| !self.lines[*line].trim().is_empty()).unwrap_or(line);
+                    let end = self.line_starts[line] + self.lines[line].trim_end().chars().count();
+                    (end, end)
+                },
+            };
+
+            let message = match &error.at {
+                Some(at) => format!("{} at '{}'", error.message, at),
+                None => error.message.clone(),
+            };
+
+            Json::object(vec![
+                ("range", self.range(span)),
+                ("severity", Json::from(1i64)),
+                ("source", Json::from("copper")),
+                ("message", Json::from(message)),
+            ])
+        }).collect();
+
+        return Json::from(diagnostics);
+    }
+}
+
+// Speaks the language server protocol, giving editors diagnostics, hovers, completions, document
+// symbols and go to definition for copper scripts.
+pub struct LanguageServer<R: BufRead, W: Write> {
+    input: R,
+    output: W,
+    // The name and argument count of each native, for completions.
+    natives: Vec<(String, usize)>,
+    documents: HashMap<String, Document>,
+    // Set once the editor asks the server to shut down, it only waits to exit after that.
+    shut_down: bool,
+}
+
+impl<R: BufRead, W: Write> LanguageServer<R, W> {
+    // Only the natives bound to the generator are used, so they can be completed.
+    pub fn new(gen: &CopperGen, input: R, output: W) -> LanguageServer<R, W> {
+        let natives = gen.chunk.functions.iter().filter_map(|function| match function {
+            Function::Native(name, arg_count, _, _) => Some((name.clone(), *arg_count)),
+            _ => None,
+        }).collect();
+
+        return LanguageServer { input, output, natives, documents: HashMap::new(), shut_down: false };
+    }
+
+    // Handles messages until the editor says to exit or the input ends.
+    pub fn run(&mut self) {
+        while let Some(message) = read_message(&mut self.input) {
+            // Responses to anything the server asked for aren't needed.
+            let method = match message.get("method").and_then(Json::as_str) {
+                Some(method) => method.to_string(),
+                None => continue,
+            };
+
+            if !self.handle(&method, &message) {
+                break;
+            }
+        }
+    }
+
+    fn send(&mut self, fields: Vec<(&str, Json)>) {
+        let mut message = vec![("jsonrpc", Json::from("2.0"))];
+        message.extend(fields);
+
+        write_message(&mut self.output, &Json::object(message));
+    }
+
+    fn respond(&mut self, id: Json, result: Json) {
+        self.send(vec![("id", id), ("result", result)]);
+    }
+
+    fn respond_error(&mut self, id: Json, code: i64, message: String) {
+        self.send(vec![("id", id), ("error", Json::object(vec![("code", Json::from(code)), ("message", Json::from(message))]))]);
+    }
+
+    fn notify(&mut self, method: &str, params: Json) {
+        self.send(vec![("method", Json::from(method)), ("params", params)]);
+    }
+
+    fn publish_diagnostics(&mut self, uri: &str) {
+        let diagnostics = match self.documents.get(uri) {
+            Some(document) => document.diagnostics(),
+            None => Json::Array(Vec::new()),
+        };
+
+        self.notify("textDocument/publishDiagnostics", Json::object(vec![("uri", Json::from(uri)), ("diagnostics", diagnostics)]));
+    }
+
+    // Gives back false once the editor says to exit.
+    fn handle(&mut self, method: &str, message: &Json) -> bool {
+        let params = message.get("params").cloned().unwrap_or(Json::Null);
+        let id = message.get("id").cloned();
+        let uri = params.get("textDocument").and_then(|document| document.get("uri")).and_then(Json::as_str).unwrap_or("").to_string();
+
+        if method == "exit" {
+            return false;
+        }
+
+        if self.shut_down {
+            if let Some(id) = id {
+                self.respond_error(id, -32600, "The server has been shut down.".to_string());
+            }
+
+            return true;
+        }
+
+        let result = match method {
+            "initialize" => Json::object(vec![
+                ("capabilities", Json::object(vec![
+                    // The whole script is sent every time it changes.
+                    ("textDocumentSync", Json::from(1i64)),
+                    ("hoverProvider", Json::from(true)),
+                    ("definitionProvider", Json::from(true)),
+                    ("completionProvider", Json::object(vec![("triggerCharacters", Json::from(vec![Json::from(".")]))])),
+                    ("documentSymbolProvider", Json::from(true)),
+                ])),
+                ("serverInfo", Json::object(vec![("name", Json::from("copper"))])),
+            ]),
+            "shutdown" => {
+                self.shut_down = true;
+                Json::Null
+            },
+            "textDocument/didOpen" | "textDocument/didChange" => {
+                let text = match method {
+                    "textDocument/didOpen" => params.get("textDocument").and_then(|document| document.get("text")),
+                    _ => params.get("contentChanges").and_then(Json::as_array).and_then(|changes| changes.last()).and_then(|change| change.get("text")),
+                };
+
+                if let Some(text) = text.and_then(Json::as_str) {
+                    self.documents.insert(uri.clone(), Document::new(text));
+                    self.publish_diagnostics(&uri);
+                }
+
+                return true;
+            },
+            "textDocument/didClose" => {
+                self.documents.remove(&uri);
+                self.publish_diagnostics(&uri);
+                return true;
+            },
+            "textDocument/hover" => self.hover(&uri, &params),
+            "textDocument/definition" => self.definition(&uri, &params),
+            "textDocument/completion" => self.completion(&uri, &params),
+            "textDocument/documentSymbol" => self.document_symbols(&uri),
+            _ => {
+                // Notifications that aren't handled are left alone, like the protocol says.
+                if let Some(id) = id {
+                    self.respond_error(id, -32601, format!("Unsupported method '{}'.", method));
+                }
+
+                return true;
+            },
+        };
+
+        if let Some(id) = id {
+            self.respond(id, result);
+        }
+
+        return true;
+    }
+
+    // The document and the offset the request is about.
+    fn request_offset(&self, uri: &str, params: &Json) -> Option<(&Document, usize)> {
+        let document = self.documents.get(uri)?;
+        let offset = document.offset(params.get("position")?)?;
+
+        return Some((document, offset));
+    }
+
+    fn hover(&self, uri: &str, params: &Json) -> Json {
+        let (document, offset) = match self.request_offset(uri, params) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+
+        let analysis = &document.analysis;
+
+        let (detail, span) = match analysis.resolve(offset) {
+            Some(symbol) => (symbol.detail.clone(), None),
+            None => {
+                let at = match analysis.lexeme_at(offset) {
+                    Some(at) => at,
+                    None => return Json::Null,
+                };
+
+                let name = analysis.identifer(at).unwrap_or_default();
+
+                match self.natives.iter().find(|(native, _)| *native == name) {
+                    Some((name, arg_count)) => (native_detail(name, *arg_count), Some((analysis.lexemes[at].start, analysis.lexemes[at].end))),
+                    None => return Json::Null,
+                }
+            },
+        };
+
+        let mut fields = vec![("contents", Json::object(vec![
+            ("kind", Json::from("markdown")),
+            ("value", Json::from(format!("```copper\n{}\n```", detail))),
+        ]))];
+
+        if let Some(span) = span {
+            fields.push(("range", document.range(span)));
+        }
+
+        return Json::object(fields);
+    }
+
+    fn definition(&self, uri: &str, params: &Json) -> Json {
+        let (document, offset) = match self.request_offset(uri, params) {
+            Some(found) => found,
+            None => return Json::Null,
+        };
+
+        return match document.analysis.resolve(offset) {
+            Some(symbol) => Json::object(vec![("uri", Json::from(uri)), ("range", document.range(symbol.name_span))]),
+            None => Json::Null,
+        };
+    }
+
+    fn completion(&self, uri: &str, params: &Json) -> Json {
+        let mut items: Vec<Json> = Vec::new();
+        let mut seen: Vec<String> = Vec::new();
+
+        let mut item = |label: String, kind: i64, detail: String| {
+            if !seen.contains(&label) {
+                items.push(Json::object(vec![("label", Json::from(label.clone())), ("kind", Json::from(kind)), ("detail", Json::from(detail))]));
+                seen.push(label);
+            }
+        };
+
+        if let Some((document, offset)) = self.request_offset(uri, params) {
+            let analysis = &document.analysis;
+
+            // After a '.' only fields make sense.
+            let at = offset.saturating_sub(1);
+            let after_dot = analysis.lexemes.iter().any(|lexeme| lexeme.token == Token::Dot && lexeme.end == offset)
+                || analysis.lexeme_at(at).is_some_and(|i| i > 0 && analysis.token(i - 1) == Some(&Token::Dot));
+
+            for symbol in &analysis.symbols {
+                let visible = match symbol.kind {
+                    SymbolKind::Field => after_dot,
+                    SymbolKind::Variable | SymbolKind::Parameter => !after_dot && symbol.scope.0 <= offset && offset <= symbol.scope.1,
+                    _ => !after_dot,
+                };
+
+                if visible {
+                    let label = if symbol.kind == SymbolKind::Macro { format!("{}!", symbol.name) } else { symbol.name.clone() };
+                    item(label, symbol.kind.completion_kind(), symbol.detail.clone());
+                }
+            }
+
+            if after_dot {
+                return Json::from(items);
+            }
+        }
+
+        for (name, arg_count) in &self.natives {
+            item(name.clone(), 3, native_detail(name, *arg_count));
+        }
+
+        return Json::from(items);
+    }
+
+    fn document_symbol(document: &Document, symbol: &Symbol, children: Vec<Json>) -> Json {
+        let mut fields = vec![
+            ("name", Json::from(symbol.name.clone())),
+            ("detail", Json::from(symbol.detail.clone())),
+            ("kind", Json::from(symbol.kind.symbol_kind())),
+            ("range", document.range(symbol.span)),
+            ("selectionRange", document.range(symbol.name_span)),
+        ];
+
+        if !children.is_empty() {
+            fields.push(("children", Json::from(children)));
+        }
+
+        return Json::object(fields);
+    }
+
+    // Functions, structures with their fields, macros and globals.
+    fn document_symbols(&self, uri: &str) -> Json {
+        let document = match self.documents.get(uri) {
+            Some(document) => document,
+            None => return Json::Null,
+        };
+
+        let symbols = &document.analysis.symbols;
+        let end = document.line_starts.last().copied().unwrap_or(0) + document.lines.last().map(|line| line.chars().count()).unwrap_or(0);
+
+        let found: Vec<Json> = symbols.iter().filter_map(|symbol| match symbol.kind {
+            SymbolKind::Function | SymbolKind::Macro => Some(LanguageServer::<R, W>::document_symbol(document, symbol, Vec::new())),
+            SymbolKind::Struct => {
+                let fields = symbols.iter()
+                    .filter(|field| field.kind == SymbolKind::Field && field.parent.as_ref() == Some(&symbol.name) && field.span.0 >= symbol.span.0 && field.span.1 <= symbol.span.1)
+                    .map(|field| LanguageServer::<R, W>::document_symbol(document, field, Vec::new()))
+                    .collect();
+
+                Some(LanguageServer::<R, W>::document_symbol(document, symbol, fields))
+            },
+            SymbolKind::Variable if symbol.scope.1 >= end => Some(LanguageServer::<R, W>::document_symbol(document, symbol, Vec::new())),
+            _ => None,
+        }).collect();
+
+        return Json::from(found);
+    }
+}
@@ -1,36 +1,67 @@
-pub mod mini_macros;
-pub mod tokens;
-pub mod value;
-pub mod chunk;
-pub mod vm;
-pub mod environment;
-pub mod parser;
-pub mod codegen;
-
-use std::io::{Write, stdin, stdout};
-
-use codegen::CopperGen;
-use value::Value;
-use parser::CopperParser;
-use vm::VM;
+#![allow(clippy::needless_return, clippy::len_zero)]
+
+use std::io::{BufReader, Write, stdin, stdout};
+use std::sync::{Mutex, OnceLock};
+
+use copper::chunk::Chunk;
+use copper::codegen::CopperGen;
+use copper::dap::DapServer;
+use copper::lsp::LanguageServer;
+use copper::formatter::Formatter;
+use copper::linter::{Level, Lint, Linter};
+use copper::debugger::DebugConsole;
+use copper::repl::Repl;
+use copper::mini_macros::tokenizer::MacroExpander;
+use copper::value::Value;
+use copper::trace::{JsonTracer, TextTracer};
+use copper::vm::VM;
+
+// When debugging through 'copper dap' stdout is where the protocol goes, so what scripts print
+// is kept here and sent to the editor instead.
+static CAPTURED_OUTPUT: Mutex<Option<String>> = Mutex::new(None);
+
+// Gives back true if the text was captured instead of needing to be printed.
+fn capture_output(text: &str) -> bool {
+    return match CAPTURED_OUTPUT.lock().unwrap().as_mut() {
+        Some(output) => {
+            output.push_str(text);
+            true
+        },
+        None => false,
+    };
+}
+
+fn take_captured_output() -> String {
+    return CAPTURED_OUTPUT.lock().unwrap().as_mut().map(std::mem::take).unwrap_or_default();
+}
 
 // Example of making a rust function native ot copper
 fn copper_print(values: Vec<Value>) -> Value {
     let val = values[0].clone();
-    val.print();
+    if !capture_output(&val.string_s()) {
+        val.print();
+    }
 
     return Value::None;
 }
 
 fn copper_println(values: Vec<Value>) -> Value {
     let val = values[0].clone();
-    val.println();
+    if !capture_output(&format!("{}\n", val.string_s())) {
+        val.println();
+    }
 
     return Value::None;
 }
 
 fn copper_input(values: Vec<Value>) -> Value {
     let val = values[0].clone();
+
+    // Stdin is where the editor's requests come from, so there's nothing to read.
+    if capture_output(&val.string_s()) {
+        return Value::Str(String::new());
+    }
+
     val.print();
     let _ = stdout().flush();
 
@@ -48,6 +79,12 @@ fn copper_input(values: Vec<Value>) -> Value {
 
 fn copper_inputln(values: Vec<Value>) -> Value {
     let val = values[0].clone();
+
+    // Stdin is where the editor's requests come from, so there's nothing to read.
+    if capture_output(&format!("{}\n", val.string_s())) {
+        return Value::Str(String::new());
+    }
+
     val.println();
     let _ = stdout().flush();
 
@@ -74,6 +111,7 @@ fn copper_abs(values: Vec<Value>) -> Value {
         Value::Str(x) => return Value::Str(x),
         Value::Bool(_) => return Value::Bool(true),
         Value::Struct(_) => return Value::None,
+        Value::Coroutine(_) => return Value::None,
     }
 }
 
@@ -83,36 +121,407 @@ pub fn copper_type_to_string(values: Vec<Value>) -> Value {
     return Value::Str(val.type_to_string());
 }
 
+// What was given with '--arg', natives can't hold onto anything so it's kept here.
+static SCRIPT_ARGS: OnceLock<Vec<String>> = OnceLock::new();
 
-fn main() {
-    let mut cmd_args: Vec<String> = std::env::args().collect();
-    cmd_args.remove(0);
+fn script_args() -> &'static [String] {
+    return SCRIPT_ARGS.get().map(|args| args.as_slice()).unwrap_or(&[]);
+}
 
-    if cmd_args.len() == 0 {
-        //println!("copper [file names...]");
-        //return;
-        cmd_args.push(String::from("tests/test.txt"));
-    }
+// Gives back the argument at the index as a string, or none past the last one.
+fn copper_args(values: Vec<Value>) -> Value {
+    let index = match values[0] {
+        Value::Int(x) if x >= 0 => x as usize,
+        Value::Uint(x) => x as usize,
+        _ => return Value::None,
+    };
+
+    return match script_args().get(index) {
+        Some(arg) => Value::Str(arg.clone()),
+        None => Value::None,
+    };
+}
+
+fn copper_arg_count(_values: Vec<Value>) -> Value {
+    return Value::Int(script_args().len() as i64);
+}
+
+
+type Native = (&'static str, usize, &'static dyn Fn(Vec<Value>) -> Value);
+
+// Every native copper scripts can use, bound before generating or after loading a compiled chunk.
+fn natives() -> Vec<Native> {
+    return vec![
+        ("print", 1, &copper_print),
+        ("println", 1, &copper_println),
+        ("input", 1, &copper_input),
+        ("inputln", 1, &copper_inputln),
+        ("abs", 1, &copper_abs),
+        ("type_str", 1, &copper_type_to_string),
+        ("args", 1, &copper_args),
+        ("arg_count", 0, &copper_arg_count),
+    ];
+}
 
+fn new_gen() -> CopperGen {
     let mut gen = CopperGen::new();
 
+    // Rust functions can be bound as natives to copper before generating, so calls to them
+    // are resolved like any other function.
+    for (name, arg_count, func) in natives() {
+        gen.bind_native_function(name.to_string(), arg_count, func);
+    }
+
+    return gen;
+}
+
+// Exit codes for when copper can't carry on, following the ones from 'sysexits.h'.
+// Only for 'copper fmt --check' when some files aren't formatted, and 'copper lint' when
+// a denied lint was found.
+const EXIT_CHECK_FAILED: i32 = 1;
+const EXIT_USAGE: i32 = 64;
+const EXIT_COMPILE_ERROR: i32 = 65;
+const EXIT_RUNTIME_ERROR: i32 = 70;
+const EXIT_IO_ERROR: i32 = 74;
+
+const USAGE: &str = "copper                                 start the REPL
+copper [run] [options] <files...>      run scripts, or a compiled chunk
+copper check <files...>                generate and check scripts without running them
+copper debug <files...>                step through scripts with breakpoints
+copper dap                             serve the debug adapter protocol over stdin and stdout
+copper lsp                             serve the language server protocol over stdin and stdout
+copper disasm <files...>               print the bytecode of scripts or a compiled chunk
+copper expand <files...>               print scripts with their macros expanded
+copper fmt [--check] [--indent <n>] <files...>
+                                       format scripts in place, or list the ones that aren't
+copper lint [lint options] <files...>  check scripts for common mistakes
+copper compile <output> <files...>     write the generated chunk to the output
+
+options:
+  --trace        print every op as it's run to stderr
+  --trace-json   print every op as it's run to stderr as json lines
+  --no-optimize  keep the ops as they were generated, to compare with the optimized ones
+  --fuel <n>     stop the script after running n ops
+  --arg <value>  hand a value to the script, read with 'args(i)' and 'arg_count()'
+
+lint options:
+  --allow <lint>  don't report the lint
+  --warn <lint>   report the lint without failing, which they all do to begin with
+  --deny <lint>   report the lint and fail
+
+lints: unused-variable, unused-function, shadowing, unreachable, non-bool-condition,
+       undeclared-name, undeclared-function";
+
+fn usage_error(message: &str) -> ! {
+    println!("{}\n\n{}", message, USAGE);
+    std::process::exit(EXIT_USAGE);
+}
+
+enum TraceFormat {
+    Text,
+    Json,
+}
+
+// Options for running scripts, they can go anywhere after the command.
+struct Options {
+    trace: Option<TraceFormat>,
+    optimize: bool,
+    fuel: Option<u64>,
+    args: Vec<String>,
+    files: Vec<String>,
+}
+
+fn parse_options(cmd_args: &[String]) -> Options {
+    let mut options = Options { trace: None, optimize: true, fuel: None, args: Vec::new(), files: Vec::new() };
+    let mut iter = cmd_args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--trace" => options.trace = Some(TraceFormat::Text),
+            "--trace-json" => options.trace = Some(TraceFormat::Json),
+            "--no-optimize" => options.optimize = false,
+            "--fuel" => match iter.next().map(|fuel| fuel.parse::<u64>()) {
+                Some(Ok(fuel)) => options.fuel = Some(fuel),
+                _ => usage_error("'--fuel' needs a number of ops."),
+            },
+            "--arg" => match iter.next() {
+                Some(value) => options.args.push(value.clone()),
+                None => usage_error("'--arg' needs a value."),
+            },
+            flag if flag.starts_with("--") => usage_error(&format!("Unknown option '{}'.", flag)),
+            file => options.files.push(file.to_string()),
+        }
+    }
+
+    return options;
+}
+
+// The macro expander can't report files it can't read, so they're checked here first.
+fn check_files(files: &[String]) {
+    if files.len() == 0 {
+        usage_error("No files were given.");
+    }
+
+    for file in files {
+        if let Err(err) = std::fs::metadata(file) {
+            println!("Couldn't read '{}': {}", file, err);
+            std::process::exit(EXIT_IO_ERROR);
+        }
+    }
+}
+
+// Formats each file in place, or with '--check' only lists the ones that would change.
+fn format_files(cmd_args: &[String]) {
+    let mut formatter = Formatter::new();
+    let mut check = false;
+    let mut files = Vec::new();
+    let mut iter = cmd_args.iter();
+
+    while let Some(arg) = iter.next() {
+        match arg.as_str() {
+            "--check" => check = true,
+            "--indent" => match iter.next().map(|width| width.parse::<usize>()) {
+                Some(Ok(width)) => formatter.indent_width = width,
+                _ => usage_error("'--indent' needs a number of spaces."),
+            },
+            flag if flag.starts_with("--") => usage_error(&format!("Unknown option '{}'.", flag)),
+            file => files.push(file.to_string()),
+        }
+    }
+
+    check_files(&files);
+
+    let mut unformatted = false;
+
+    for file in files {
+        let source = match std::fs::read_to_string(&file) {
+            Ok(source) => source,
+            Err(err) => {
+                println!("Couldn't read '{}': {}", file, err);
+                std::process::exit(EXIT_IO_ERROR);
+            },
+        };
+
+        let formatted = match formatter.format(&source) {
+            Ok(formatted) => formatted,
+            Err(err) => {
+                println!("Couldn't format '{}': {}", file, err);
+                std::process::exit(EXIT_COMPILE_ERROR);
+            },
+        };
+
+        if formatted == source {
+            continue;
+        }
+
+        if check {
+            println!("{}", file);
+            unformatted = true;
+        } else if let Err(err) = std::fs::write(&file, formatted) {
+            println!("Couldn't write '{}': {}", file, err);
+            std::process::exit(EXIT_IO_ERROR);
+        }
+    }
+
+    if unformatted {
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+}
+
+// Prints what the linter finds, failing if any of it was denied.
+fn lint_files(cmd_args: &[String]) {
+    let mut linter = Linter::new(&new_gen());
+    let mut files = Vec::new();
+    let mut iter = cmd_args.iter();
+
+    while let Some(arg) = iter.next() {
+        let level = match arg.as_str() {
+            "--allow" => Level::Allow,
+            "--warn" => Level::Warn,
+            "--deny" => Level::Deny,
+            flag if flag.starts_with("--") => usage_error(&format!("Unknown option '{}'.", flag)),
+            file => {
+                files.push(file.to_string());
+                continue;
+            },
+        };
+
+        match iter.next() {
+            Some(name) => match Lint::from_name(name) {
+                Some(lint) => linter.set_level(lint, level),
+                None => usage_error(&format!("Unknown lint '{}'.", name)),
+            },
+            None => usage_error(&format!("'{}' needs the name of a lint.", arg)),
+        }
+    }
+
+    check_files(&files);
+
+    let warnings = match linter.lint_files(&files) {
+        Ok(warnings) => warnings,
+        Err(errors) => {
+            for error in errors {
+                println!("{}", error);
+            }
+
+            std::process::exit(EXIT_COMPILE_ERROR);
+        },
+    };
+
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+
+    if warnings.iter().any(|warning| warning.level == Level::Deny) {
+        std::process::exit(EXIT_CHECK_FAILED);
+    }
+}
+
+fn generate(files: Vec<String>, optimize: bool) -> Chunk {
+    check_files(&files);
+
+    let mut gen = new_gen();
+    gen.optimize = optimize;
+
     /* This is an example of adding new source code to your scripts to have.
        *   gen.add_content(" println(\"Hello, World!\"); "); 
     */
 
-    let mut new_chunk = gen.generate_chunk(cmd_args);
+    let chunk = gen.generate_chunk(files);
 
-    // Chunks can bind functions that are native to rust to copper
-    new_chunk.bind_native_function("print".to_string(), 1, &copper_print);
-    new_chunk.bind_native_function("println".to_string(), 1, &copper_println);
-    new_chunk.bind_native_function("input".to_string(), 1, &copper_input);
-    new_chunk.bind_native_function("inputln".to_string(), 1, &copper_inputln);
-    new_chunk.bind_native_function("abs".to_string(), 1, &copper_abs);
-    new_chunk.bind_native_function("type_str".to_string(), 1, &copper_type_to_string);
+    if gen.had_error {
+        std::process::exit(EXIT_COMPILE_ERROR);
+    }
 
-    //new_chunk.disassemble();
+    return chunk;
+}
 
-    let mut vm = VM::new(&new_chunk);
+// Compiled chunks only know the names of the natives they need, so they're bound again here.
+fn load(path: &str) -> Chunk {
+    let mut chunk = match Chunk::load(path) {
+        Ok(chunk) => chunk,
+        Err(err) => {
+            println!("Couldn't load '{}': {}", path, err);
+            std::process::exit(EXIT_COMPILE_ERROR);
+        }
+    };
 
-    vm.interpret();
+    for (name, arg_count, func) in natives() {
+        if chunk.unbound_functions().contains(&name) {
+            chunk.bind_native_function(name.to_string(), arg_count, func);
+        }
+    }
+
+    let missing = chunk.unbound_functions();
+    if missing.len() != 0 {
+        println!("Couldn't load '{}': it needs the natives {:?} which aren't bound", path, missing);
+        std::process::exit(EXIT_COMPILE_ERROR);
+    }
+
+    // Compiled chunks could have come from anywhere, so they're checked before being run.
+    if let Err(err) = chunk.verify() {
+        println!("Couldn't load '{}': {}", path, err);
+        std::process::exit(EXIT_COMPILE_ERROR);
+    }
+
+    return chunk;
+}
+
+// A single file can be a compiled chunk instead of a script.
+// Compiled chunks are loaded as they were saved, whether they were optimized or not.
+fn load_or_generate(files: Vec<String>, optimize: bool) -> Chunk {
+    let compiled = files.len() == 1 && match std::fs::read(&files[0]) {
+        Ok(bytes) => Chunk::is_compiled(&bytes),
+        Err(_) => false,
+    };
+
+    return if compiled { load(&files[0]) } else { generate(files, optimize) };
+}
+
+fn run(options: Options) {
+    let chunk = load_or_generate(options.files, options.optimize);
+    let _ = SCRIPT_ARGS.set(options.args);
+
+    let mut vm = VM::new(chunk);
+    vm.tracer = match options.trace {
+        Some(TraceFormat::Text) => Some(Box::new(TextTracer::stderr())),
+        Some(TraceFormat::Json) => Some(Box::new(JsonTracer::stderr())),
+        None => None,
+    };
+    vm.fuel = options.fuel;
+
+    if let Err(error) = vm.interpret() {
+        println!("{}", error);
+        std::process::exit(EXIT_RUNTIME_ERROR);
+    }
+}
+
+fn main() {
+    let cmd_args: Vec<String> = std::env::args().skip(1).collect();
+
+    // Without any files the code is typed in instead.
+    if cmd_args.len() == 0 {
+        Repl::new(new_gen()).run();
+        return;
+    }
+
+    match cmd_args[0].as_str() {
+        "run" => run(parse_options(&cmd_args[1..])),
+        "check" => {
+            let options = parse_options(&cmd_args[1..]);
+            let chunk = generate(options.files, options.optimize);
+
+            if let Err(err) = chunk.verify() {
+                println!("{}", err);
+                std::process::exit(EXIT_COMPILE_ERROR);
+            }
+        },
+        "debug" => {
+            let options = parse_options(&cmd_args[1..]);
+            let chunk = load_or_generate(options.files, options.optimize);
+
+            if !DebugConsole::new(chunk).run() {
+                std::process::exit(EXIT_RUNTIME_ERROR);
+            }
+        },
+        "dap" => {
+            *CAPTURED_OUTPUT.lock().unwrap() = Some(String::new());
+
+            let mut server = DapServer::new(new_gen(), BufReader::new(stdin()), stdout());
+            server.take_output = Some(take_captured_output);
+            server.run();
+        },
+        "lsp" => LanguageServer::new(&new_gen(), stdin().lock(), stdout()).run(),
+        "disasm" => {
+            let options = parse_options(&cmd_args[1..]);
+            load_or_generate(options.files, options.optimize).disassemble();
+        },
+        "expand" => {
+            let files = parse_options(&cmd_args[1..]).files;
+            check_files(&files);
+
+            for file in files {
+                println!("{}", MacroExpander::new(vec![file]).compile());
+            }
+        },
+        "fmt" => format_files(&cmd_args[1..]),
+        "lint" => lint_files(&cmd_args[1..]),
+        // Writes the generated chunk to the output so it can be run later without generating it again.
+        "compile" => {
+            let mut options = parse_options(&cmd_args[1..]);
+            if options.files.len() < 2 {
+                usage_error("copper compile <output> <files...>");
+            }
+
+            let output = options.files.remove(0);
+            let chunk = generate(options.files, options.optimize);
+
+            if let Err(err) = chunk.save(&output) {
+                println!("Couldn't write '{}': {}", output, err);
+                std::process::exit(EXIT_IO_ERROR);
+            }
+        },
+        "help" | "--help" | "-h" => println!("{}", USAGE),
+        _ => run(parse_options(&cmd_args)),
+    }
 }
@@ -69,7 +69,7 @@ impl MacroTokenizer {
         self.end += 1;
         let chars = &self.source[self.start..self.end];
         
-        while !self.at_end() {
+        if !self.at_end() {
             match chars {
                 " " | "\t" | "\r" => {
                     return Some(MiniToken::Space)
@@ -87,7 +87,7 @@ impl MacroTokenizer {
                 "\n" => {
                     return Some(MiniToken::NewLine);
                 }
-                _ => break,
+                _ => {},
             }
         }
 
@@ -305,16 +305,8 @@ impl MacroDefs {
         if !self.check(MiniToken::RightParen) {
             loop {
                 let mut argument: Vec<MiniToken> = Vec::new();
-                while match self.current_token {
-                    MiniToken::Rest(_) => true,
-                    MiniToken::Space => true,
-                    MiniToken::NewLine => true,
-                    _ => false,
-                } {
-                    argument.push(match self.advance() {
-                        Some(x) => x,
-                        None => return None,
-                    });
+                while matches!(self.current_token, MiniToken::Rest(_) | MiniToken::Space | MiniToken::NewLine) {
+                    argument.push(self.advance()?);
                 }
 
 
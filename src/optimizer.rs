@@ -0,0 +1,263 @@
+use crate::chunk::{Chunk, Function, OpCode};
+use crate::value::Value;
+
+impl Chunk {
+    // Shrinks the generated ops without changing what they do. Each pass only marks ops to
+    // remove, and the ops are then compacted with every position moved to match. Passes are
+    // run until none of them find anything, since one pass can open things up for another.
+    pub fn optimize(&mut self) {
+        loop {
+            let mut changed = self.thread_jumps();
+
+            for pass in [Chunk::fold_constants, Chunk::remove_unreachable, Chunk::remove_empty_scopes, Chunk::remove_jumps_to_next] {
+                let mut removed = vec![false; self.code.len()];
+
+                if pass(self, &mut removed) {
+                    self.compact(&removed);
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+    }
+
+    // Positions something other than the op before it can continue from.
+    fn targets(&self) -> Vec<bool> {
+        let mut targets = vec![false; self.code.len() + 1];
+
+        for op in &self.code {
+            match op {
+                OpCode::Jmp(at) | OpCode::JmpIfFalse(at) | OpCode::TryStart(at) | OpCode::ForIter(at) => targets[*at] = true,
+                _ => {},
+            }
+        }
+
+        for function in &self.functions {
+            if let Function::Script(_, _, _, position) = function {
+                targets[*position] = true;
+            }
+        }
+
+        return targets;
+    }
+
+    // Jumps that land on an unconditional jump go straight to where that one goes.
+    fn thread_jumps(&mut self) -> bool {
+        let mut changed = false;
+
+        for i in 0..self.code.len() {
+            let at = match self.code[i] {
+                OpCode::Jmp(at) | OpCode::JmpIfFalse(at) => at,
+                _ => continue,
+            };
+
+            // Following at most as many jumps as there are ops stops a loop of jumps from hanging.
+            let mut to = at;
+            let mut followed = 0;
+            while let Some(OpCode::Jmp(next)) = self.code.get(to) {
+                if *next == to || followed > self.code.len() {
+                    break;
+                }
+
+                to = *next;
+                followed += 1;
+            }
+
+            if to != at {
+                match &mut self.code[i] {
+                    OpCode::Jmp(at) | OpCode::JmpIfFalse(at) => *at = to,
+                    _ => {},
+                }
+                changed = true;
+            }
+        }
+
+        return changed;
+    }
+
+    // Works out operations on constants ahead of time. Anything that would fail, like whole
+    // numbers overflowing, is left for the vm so the error still happens when it runs.
+    fn fold_constants(&mut self, removed: &mut [bool]) -> bool {
+        let targets = self.targets();
+        let mut changed = false;
+        let mut i = 0;
+
+        while i < self.code.len() {
+            let a = match self.code[i] {
+                OpCode::Push(a) => self.constants[a as usize].clone(),
+                _ => {
+                    i += 1;
+                    continue;
+                }
+            };
+
+            if i + 2 < self.code.len() && !targets[i+1] && !targets[i+2] {
+                if let OpCode::Push(b) = self.code[i+1] {
+                    let b = &self.constants[b as usize];
+
+                    let folded = match self.code[i+2] {
+                        OpCode::Add => a.add_s(b).ok(),
+                        OpCode::Sub => a.sub_s(b).ok(),
+                        OpCode::Mul => a.mul_s(b).ok(),
+                        OpCode::Div => a.div_s(b).ok(),
+                        _ => None,
+                    };
+
+                    if let Some(value) = folded {
+                        self.code[i] = OpCode::Push(self.add_constant(value));
+                        removed[i+1] = true;
+                        removed[i+2] = true;
+                        changed = true;
+                        i += 3;
+                        continue;
+                    }
+                }
+            }
+
+            if i + 1 < self.code.len() && !targets[i+1] {
+                let folded = match (&self.code[i+1], &a) {
+                    (OpCode::Negate, Value::Int(x)) => x.checked_neg().map(Value::Int),
+                    (OpCode::Negate, Value::Decimal(x)) => Some(Value::Decimal(-x)),
+                    (OpCode::Not, Value::Uint(_) | Value::Int(_) | Value::Decimal(_) | Value::Bool(_)) => match a.bool_s() {
+                        Ok(x) => Some(Value::Bool(!x)),
+                        Err(_) => None,
+                    },
+                    _ => None,
+                };
+
+                if let Some(value) = folded {
+                    self.code[i] = OpCode::Push(self.add_constant(value));
+                    removed[i+1] = true;
+                    changed = true;
+                    i += 2;
+                    continue;
+                }
+            }
+
+            i += 1;
+        }
+
+        return changed;
+    }
+
+    // Removes ops that nothing can reach, like what's after a 'return', 'quit' or a jump.
+    fn remove_unreachable(&mut self, removed: &mut [bool]) -> bool {
+        let mut reachable = vec![false; self.code.len()];
+        let mut pending = vec![0];
+
+        for function in &self.functions {
+            if let Function::Script(_, _, _, position) = function {
+                pending.push(*position);
+            }
+        }
+
+        while let Some(at) = pending.pop() {
+            if at >= self.code.len() || reachable[at] {
+                continue;
+            }
+
+            reachable[at] = true;
+
+            match self.code[at] {
+                OpCode::Return | OpCode::EndScript | OpCode::Throw => {},
+                OpCode::Jmp(to) => pending.push(to),
+                OpCode::JmpIfFalse(to) | OpCode::TryStart(to) | OpCode::ForIter(to) => {
+                    pending.push(to);
+                    pending.push(at + 1);
+                },
+                _ => pending.push(at + 1),
+            }
+        }
+
+        let mut changed = false;
+        for i in 0..self.code.len() {
+            if !reachable[i] {
+                removed[i] = true;
+                changed = true;
+            }
+        }
+
+        return changed;
+    }
+
+    // A scope that's ended right after it's started has nothing in it to clean up.
+    fn remove_empty_scopes(&mut self, removed: &mut [bool]) -> bool {
+        let targets = self.targets();
+        let mut changed = false;
+        let mut i = 0;
+
+        while i + 1 < self.code.len() {
+            if matches!(self.code[i], OpCode::StartScope) && matches!(self.code[i+1], OpCode::EndScope) && !targets[i+1] {
+                removed[i] = true;
+                removed[i+1] = true;
+                changed = true;
+                i += 2;
+            } else {
+                i += 1;
+            }
+        }
+
+        return changed;
+    }
+
+    fn remove_jumps_to_next(&mut self, removed: &mut [bool]) -> bool {
+        let mut changed = false;
+
+        for i in 0..self.code.len() {
+            if let OpCode::Jmp(at) = self.code[i] {
+                if at == i + 1 {
+                    removed[i] = true;
+                    changed = true;
+                }
+            }
+        }
+
+        return changed;
+    }
+
+    // Drops the removed ops and their lines. Positions of removed ops move to the next op
+    // that's kept, which is where running them would have ended up anyway.
+    fn compact(&mut self, removed: &[bool]) {
+        let mut new_positions = Vec::with_capacity(self.code.len() + 1);
+        let mut kept = 0;
+
+        for is_removed in removed {
+            new_positions.push(kept);
+            if !is_removed {
+                kept += 1;
+            }
+        }
+        new_positions.push(kept);
+
+        let code = std::mem::take(&mut self.code);
+        let lines = std::mem::take(&mut self.lines);
+        let files = std::mem::take(&mut self.files);
+
+        for (i, ((op, line), file)) in code.into_iter().zip(lines).zip(files).enumerate() {
+            if removed[i] {
+                continue;
+            }
+
+            let op = match op {
+                OpCode::Jmp(at) => OpCode::Jmp(new_positions[at]),
+                OpCode::JmpIfFalse(at) => OpCode::JmpIfFalse(new_positions[at]),
+                OpCode::TryStart(at) => OpCode::TryStart(new_positions[at]),
+                OpCode::ForIter(at) => OpCode::ForIter(new_positions[at]),
+                op => op,
+            };
+
+            self.code.push(op);
+            self.lines.push(line);
+            self.files.push(file);
+        }
+
+        for function in &mut self.functions {
+            if let Function::Script(_, _, _, position) = function {
+                *position = new_positions[*position];
+            }
+        }
+    }
+}
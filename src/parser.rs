@@ -1,7 +1,7 @@
 use core::fmt;
 use std::{ops::Deref};
 
-use crate::{tokens::{Lexer, Token}, value::{ClassType, Value}};
+use crate::{error::CompileError, tokens::{Lexer, Token}, value::{ClassType, Value}};
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum AstExpr {
@@ -30,11 +30,22 @@ pub enum AstStmt {
     InferDeclaration(String, AstExpr),
     If(AstExpr, Box<AstStmt>, Option<Box<AstStmt>>),
     While(AstExpr, AstExpr),
-    Function(String, ClassType, Vec<String>, Vec<ClassType>, AstExpr),
+    // The name of the loop variable, the coroutine it takes values from, and the body.
+    ForIn(String, AstExpr, AstExpr),
+    // The last field is set for generators, declared with 'func*'.
+    Function(String, ClassType, Vec<String>, Vec<ClassType>, AstExpr, bool),
     Return(Option<AstExpr>),
     Quit,
     Import(AstExpr),
     Struct(String, Vec<String>),
+    // The try body, name of the caught value, catch body, and finally body.
+    Try(AstExpr, Option<String>, Option<AstExpr>, Option<AstExpr>),
+    Throw(AstExpr),
+    // Hands control back to the host, which can carry on running the script later. Inside of
+    // a generator it hands the value back to whatever resumed it instead.
+    Yield(Option<AstExpr>),
+    // The line the statement after it in a block starts on, it doesn't do anything by itself.
+    Line(usize),
 }
 
 macro_rules! unwrap_ast {
@@ -71,7 +82,10 @@ pub struct CopperParser {
     pub current_lexer: Lexer,
     pub current_lexeme: String,
     pub current_line: usize,
+    // The line the statement given back by 'parse' starts on.
+    pub statement_line: usize,
     pub continue_parsing: bool,
+    pub errors: Vec<CompileError>,
 }
 
 impl CopperParser {
@@ -98,21 +112,17 @@ impl CopperParser {
         return self.previous_token.clone();
     }
 
+    // Errors are kept for whatever is parsing to report, since the parser doesn't know where
+    // they should go.
     fn report_error(&mut self, message: &str) {
-        print!("[Line {}] Error", self.current_line);
+        let at = if self.at_end() { None } else { Some(self.current_lexeme.clone()) };
 
-        if self.at_end() {
-            print!(" at end");
-        } else {
-            print!(" at '{}'", self.current_lexeme);
-        }
-
-        println!(": '{}'", message);
+        self.errors.push(CompileError { line: self.current_line, at, message: message.to_string() });
         self.continue_parsing = false;
     }
 
     fn at_end(&self) -> bool {
-        return self.current_token == None;
+        return self.current_token.is_none();
     }
 
     fn check(&self, token: Token) -> bool {
@@ -368,7 +378,7 @@ impl CopperParser {
     fn ternary_expr(&mut self) -> Option<AstExpr> {
         let expr = unwrap_ast!(self.or_expr());
 
-        while self.match_tokens(&[Token::QuestionMark]) {
+        if self.match_tokens(&[Token::QuestionMark]) {
             let true_expr = unwrap_ast!(self.ternary_expr());
             consume!(self, Token::Colon, "Expected ':' before false expr for ternary");
             let false_expr = unwrap_ast!(self.ternary_expr());
@@ -470,6 +480,7 @@ impl CopperParser {
         let mut stmts: Vec<AstStmt> = Vec::new();
 
         while !self.check(Token::RightBrace) && !self.at_end() {
+            stmts.push(AstStmt::Line(self.current_line));
             stmts.push(unwrap_ast!(self.declaration_stmt()));
         }
 
@@ -477,7 +488,7 @@ impl CopperParser {
         return Some(AstExpr::Block(stmts));
     }
 
-    fn function_stmt(&mut self, ftype: &str) -> Option<AstStmt> {
+    fn function_stmt(&mut self, ftype: &str, generator: bool) -> Option<AstStmt> {
         let name = self.current_lexeme.clone();
         consume!(self, Token::Identifer(self.current_lexeme.clone()), format!("Expected {} name", ftype).deref());
 
@@ -538,7 +549,7 @@ impl CopperParser {
         consume!(self, Token::LeftBrace, format!("Expected '{{' before {} body", ftype).deref());
         let block = unwrap_ast!(self.block());
 
-        return Some(AstStmt::Function(name, ctype, identifers, ctypes, block));
+        return Some(AstStmt::Function(name, ctype, identifers, ctypes, block, generator));
     }
 
     fn while_stmt(&mut self) -> Option<AstStmt> {
@@ -551,6 +562,16 @@ impl CopperParser {
     }
 
     fn for_stmt(&mut self) -> Option<AstStmt> {
+        // Looks past the loop variable to tell 'for x in' apart from a c style for loop.
+        if self.check(Token::Identifer(self.current_lexeme.clone())) {
+            let mut ahead = self.clone();
+            ahead.advance();
+
+            if ahead.check(Token::In) {
+                return self.for_in_stmt();
+            }
+        }
+
         let initializer = match self.var_declaration_stmt() {
             Some(x) => x,
             None => AstStmt::Expr(AstExpr::Nothing),
@@ -595,6 +616,19 @@ impl CopperParser {
         return Some(AstStmt::Expr(AstExpr::Block(for_body)));
     }
 
+    fn for_in_stmt(&mut self) -> Option<AstStmt> {
+        let name = self.current_lexeme.clone();
+        consume!(self, Token::Identifer(name.clone()), "Expected a name for the loop variable");
+        consume!(self, Token::In, "Expected 'in' after the loop variable");
+
+        let iterable = unwrap_ast!(self.expression());
+
+        consume!(self, Token::LeftBrace, "Expected '{' before 'for' body");
+        let body = unwrap_ast!(self.block());
+
+        return Some(AstStmt::ForIn(name, iterable, body));
+    }
+
     fn if_stmt(&mut self) -> Option<AstStmt> {
         let condition = unwrap_ast!(self.expression());
 
@@ -603,8 +637,11 @@ impl CopperParser {
         let mut else_body: Option<Box<AstStmt>> = None;
 
         if self.match_tokens(&[Token::Else]) {
+            // An 'else if' is its own block, so its condition gets the line it's written on.
             if self.match_tokens(&[Token::If]) {
-                return Some(AstStmt::If(condition, if_body, Some(Box::new(unwrap_ast!(self.if_stmt())))))
+                let line = AstStmt::Line(self.current_line);
+                let else_if = AstExpr::Block(vec![line, unwrap_ast!(self.if_stmt())]);
+                return Some(AstStmt::If(condition, if_body, Some(Box::new(AstStmt::Expr(else_if)))));
             }
             consume!(self, Token::LeftBrace, "Expected '{' after 'else'");
             else_body = Some(Box::new(AstStmt::Expr(unwrap_ast!(self.block()))));
@@ -625,6 +662,55 @@ impl CopperParser {
         return Some(AstStmt::Return(Some(expr)));
     }
 
+    fn try_stmt(&mut self) -> Option<AstStmt> {
+        consume!(self, Token::LeftBrace, "Expected '{' after 'try'");
+        let body = unwrap_ast!(self.block());
+
+        let mut catch_name: Option<String> = None;
+        let mut catch_body: Option<AstExpr> = None;
+        let mut finally_body: Option<AstExpr> = None;
+
+        if self.match_tokens(&[Token::Catch]) {
+            if self.check(Token::Identifer(self.current_lexeme.clone())) {
+                catch_name = Some(self.current_lexeme.clone());
+                self.advance();
+            }
+
+            consume!(self, Token::LeftBrace, "Expected '{' after 'catch'");
+            catch_body = Some(unwrap_ast!(self.block()));
+        }
+
+        if self.match_tokens(&[Token::Finally]) {
+            consume!(self, Token::LeftBrace, "Expected '{' after 'finally'");
+            finally_body = Some(unwrap_ast!(self.block()));
+        }
+
+        if catch_body.is_none() && finally_body.is_none() {
+            self.report_error("Expected 'catch' or 'finally' after 'try' body");
+            return None;
+        }
+
+        return Some(AstStmt::Try(body, catch_name, catch_body, finally_body));
+    }
+
+    fn throw_stmt(&mut self) -> Option<AstStmt> {
+        let expr = unwrap_ast!(self.expression());
+        consume!(self, Token::Semicolon, "Expected ';' after throw statement");
+
+        return Some(AstStmt::Throw(expr));
+    }
+
+    fn yield_stmt(&mut self) -> Option<AstStmt> {
+        if self.match_tokens(&[Token::Semicolon]) {
+            return Some(AstStmt::Yield(None));
+        }
+
+        let expr = unwrap_ast!(self.expression());
+        consume!(self, Token::Semicolon, "Expected ';' after yield statement");
+
+        return Some(AstStmt::Yield(Some(expr)));
+    }
+
     fn expr_stmt(&mut self) -> Option<AstStmt> {
         let expr = unwrap_ast!(self.expression());
         consume!(self, Token::Semicolon, "Expected ';' after expression");
@@ -664,7 +750,11 @@ impl CopperParser {
 
     fn stmt(&mut self) -> Option<AstStmt> {
         if self.match_tokens(&[Token::Func]) {
-            return self.function_stmt("function");
+            if self.match_tokens(&[Token::Star]) {
+                return self.function_stmt("generator", true);
+            }
+
+            return self.function_stmt("function", false);
         }
 
         if self.match_tokens(&[Token::While]) {
@@ -696,6 +786,18 @@ impl CopperParser {
             return self.struct_stmt();
         }
 
+        if self.match_tokens(&[Token::Try]) {
+            return self.try_stmt();
+        }
+
+        if self.match_tokens(&[Token::Throw]) {
+            return self.throw_stmt();
+        }
+
+        if self.match_tokens(&[Token::Yield]) {
+            return self.yield_stmt();
+        }
+
         return self.expr_stmt();
     }
 
@@ -721,6 +823,7 @@ impl CopperParser {
 
         unwrap_ast!(self.current_token.clone());
 
+        self.statement_line = self.current_line;
         return self.declaration_stmt();
     }
 
@@ -739,6 +842,8 @@ impl CopperParser {
             current_lexer: Lexer::new(source),
             current_lexeme: String::new(),
             current_line: 0,
+            statement_line: 0,
+            errors: Vec::new(),
             continue_parsing: true,
         };
     }
@@ -805,14 +910,14 @@ impl fmt::Display for AstStmt {
             AstStmt::Expr(expr) => write!(f, "{}\n", expr),
             AstStmt::Declaration(name, ctype, value) => write!(f, "var {}: {:?} = {}\n", name, ctype, value),
             AstStmt::InferDeclaration(name, value) => write!(f, "var {} = {}\n", name, value),
-            AstStmt::If(condition, then, next) => if next == None {
-                write!(f, "if {} {{\n {} \n }}", condition, then)
-            } else {
-                write!(f, "if {} {{\n {} \n }}\n else {{\n {} \n}}\n", condition, then, next.unwrap())
+            AstStmt::If(condition, then, next) => match next {
+                None => write!(f, "if {} {{\n {} \n }}", condition, then),
+                Some(next) => write!(f, "if {} {{\n {} \n }}\n else {{\n {} \n}}\n", condition, then, next),
             },
             AstStmt::While(condition, body) => write!(f, "while {} {{\n {} \n}}\n", condition, body),
-            AstStmt::Function(name, ctype, identifers, ctypes, body) => {                
-                write!(f, "function {}(", name)?;
+            AstStmt::ForIn(name, iterable, body) => write!(f, "for {} in {} {{\n {} \n}}\n", name, iterable, body),
+            AstStmt::Function(name, ctype, identifers, ctypes, body, generator) => {                
+                write!(f, "{} {}(", if generator { "generator" } else { "function" }, name)?;
 
                 for i in 0..identifers.len() {
                     write!(f, "{}: {:?}", identifers[i], ctypes[i])?;
@@ -824,13 +929,31 @@ impl fmt::Display for AstStmt {
 
                 write!(f, "): {:?} {}\n", ctype, body)
             },
-            AstStmt::Return(value) => if value == None {
-                write!(f, "return\n")
-            } else {
-                write!(f, "return {}\n", value.unwrap())
+            AstStmt::Return(value) => match value {
+                None => write!(f, "return\n"),
+                Some(value) => write!(f, "return {}\n", value),
             },
 
             AstStmt::Import(expr) => write!(f, "import {}\n", expr),
+            AstStmt::Try(body, name, catch_body, finally_body) => {
+                write!(f, "try {}", body)?;
+
+                if let Some(catch_body) = catch_body {
+                    write!(f, "catch {} {}", name.unwrap_or_default(), catch_body)?;
+                }
+
+                if let Some(finally_body) = finally_body {
+                    write!(f, "finally {}", finally_body)?;
+                }
+
+                Ok(())
+            },
+            AstStmt::Throw(expr) => write!(f, "throw {}\n", expr),
+            AstStmt::Yield(value) => match value {
+                Some(value) => write!(f, "yield {}\n", value),
+                None => write!(f, "yield\n"),
+            },
+            AstStmt::Line(_) => Ok(()),
         }
     }
 }
\ No newline at end of file
@@ -0,0 +1,394 @@
+use std::io::{IsTerminal, Read, Write, stdin, stdout};
+use std::process::{Command, Stdio};
+
+use crate::chunk::Chunk;
+use crate::codegen::CopperGen;
+use crate::mini_macros::tokenizer::MacroExpander;
+use crate::parser::{AstExpr, AstStmt, CopperParser};
+use crate::value::Value;
+use crate::vm::VM;
+
+const PROMPT: &str = "copper> ";
+const CONTINUE_PROMPT: &str = "   ...> ";
+
+// The global the value of an expression typed into the REPL is kept in, so it can be used again.
+const RESULT_GLOBAL: &str = "_";
+
+const HELP: &str = "Type copper code to run it, expressions have their value printed.
+  :dis          disassembles the code from the last input
+  :env          shows the variables and structures the vm has
+  :load <file>  runs a file in the session
+  :help         shows this
+  :quit         leaves the REPL";
+
+// What the user did when asked for a line.
+pub(crate) enum Input {
+    Line(String),
+    // Ctrl-C, which throws away what's being typed.
+    Interrupt,
+    // Ctrl-D or the end of piped input.
+    End,
+}
+
+// Reads lines with basic editing and history when run in a terminal. The terminal is put in
+// raw mode with 'stty' only while a line is being read, so scripts read input like normal.
+pub(crate) struct LineEditor {
+    history: Vec<String>,
+    terminal: bool,
+}
+
+impl LineEditor {
+    pub(crate) fn new() -> LineEditor {
+        return LineEditor { history: Vec::new(), terminal: stdin().is_terminal() };
+    }
+
+    fn stty(args: &[&str]) -> Option<String> {
+        let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output().ok()?;
+
+        if !output.status.success() {
+            return None;
+        }
+
+        return Some(String::from_utf8_lossy(&output.stdout).trim().to_string());
+    }
+
+    pub(crate) fn read_line(&mut self, prompt: &str) -> Input {
+        if self.terminal {
+            if let Some(settings) = LineEditor::stty(&["-g"]) {
+                if LineEditor::stty(&["raw", "-echo"]).is_some() {
+                    let input = self.edit_line(prompt);
+                    LineEditor::stty(&[&settings]);
+                    return input;
+                }
+            }
+        }
+
+        print!("{}", prompt);
+        let _ = stdout().flush();
+
+        let mut line = String::new();
+        return match stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => Input::End,
+            Ok(_) => Input::Line(line.trim_end_matches(['\r', '\n']).to_string()),
+        };
+    }
+
+    fn read_byte() -> Option<u8> {
+        let mut byte = [0u8; 1];
+        return match stdin().lock().read(&mut byte) {
+            Ok(1) => Some(byte[0]),
+            _ => None,
+        };
+    }
+
+    fn redraw(prompt: &str, buffer: &[char], cursor: usize) {
+        let line: String = buffer.iter().collect();
+        let before: String = buffer[..cursor].iter().collect();
+
+        print!("\r{}{}\x1b[K\r{}{}", prompt, line, prompt, before);
+        let _ = stdout().flush();
+    }
+
+    fn edit_line(&mut self, prompt: &str) -> Input {
+        let mut buffer: Vec<char> = Vec::new();
+        let mut cursor = 0;
+        // Where in the history the line came from, the line being typed is kept while looking back.
+        let mut history_index = self.history.len();
+        let mut typed: Vec<char> = Vec::new();
+
+        LineEditor::redraw(prompt, &buffer, cursor);
+
+        loop {
+            let byte = match LineEditor::read_byte() {
+                Some(byte) => byte,
+                None => return Input::End,
+            };
+
+            match byte {
+                b'\r' | b'\n' => {
+                    print!("\r\n");
+                    let _ = stdout().flush();
+
+                    let line: String = buffer.iter().collect();
+                    if !line.trim().is_empty() && self.history.last() != Some(&line) {
+                        self.history.push(line.clone());
+                    }
+
+                    return Input::Line(line);
+                },
+                // Ctrl-C
+                3 => {
+                    print!("^C\r\n");
+                    let _ = stdout().flush();
+                    return Input::Interrupt;
+                },
+                // Ctrl-D
+                4 => {
+                    if buffer.is_empty() {
+                        print!("\r\n");
+                        let _ = stdout().flush();
+                        return Input::End;
+                    }
+
+                    if cursor < buffer.len() {
+                        buffer.remove(cursor);
+                    }
+                },
+                // Ctrl-A and Ctrl-E
+                1 => cursor = 0,
+                5 => cursor = buffer.len(),
+                // Backspace
+                8 | 127 => {
+                    if cursor > 0 {
+                        cursor -= 1;
+                        buffer.remove(cursor);
+                    }
+                },
+                // Arrow keys and the like come as escape sequences.
+                27 => {
+                    let kind = LineEditor::read_byte();
+                    let key = LineEditor::read_byte();
+
+                    match (kind, key) {
+                        (Some(b'['), Some(b'A')) => if history_index > 0 {
+                            if history_index == self.history.len() {
+                                typed = buffer.clone();
+                            }
+
+                            history_index -= 1;
+                            buffer = self.history[history_index].chars().collect();
+                            cursor = buffer.len();
+                        },
+                        (Some(b'['), Some(b'B')) => if history_index < self.history.len() {
+                            history_index += 1;
+
+                            buffer = if history_index == self.history.len() {
+                                typed.clone()
+                            } else {
+                                self.history[history_index].chars().collect()
+                            };
+                            cursor = buffer.len();
+                        },
+                        (Some(b'['), Some(b'C')) => if cursor < buffer.len() {
+                            cursor += 1;
+                        },
+                        (Some(b'['), Some(b'D')) => cursor = cursor.saturating_sub(1),
+                        (Some(b'['), Some(b'H')) | (Some(b'O'), Some(b'H')) => cursor = 0,
+                        (Some(b'['), Some(b'F')) | (Some(b'O'), Some(b'F')) => cursor = buffer.len(),
+                        // Delete
+                        (Some(b'['), Some(b'3')) => {
+                            LineEditor::read_byte();
+
+                            if cursor < buffer.len() {
+                                buffer.remove(cursor);
+                            }
+                        },
+                        _ => {},
+                    }
+                },
+                byte if byte >= 32 => {
+                    // Characters past ascii take up more than one byte.
+                    let length = if byte >= 0xF0 { 4 } else if byte >= 0xE0 { 3 } else if byte >= 0xC0 { 2 } else { 1 };
+
+                    let mut bytes = vec![byte];
+                    for _ in 1..length {
+                        match LineEditor::read_byte() {
+                            Some(byte) => bytes.push(byte),
+                            None => return Input::End,
+                        }
+                    }
+
+                    for c in String::from_utf8_lossy(&bytes).chars() {
+                        buffer.insert(cursor, c);
+                        cursor += 1;
+                    }
+                },
+                _ => {},
+            }
+
+            LineEditor::redraw(prompt, &buffer, cursor);
+        }
+    }
+}
+
+// How many more braces and parentheses were opened than closed, leaving out strings and comments.
+fn unclosed(source: &str) -> i32 {
+    let mut depth = 0;
+    let mut in_string = false;
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => in_string = !in_string,
+            '\\' if in_string => {
+                chars.next();
+            },
+            '/' if !in_string && chars.peek() == Some(&'/') => {
+                while chars.peek().is_some_and(|c| *c != '\n') {
+                    chars.next();
+                }
+            },
+            '{' | '(' if !in_string => depth += 1,
+            '}' | ')' if !in_string => depth -= 1,
+            _ => {},
+        }
+    }
+
+    return depth;
+}
+
+// Runs copper code a piece at a time as it's typed, keeping one vm for the whole session.
+pub struct Repl {
+    pub gen: CopperGen,
+    pub vm: VM,
+    editor: LineEditor,
+    // Where the code from the last input starts, for ':dis'.
+    last_start: usize,
+}
+
+impl Repl {
+    // Natives should be bound to the generator before it's given to the REPL.
+    pub fn new(gen: CopperGen) -> Repl {
+        return Repl { gen, vm: VM::new(Chunk::new()), editor: LineEditor::new(), last_start: 0 };
+    }
+
+    pub fn run(&mut self) {
+        println!("Copper REPL, type ':help' for help.");
+
+        while let Some(source) = self.read_input() {
+            let trimmed = source.trim();
+
+            if trimmed.is_empty() {
+                continue;
+            }
+
+            if trimmed.starts_with(':') {
+                if !self.command(trimmed) {
+                    break;
+                }
+
+                continue;
+            }
+
+            self.eval(&source);
+        }
+    }
+
+    // Reads lines until the braces and parentheses in them are closed.
+    fn read_input(&mut self) -> Option<String> {
+        let mut source = String::new();
+
+        loop {
+            let prompt = if source.is_empty() { PROMPT } else { CONTINUE_PROMPT };
+
+            match self.editor.read_line(prompt) {
+                Input::Line(line) => {
+                    source.push_str(&line);
+                    source.push('\n');
+
+                    if unclosed(&source) <= 0 {
+                        return Some(source);
+                    }
+                },
+                Input::Interrupt => source.clear(),
+                Input::End => return None,
+            }
+        }
+    }
+
+    // Gives back false once the REPL should stop.
+    fn command(&mut self, line: &str) -> bool {
+        let (name, argument) = match line.split_once(char::is_whitespace) {
+            Some((name, argument)) => (name, argument.trim()),
+            None => (line, ""),
+        };
+
+        match name {
+            ":dis" => self.vm.chunk.disassemble_from(self.last_start),
+            ":env" => println!("{}", self.vm.environment),
+            ":load" => {
+                if argument.is_empty() {
+                    println!(":load <file>");
+                } else if let Err(err) = std::fs::metadata(argument) {
+                    println!("Couldn't load '{}': {}", argument, err);
+                } else {
+                    let source = MacroExpander::new(vec![argument.to_string()]).compile();
+                    self.run_source(&source);
+                }
+            },
+            ":help" => println!("{}", HELP),
+            ":quit" | ":q" => return false,
+            _ => println!("Unknown command '{}', type ':help' for the commands.", name),
+        }
+
+        return true;
+    }
+
+    // Runs what was typed, printing the value if it was a single expression.
+    fn eval(&mut self, source: &str) {
+        // The last ';' can be left out, like for an expression that's only there to be printed.
+        let mut source = source.trim().to_string();
+        if !source.ends_with(';') && !source.ends_with('}') {
+            source.push(';');
+        }
+
+        let mut parser = CopperParser::new(source.clone());
+        let mut statements = Vec::new();
+
+        while let Some(stmt) = parser.parse() {
+            statements.push(stmt);
+        }
+
+        if !parser.continue_parsing {
+            for error in &parser.errors {
+                println!("{}", error);
+            }
+
+            return;
+        }
+
+        let echo = match statements.as_slice() {
+            [AstStmt::Expr(AstExpr::Assign(_, _))] | [AstStmt::Expr(AstExpr::AssignByOp(_, _, _))] => false,
+            [AstStmt::Expr(_)] => true,
+            _ => false,
+        };
+
+        if !echo {
+            self.run_source(&source);
+            return;
+        }
+
+        let expression = source.trim_end_matches(';');
+        if !self.run_source(&format!("var {} = {};", RESULT_GLOBAL, expression)) {
+            return;
+        }
+
+        match self.vm.get_global(RESULT_GLOBAL) {
+            Some(Value::None) | None => {},
+            Some(value) => value.println(),
+        }
+    }
+
+    // Gives back whether the source ran without any errors.
+    fn run_source(&mut self, source: &str) -> bool {
+        let chunk = match self.gen.generate_more(source) {
+            Some(chunk) => chunk,
+            None => return false,
+        };
+
+        self.last_start = self.vm.chunk.bytecode.len();
+
+        if let Err(err) = self.vm.update(chunk) {
+            println!("{}", err);
+            return false;
+        }
+
+        if let Err(err) = self.vm.interpret() {
+            println!("{}", err);
+            self.vm.skip_to_end();
+            return false;
+        }
+
+        return true;
+    }
+}
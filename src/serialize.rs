@@ -0,0 +1,473 @@
+use std::fs;
+use std::io;
+
+use crate::chunk::{Chunk, Function, OpCode};
+use crate::environment::{CopperStruct, EnvEntry};
+use crate::value::{ClassType, Value};
+
+// Compiled chunks start with these bytes, followed by the version of the format.
+pub const MAGIC: &[u8; 4] = b"CPBC";
+// Bump whenever the layout of the file or the meaning of an opcode byte changes, so older
+// versions of copper refuse chunks they can't run instead of failing part way through.
+//   2: added 'yield'.
+//   3: added generators and the ops for coroutines.
+//   4: ops record the file they were generated from.
+pub const FORMAT_VERSION: u16 = 4;
+
+fn invalid(message: String) -> io::Error {
+    return io::Error::new(io::ErrorKind::InvalidData, message);
+}
+
+struct Writer {
+    bytes: Vec<u8>,
+}
+
+impl Writer {
+    fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    fn u32(&mut self, value: usize) {
+        self.bytes.extend_from_slice(&(value as u32).to_le_bytes());
+    }
+
+    fn str(&mut self, value: &str) {
+        self.u32(value.len());
+        self.bytes.extend_from_slice(value.as_bytes());
+    }
+
+    fn ctype(&mut self, ctype: &ClassType) {
+        match ctype {
+            ClassType::Any => self.u8(0),
+            ClassType::Uint => self.u8(1),
+            ClassType::Int => self.u8(2),
+            ClassType::Decimal => self.u8(3),
+            ClassType::Str => self.u8(4),
+            ClassType::Bool => self.u8(5),
+            ClassType::Struct(name) => {
+                self.u8(6);
+                self.str(name);
+            }
+        }
+    }
+
+    fn structure(&mut self, structure: &CopperStruct) -> io::Result<()> {
+        self.str(&structure.name);
+        self.u32(structure.field_names.len());
+
+        for i in 0..structure.field_names.len() {
+            self.str(&structure.field_names[i]);
+            self.value(&structure.field_values[i])?;
+        }
+
+        return Ok(());
+    }
+
+    fn value(&mut self, value: &Value) -> io::Result<()> {
+        match value {
+            Value::None => self.u8(0),
+            Value::Uint(x) => {
+                self.u8(1);
+                self.bytes.extend_from_slice(&x.to_le_bytes());
+            },
+            Value::Int(x) => {
+                self.u8(2);
+                self.bytes.extend_from_slice(&x.to_le_bytes());
+            },
+            Value::Decimal(x) => {
+                self.u8(3);
+                self.bytes.extend_from_slice(&x.to_le_bytes());
+            },
+            Value::Str(x) => {
+                self.u8(4);
+                self.str(x);
+            },
+            Value::Bool(x) => {
+                self.u8(5);
+                self.u8(*x as u8);
+            },
+            Value::Struct(x) => {
+                self.u8(6);
+                self.structure(x)?;
+            },
+            // Coroutines only exist while a chunk is running, so they can't be written out.
+            Value::Coroutine(co) => return Err(invalid(format!("Cannot save the coroutine '{}'", co.name()))),
+        }
+
+        return Ok(());
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> io::Result<&'a [u8]> {
+        if self.position + count > self.bytes.len() {
+            return Err(invalid(format!("The file ends early, expected {} more byte(s) at {}", count, self.position)));
+        }
+
+        let taken = &self.bytes[self.position..self.position+count];
+        self.position += count;
+
+        return Ok(taken);
+    }
+
+    fn u8(&mut self) -> io::Result<u8> {
+        return Ok(self.take(1)?[0]);
+    }
+
+    fn u16(&mut self) -> io::Result<u16> {
+        let bytes = self.take(2)?;
+        return Ok(u16::from_le_bytes([bytes[0], bytes[1]]));
+    }
+
+    fn u32(&mut self) -> io::Result<usize> {
+        let bytes = self.take(4)?;
+        return Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as usize);
+    }
+
+    fn u64_bytes(&mut self) -> io::Result<[u8; 8]> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(self.take(8)?);
+        return Ok(bytes);
+    }
+
+    // Lengths are checked against what's left, so a bad length can't ask for a huge allocation.
+    fn len(&mut self) -> io::Result<usize> {
+        let length = self.u32()?;
+
+        if length > self.bytes.len() - self.position {
+            return Err(invalid(format!("A length of {} at {} is longer than the file", length, self.position - 4)));
+        }
+
+        return Ok(length);
+    }
+
+    fn str(&mut self) -> io::Result<String> {
+        let length = self.len()?;
+
+        return match String::from_utf8(self.take(length)?.to_vec()) {
+            Ok(string) => Ok(string),
+            Err(_) => Err(invalid(format!("A string at {} isn't valid utf-8", self.position - length))),
+        }
+    }
+
+    fn ctype(&mut self) -> io::Result<ClassType> {
+        return match self.u8()? {
+            0 => Ok(ClassType::Any),
+            1 => Ok(ClassType::Uint),
+            2 => Ok(ClassType::Int),
+            3 => Ok(ClassType::Decimal),
+            4 => Ok(ClassType::Str),
+            5 => Ok(ClassType::Bool),
+            6 => Ok(ClassType::Struct(self.str()?)),
+            tag => Err(invalid(format!("Unknown type tag '{}'", tag))),
+        }
+    }
+
+    fn structure(&mut self) -> io::Result<CopperStruct> {
+        let mut structure = CopperStruct::new(self.str()?);
+
+        for _ in 0..self.len()? {
+            let name = self.str()?;
+            let value = self.value()?;
+            structure.insert(name, value);
+        }
+
+        return Ok(structure);
+    }
+
+    fn value(&mut self) -> io::Result<Value> {
+        return match self.u8()? {
+            0 => Ok(Value::None),
+            1 => Ok(Value::Uint(u64::from_le_bytes(self.u64_bytes()?))),
+            2 => Ok(Value::Int(i64::from_le_bytes(self.u64_bytes()?))),
+            3 => Ok(Value::Decimal(f64::from_le_bytes(self.u64_bytes()?))),
+            4 => Ok(Value::Str(self.str()?)),
+            5 => Ok(Value::Bool(self.u8()? != 0)),
+            6 => Ok(Value::Struct(self.structure()?)),
+            tag => Err(invalid(format!("Unknown value tag '{}'", tag))),
+        }
+    }
+}
+
+impl Chunk {
+    // Natives can't be written out, so only their names are and they're left for the
+    // program loading the chunk to bind. Coroutines can't be written out at all.
+    pub fn to_bytes(&self) -> io::Result<Vec<u8>> {
+        let mut writer = Writer { bytes: Vec::new() };
+        writer.bytes.extend_from_slice(MAGIC);
+        writer.bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+
+        writer.u32(self.bytecode.len());
+        writer.bytes.extend_from_slice(&self.bytecode);
+
+        writer.u32(self.offsets.len());
+        for offset in &self.offsets {
+            writer.u32(*offset);
+        }
+
+        writer.u32(self.lines.len());
+        for line in &self.lines {
+            writer.u32(*line);
+        }
+
+        writer.u32(self.files.len());
+        for file in &self.files {
+            writer.u32(*file as usize);
+        }
+
+        writer.u32(self.sources.len());
+        for source in &self.sources {
+            writer.str(source);
+        }
+
+        writer.u32(self.constants.len());
+        for constant in &self.constants {
+            writer.value(constant)?;
+        }
+
+        writer.u32(self.names.len());
+        for name in &self.names {
+            writer.str(name);
+        }
+
+        writer.u32(self.paths.len());
+        for path in &self.paths {
+            writer.u32(path.len());
+            for name in path {
+                writer.str(name);
+            }
+        }
+
+        writer.u32(self.types.len());
+        for ctype in &self.types {
+            writer.ctype(ctype);
+        }
+
+        writer.u32(self.globals.len());
+        for global in &self.globals {
+            writer.str(global);
+        }
+
+        writer.u32(self.functions.len());
+        for function in &self.functions {
+            match function {
+                Function::Script(name, ctype, arg_count, position) => {
+                    writer.u8(0);
+                    writer.str(name);
+                    writer.ctype(ctype);
+                    writer.u32(*arg_count);
+                    writer.u32(*position);
+                },
+                Function::Native(name, arg_count, _, _) => {
+                    writer.u8(1);
+                    writer.str(name);
+                    writer.u32(*arg_count);
+                },
+                Function::Unbound(name) => {
+                    writer.u8(2);
+                    writer.str(name);
+                },
+            }
+        }
+
+        let structs: Vec<&CopperStruct> = self.structs.entries.iter().filter_map(|entry| match entry {
+            EnvEntry::Structure(structure) => Some(structure),
+            _ => None,
+        }).collect();
+
+        writer.u32(structs.len());
+        for structure in structs {
+            writer.structure(structure)?;
+        }
+
+        return Ok(writer.bytes);
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Chunk> {
+        let mut reader = Reader { bytes, position: 0 };
+
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(invalid("Not a compiled copper chunk".to_string()));
+        }
+
+        let version = reader.u16()?;
+        if version != FORMAT_VERSION {
+            return Err(invalid(format!("Compiled with format version {}, but only version {} can be loaded", version, FORMAT_VERSION)));
+        }
+
+        let mut chunk = Chunk::new();
+
+        let length = reader.len()?;
+        chunk.bytecode = reader.take(length)?.to_vec();
+
+        for _ in 0..reader.len()? {
+            chunk.offsets.push(reader.u32()?);
+        }
+
+        for _ in 0..reader.len()? {
+            chunk.lines.push(reader.u32()?);
+        }
+
+        for _ in 0..reader.len()? {
+            chunk.files.push(reader.u32()? as u32);
+        }
+
+        for _ in 0..reader.len()? {
+            chunk.sources.push(reader.str()?);
+        }
+
+        for _ in 0..reader.len()? {
+            chunk.constants.push(reader.value()?);
+        }
+
+        for _ in 0..reader.len()? {
+            let name = reader.str()?;
+
+            if chunk.intern(&name) as usize != chunk.names.len() - 1 {
+                return Err(invalid(format!("The name '{}' is in the names table more than once", name)));
+            }
+        }
+
+        for _ in 0..reader.len()? {
+            let mut path = Vec::new();
+            for _ in 0..reader.len()? {
+                path.push(reader.str()?);
+            }
+            chunk.paths.push(path);
+        }
+
+        for _ in 0..reader.len()? {
+            chunk.types.push(reader.ctype()?);
+        }
+
+        for _ in 0..reader.len()? {
+            chunk.globals.push(reader.str()?);
+        }
+
+        for _ in 0..reader.len()? {
+            let tag = reader.u8()?;
+            let name = reader.str()?;
+            let index = chunk.function_index(&name) as usize;
+
+            if index != chunk.functions.len() - 1 {
+                return Err(invalid(format!("The function '{}' is in the function table more than once", name)));
+            }
+
+            match tag {
+                0 => {
+                    let ctype = reader.ctype()?;
+                    let arg_count = reader.u32()?;
+                    let position = reader.u32()?;
+                    chunk.bind_function(name, ctype, arg_count, position);
+                },
+                // Natives that were bound when it was compiled have to be bound again.
+                1 => { reader.u32()?; },
+                2 => {},
+                tag => return Err(invalid(format!("Unknown function tag '{}'", tag))),
+            }
+        }
+
+        for _ in 0..reader.len()? {
+            let structure = reader.structure()?;
+            chunk.structs.add_struct(structure);
+        }
+
+        if reader.position != bytes.len() {
+            return Err(invalid(format!("There are {} byte(s) left over after the chunk", bytes.len() - reader.position)));
+        }
+
+        chunk.rebuild_code().map_err(invalid)?;
+
+        return Ok(chunk);
+    }
+
+    // Decodes the bytecode back into ops, checking that it lines up with the offsets table
+    // and only refers to things the chunk has.
+    fn rebuild_code(&mut self) -> Result<(), String> {
+        if self.offsets.len() != self.lines.len() + 1 {
+            return Err("The offsets and line tables don't match".to_string());
+        }
+
+        if self.files.len() != self.lines.len() {
+            return Err("The file and line tables don't match".to_string());
+        }
+
+        if self.offsets.last() != Some(&self.bytecode.len()) {
+            return Err("The offsets table doesn't end at the end of the bytecode".to_string());
+        }
+
+        let position = |offsets: &[usize], at: usize| -> Result<usize, String> {
+            return match offsets.binary_search(&at) {
+                Ok(index) => Ok(index),
+                Err(_) => Err(format!("Offset {} isn't the start of an instruction", at)),
+            }
+        };
+
+        let mut code = Vec::with_capacity(self.lines.len());
+        let mut offset = 0;
+
+        for i in 0..self.lines.len() {
+            if self.offsets[i] != offset {
+                return Err(format!("Instruction {} should start at offset {}, not {}", i, offset, self.offsets[i]));
+            }
+
+            let (op, next) = self.try_decode(offset)?;
+
+            let in_range = match &op {
+                OpCode::Push(index) => (*index as usize) < self.constants.len(),
+                OpCode::Call(index) => (*index as usize) < self.functions.len(),
+                OpCode::StructSet(index) => (*index as usize) < self.paths.len(),
+                OpCode::ArgumentStore(name, _) | OpCode::Store(name, _) | OpCode::InferStore(name)
+                | OpCode::Load(name) | OpCode::Assign(name) | OpCode::NewStruct(name) | OpCode::StructGet(name)
+                | OpCode::ArgumentLocal(_, name, _) | OpCode::DefineLocal(_, name, _) | OpCode::InferLocal(_, name) => (*name as usize) < self.names.len(),
+                OpCode::DefineGlobal(index, _) | OpCode::InferGlobal(index)
+                | OpCode::LoadGlobal(index) | OpCode::StoreGlobal(index) => (*index as usize) < self.globals.len(),
+                _ => true,
+            };
+
+            if !in_range {
+                return Err(format!("The instruction at offset {} refers to something the chunk doesn't have", offset));
+            }
+
+            // Positions are turned back into indices of ops, like they are when generated.
+            let op = match op {
+                OpCode::Jmp(at) => OpCode::Jmp(position(&self.offsets, at)?),
+                OpCode::JmpIfFalse(at) => OpCode::JmpIfFalse(position(&self.offsets, at)?),
+                OpCode::TryStart(at) => OpCode::TryStart(position(&self.offsets, at)?),
+                OpCode::ForIter(at) => OpCode::ForIter(position(&self.offsets, at)?),
+                op => op,
+            };
+
+            code.push(op);
+            offset = next;
+        }
+
+        for function in &self.functions {
+            if let Function::Script(name, _, _, position) = function {
+                if *position >= code.len() {
+                    return Err(format!("The function '{}' starts past the end of the code", name));
+                }
+            }
+        }
+
+        self.code = code;
+        return Ok(());
+    }
+
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        return fs::write(path, self.to_bytes()?);
+    }
+
+    pub fn load(path: &str) -> io::Result<Chunk> {
+        return Chunk::from_bytes(&fs::read(path)?);
+    }
+
+    pub fn is_compiled(bytes: &[u8]) -> bool {
+        return bytes.starts_with(MAGIC);
+    }
+}
@@ -15,6 +15,7 @@ pub enum Token {
     If,
     Else,
     For,
+    In,
     Return,
     QuestionMark,
 
@@ -43,6 +44,13 @@ pub enum Token {
     While,
     Quit,
 
+    Try,
+    Catch,
+    Finally,
+    Throw,
+
+    Yield,
+
     Colon,
     ColonEqual,
     Equal,
@@ -97,6 +105,8 @@ pub struct Lexer {
     pub line: usize,
     start: usize,
     end: usize,
+    // When set comments are given back as tokens instead of being skipped.
+    keep_comments: bool,
 }
 
 impl Lexer {
@@ -139,6 +149,10 @@ impl Lexer {
                 }
                 self.end += 1;
             } else {
+                if self.peek() == "\n" {
+                    self.line += 1;
+                }
+
                 contents.push_str(self.peek());
             }
             self.end += 1;
@@ -165,6 +179,7 @@ impl Lexer {
             "true" => return Some(Token::CmpTrue),
             "false" => return Some(Token::CmpFalse),
             "for" => return Some(Token::For),
+            "in" => return Some(Token::In),
             "func" => return Some(Token::Func),
             "while" => return Some(Token::While),
             "not" => return Some(Token::Not),
@@ -182,6 +197,11 @@ impl Lexer {
             "quit" => return Some(Token::Quit),
             "struct" => return Some(Token::Struct),
             "new" => return Some(Token::New),
+            "try" => return Some(Token::Try),
+            "catch" => return Some(Token::Catch),
+            "finally" => return Some(Token::Finally),
+            "throw" => return Some(Token::Throw),
+            "yield" => return Some(Token::Yield),
             _ => return Some(Token::Identifer(identifer.to_string())),
         }
     }
@@ -209,6 +229,7 @@ impl Lexer {
         }
     }
 
+    #[allow(clippy::should_implement_trait)]
     pub fn next(&mut self) -> Option<Token> {
         if self.at_end() {
             return None;
@@ -242,10 +263,17 @@ impl Lexer {
                         self.end += 1;
                     }
 
+                    // The newline is left for the next token.
+                    if self.keep_comments {
+                        return Some(Token::Comment);
+                    }
+
                     if self.at_end() {
                         return None;
                     }
 
+                    // The newline ending the comment is skipped over here.
+                    self.line += 1;
                     self.end += 1;
                     self.start = self.end;
                     self.end += 1;
@@ -314,16 +342,31 @@ impl Lexer {
         String::from(&self.source[self.start..self.end])
     }
 
+    // Where the last token is in the source, as byte offsets to its start and end.
+    pub fn span(&self) -> (usize, usize) {
+        return (self.start, self.end);
+    }
+
     pub fn new(source: String) -> Self {
         let mut source = source;
         source.push(' ');
         Self {
             source,
-            line: 0,
+            line: 1,
             start: 0,
             end: 0,
+            keep_comments: false,
         }
     }
+
+    // Gives back comments as tokens too, so with the spans every part of the source that
+    // isn't whitespace is in a token. Used by tools that print the source back out.
+    pub fn with_comments(source: String) -> Self {
+        let mut lexer = Lexer::new(source);
+        lexer.keep_comments = true;
+
+        return lexer;
+    }
 }
 
 impl fmt::Display for Token {
@@ -0,0 +1,92 @@
+use std::io::{Write, stderr, Stderr};
+
+use crate::chunk::{Chunk, OpCode};
+use crate::environment::Environment;
+use crate::json::json_string;
+use crate::value::Value;
+
+// What the vm is about to run, handed to the tracer before every op.
+pub struct TraceEvent<'a> {
+    // Where the op is in the chunk's bytecode.
+    pub offset: usize,
+    pub line: usize,
+    pub op: OpCode,
+    pub stack: &'a [Value],
+    pub call_stack: &'a [Value],
+    pub environment: &'a Environment,
+    // How many functions are being run, the script itself is 0.
+    pub call_depth: usize,
+}
+
+// Gets told about every op the vm runs, set with 'vm.tracer'. Closures taking the chunk and the
+// event can be used as one.
+pub trait Tracer {
+    fn trace(&mut self, chunk: &Chunk, event: &TraceEvent);
+}
+
+impl<F: FnMut(&Chunk, &TraceEvent)> Tracer for F {
+    fn trace(&mut self, chunk: &Chunk, event: &TraceEvent) {
+        self(chunk, event);
+    }
+}
+
+// Writes every op as a line of text, like it looks when disassembling, with the stack above it.
+pub struct TextTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> TextTracer<W> {
+    pub fn new(out: W) -> TextTracer<W> {
+        return TextTracer { out };
+    }
+}
+
+impl TextTracer<Stderr> {
+    pub fn stderr() -> TextTracer<Stderr> {
+        return TextTracer::new(stderr());
+    }
+}
+
+impl<W: Write> Tracer for TextTracer<W> {
+    fn trace(&mut self, chunk: &Chunk, event: &TraceEvent) {
+        let stack: Vec<String> = event.stack.iter().map(|value| value.string_s()).collect();
+
+        // Tracing is only there to help, so a sink that can't be written to isn't an error.
+        let _ = writeln!(self.out, "          [ {} ]  scope {}", stack.join(", "), event.environment.current_scope);
+        let _ = writeln!(self.out, "{:04} {:4} {}", event.offset, event.line, chunk.describe(&event.op));
+    }
+}
+
+// Writes every op as a json object on its own line, for tools to read.
+pub struct JsonTracer<W: Write> {
+    out: W,
+}
+
+impl<W: Write> JsonTracer<W> {
+    pub fn new(out: W) -> JsonTracer<W> {
+        return JsonTracer { out };
+    }
+}
+
+impl JsonTracer<Stderr> {
+    pub fn stderr() -> JsonTracer<Stderr> {
+        return JsonTracer::new(stderr());
+    }
+}
+
+impl<W: Write> Tracer for JsonTracer<W> {
+    fn trace(&mut self, chunk: &Chunk, event: &TraceEvent) {
+        let stack: Vec<String> = event.stack.iter().map(|value| json_string(&value.string_s())).collect();
+
+        let _ = writeln!(
+            self.out,
+            "{{\"offset\":{},\"line\":{},\"op\":{},\"stack\":[{}],\"scope\":{},\"depth\":{}}}",
+            event.offset,
+            event.line,
+            json_string(&chunk.describe(&event.op)),
+            stack.join(","),
+            event.environment.current_scope,
+            event.call_depth,
+        );
+    }
+}
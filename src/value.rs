@@ -1,4 +1,6 @@
-use crate::environment::CopperStruct;
+use std::fmt;
+
+use crate::{coroutine::CoroutineRef, environment::CopperStruct, error::RuntimeError};
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
 pub enum Value {
@@ -9,6 +11,7 @@ pub enum Value {
     Str(String),
     Bool(bool),
     Struct(CopperStruct),
+    Coroutine(CoroutineRef),
 }
 
 #[derive(Debug, Clone, PartialEq, PartialOrd)]
@@ -22,8 +25,23 @@ pub enum ClassType {
     Struct(String),
 }
 
+// Written the way types are in scripts.
+impl fmt::Display for ClassType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClassType::Any => write!(f, "any"),
+            ClassType::Uint => write!(f, "uint"),
+            ClassType::Int => write!(f, "int"),
+            ClassType::Decimal => write!(f, "decimal"),
+            ClassType::Str => write!(f, "string"),
+            ClassType::Bool => write!(f, "bool"),
+            ClassType::Struct(name) => write!(f, "{}", name),
+        }
+    }
+}
+
 macro_rules! binary_op_with_value {
-    ($this:tt, $other:tt, $op:tt, $to_do_bool:block, $what_to_do_with_string:block) => {
+    ($this:tt, $other:tt, $op:tt, $checked:ident, $to_do_bool:block, $what_to_do_with_string:block) => {
         let mut type_used = Value::Uint(0);
 
         if *$this > type_used {
@@ -35,13 +53,21 @@ macro_rules! binary_op_with_value {
         }
 
         match type_used {
-            Value::Uint(_) => return Value::Uint($this.uint_s() $op $other.uint_s()),
-            Value::Int(_) => return Value::Int($this.int_s() $op $other.int_s()),
-            Value::Decimal(_) => return Value::Decimal($this.decimal_s() $op $other.decimal_s()),
+            // Whole numbers that overflow would take down the host, so they're reported instead.
+            Value::Uint(_) => match $this.uint_s()?.$checked($other.uint_s()?) {
+                Some(x) => return Ok(Value::Uint(x)),
+                None => return Err(format!("The result of '{} {} {}' doesn't fit in a 'uint'.", $this.string_s(), stringify!($op), $other.string_s()).into()),
+            },
+            Value::Int(_) => match $this.int_s()?.$checked($other.int_s()?) {
+                Some(x) => return Ok(Value::Int(x)),
+                None => return Err(format!("The result of '{} {} {}' doesn't fit in an 'int'.", $this.string_s(), stringify!($op), $other.string_s()).into()),
+            },
+            Value::Decimal(_) => return Ok(Value::Decimal($this.decimal_s()? $op $other.decimal_s()?)),
             Value::Bool(_) => $to_do_bool,
             Value::Str(_) => $what_to_do_with_string,
-            Value::Struct(_) => panic!("Cannot add value to a struct."),
-            Value::None => panic!("Cannot add a value of 'none'"),
+            Value::Struct(_) => return Err("Cannot add value to a struct.".into()),
+            Value::Coroutine(_) => return Err("Cannot add value to a coroutine.".into()),
+            Value::None => return Err("Cannot add a value of 'none'".into()),
             //_ => panic!("Cannot add these values.")
         }
     };
@@ -74,6 +100,7 @@ impl Value {
             Self::Bool(_) => "bool",
             Self::Struct(cs) => cs.name.as_str(),
             Self::Str(_) => "string",
+            Self::Coroutine(_) => "coroutine",
             Self::None => "none",
         }.to_string()
     }
@@ -86,11 +113,43 @@ impl Value {
             Self::Bool(x) => print!("{}", x),
             Self::Str(x) => print!("{}", x),
             Self::Struct(_) => self.print_struct(4),
+            Self::Coroutine(_) => print!("{}", self.string_s()),
             Self::None => print!("NONE"),
             //_ => print!("No Value.")
         }
     }
 
+    // The type a variable declared without one takes on from its first value.
+    pub fn class_type(&self) -> ClassType {
+        match self {
+            Value::None => ClassType::Any,
+            Value::Uint(_) => ClassType::Uint,
+            Value::Int(_) => ClassType::Int,
+            Value::Decimal(_) => ClassType::Decimal,
+            Value::Bool(_) => ClassType::Bool,
+            Value::Str(_) => ClassType::Str,
+            Value::Struct(cs) => ClassType::Struct(cs.name.clone()),
+            Value::Coroutine(_) => ClassType::Any,
+        }
+    }
+
+    // Converts the value so it can be held by a variable of the given type.
+    pub fn coerce(self, ctype: &ClassType) -> Result<Value, RuntimeError> {
+        match ctype {
+            ClassType::Any => return Ok(self),
+            ClassType::Uint => return Ok(Value::Uint(self.uint_s()?)),
+            ClassType::Int => return Ok(Value::Int(self.int_s()?)),
+            ClassType::Decimal => return Ok(Value::Decimal(self.decimal_s()?)),
+            ClassType::Str => return Ok(Value::Str(self.string_s())),
+            ClassType::Bool => return Ok(Value::Bool(self.bool_s()?)),
+            ClassType::Struct(name) => match &self {
+                Value::Struct(x) if x.name == *name => return Ok(self),
+                Value::Struct(_) => return Err("Cannot convert the value of structure to another structure that is different".into()),
+                _ => return Err(format!("Cannot convert a value of '{}' to 'struct {}'.", self.type_to_string(), name).into()),
+            },
+        }
+    }
+
     pub fn print_type(&self) {
         print!("{}", self.type_to_string());
     }
@@ -99,54 +158,58 @@ impl Value {
         self.print(); println!();
     }
 
-    pub fn struct_s(&self, name: String) -> CopperStruct {
+    pub fn struct_s(&self, name: String) -> Result<CopperStruct, RuntimeError> {
         match self {
-            Value::Int(_) => panic!("Cannot convert a value of 'int' to 'struct {}'.", name),
-            Value::Uint(_) => panic!("Cannot convert a value of 'uint' to 'struct {}'.", name),
-            Value::Decimal(_) => panic!("Cannot convert a value of 'decimal' to 'struct {}'.", name),
-            Value::Bool(_) => panic!("Cannot convert a value of 'bool' to 'struct {}'.", name),
-            Value::Str(_) => panic!("Cannot convert a value of 'string' to 'struct {}'.", name),
-            Value::Struct(x) => return x.clone(),
-            Value::None => panic!("Cannot convert a value of 'none' to 'struct {}'.", name),
+            Value::Int(_) => return Err(format!("Cannot convert a value of 'int' to 'struct {}'.", name).into()),
+            Value::Uint(_) => return Err(format!("Cannot convert a value of 'uint' to 'struct {}'.", name).into()),
+            Value::Decimal(_) => return Err(format!("Cannot convert a value of 'decimal' to 'struct {}'.", name).into()),
+            Value::Bool(_) => return Err(format!("Cannot convert a value of 'bool' to 'struct {}'.", name).into()),
+            Value::Str(_) => return Err(format!("Cannot convert a value of 'string' to 'struct {}'.", name).into()),
+            Value::Struct(x) => return Ok(x.clone()),
+            Value::Coroutine(_) => return Err(format!("Cannot convert a value of 'coroutine' to 'struct {}'.", name).into()),
+            Value::None => return Err(format!("Cannot convert a value of 'none' to 'struct {}'.", name).into()),
             //_ => panic!("Unknown value used to convert to 'int'."),
         }
     }
 
-    pub fn int_s(&self) -> i64 {
+    pub fn int_s(&self) -> Result<i64, RuntimeError> {
         match self {
-            Value::Int(x) => return *x,
-            Value::Uint(x) => return *x as i64,
-            Value::Decimal(x) => return *x as i64,
-            Value::Bool(_) => panic!("Cannot convert a value of 'bool' to 'int'."),
-            Value::Str(x) => return x.trim().parse().unwrap(),
-            Value::Struct(_) => panic!("Cannot convert a struct to any value."),
-            Value::None => 0,
+            Value::Int(x) => return Ok(*x),
+            Value::Uint(x) => return Ok(*x as i64),
+            Value::Decimal(x) => return Ok(*x as i64),
+            Value::Bool(_) => return Err("Cannot convert a value of 'bool' to 'int'.".into()),
+            Value::Str(x) => return x.trim().parse().map_err(|_| format!("Cannot convert the string '{}' to 'int'.", x).into()),
+            Value::Struct(_) => return Err("Cannot convert a struct to any value.".into()),
+            Value::Coroutine(_) => return Err("Cannot convert a coroutine to any value.".into()),
+            Value::None => Ok(0),
             //_ => panic!("Unknown value used to convert to 'int'."),
         }
     }
 
-    pub fn uint_s(&self) -> u64 {
+    pub fn uint_s(&self) -> Result<u64, RuntimeError> {
         match self {
-            Self::Int(x) => return *x as u64,
-            Self::Uint(x) => return *x,
-            Self::Decimal(x) => return *x as u64,
-            Self::Bool(_) => panic!("Cannot convert a value of 'bool' to 'uint'."),
-            Self::Str(x) => return x.trim().parse().unwrap(),
-            Value::Struct(_) => panic!("Cannot convert a struct to any value."),
-            Self::None => 0,
+            Self::Int(x) => return Ok(*x as u64),
+            Self::Uint(x) => return Ok(*x),
+            Self::Decimal(x) => return Ok(*x as u64),
+            Self::Bool(_) => return Err("Cannot convert a value of 'bool' to 'uint'.".into()),
+            Self::Str(x) => return x.trim().parse().map_err(|_| format!("Cannot convert the string '{}' to 'uint'.", x).into()),
+            Value::Struct(_) => return Err("Cannot convert a struct to any value.".into()),
+            Value::Coroutine(_) => return Err("Cannot convert a coroutine to any value.".into()),
+            Self::None => Ok(0),
             //_ => panic!("Unknown value used to convert to 'int'."),
         }
     }
 
-    pub fn decimal_s(&self) -> f64 {
+    pub fn decimal_s(&self) -> Result<f64, RuntimeError> {
         match self {
-            Self::Int(x) => return *x as f64,
-            Self::Uint(x) => return *x as f64,
-            Self::Decimal(x) => return *x,
-            Self::Bool(_) => panic!("Cannot convert a value of 'bool' to 'decimal'."),
-            Self::Str(x) => return x.trim().parse().unwrap(),
-            Value::Struct(_) => panic!("Cannot convert a struct to any value."),
-            Self::None => 0.0,
+            Self::Int(x) => return Ok(*x as f64),
+            Self::Uint(x) => return Ok(*x as f64),
+            Self::Decimal(x) => return Ok(*x),
+            Self::Bool(_) => return Err("Cannot convert a value of 'bool' to 'decimal'.".into()),
+            Self::Str(x) => return x.trim().parse().map_err(|_| format!("Cannot convert the string '{}' to 'decimal'.", x).into()),
+            Value::Struct(_) => return Err("Cannot convert a struct to any value.".into()),
+            Value::Coroutine(_) => return Err("Cannot convert a coroutine to any value.".into()),
+            Self::None => Ok(0.0),
             //_ => panic!("Unknown value used to convert to 'int'."),
         }
     }
@@ -173,41 +236,48 @@ impl Value {
 
                 return string;
             },
+            Self::Coroutine(co) => format!("coroutine '{}'", co.name()),
             Self::None => String::from(""),
             //_ => panic!("Unknown value used to convert to 'int'."),
         }
     }
 
-    pub fn bool_s(&self) -> bool {
+    pub fn bool_s(&self) -> Result<bool, RuntimeError> {
         match self {
-            Self::Int(_) => panic!("Cannot convert a value of 'int' to 'bool'."),
-            Self::Uint(_) => panic!("Cannot convert a value of 'uint' to 'bool'."),
-            Self::Decimal(_) => panic!("Cannot convert a value of 'decimal' to 'bool'."),
-            Self::Bool(x) => return *x,
-            Self::Str(x) => return x.trim().parse().unwrap(),
-            Value::Struct(_) => panic!("Cannot convert a struct to any value."),
-            Self::None => false,
+            Self::Int(_) => return Err("Cannot convert a value of 'int' to 'bool'.".into()),
+            Self::Uint(_) => return Err("Cannot convert a value of 'uint' to 'bool'.".into()),
+            Self::Decimal(_) => return Err("Cannot convert a value of 'decimal' to 'bool'.".into()),
+            Self::Bool(x) => return Ok(*x),
+            Self::Str(x) => return x.trim().parse().map_err(|_| format!("Cannot convert the string '{}' to 'bool'.", x).into()),
+            Value::Struct(_) => return Err("Cannot convert a struct to any value.".into()),
+            Value::Coroutine(_) => return Err("Cannot convert a coroutine to any value.".into()),
+            Self::None => Ok(false),
             //_ => panic!("Unknown value used to convert to 'int'."),
         }
     }
 
-    pub fn div_s(&self, other: &Value) -> Value {
-        binary_op_with_value!(self, other, /, { panic!("Cannot divide with a bool type in operation.") }, { panic!("Cannot divide with a string type in operation.") });
+    pub fn div_s(&self, other: &Value) -> Result<Value, RuntimeError> {
+        // Whole number division by zero would take down the host, so it's reported instead.
+        if *self < Value::Decimal(0.0) && *other < Value::Decimal(0.0) && other.int_s()? == 0 {
+            return Err("Cannot divide by zero.".into());
+        }
+
+        binary_op_with_value!(self, other, /, checked_div, { return Err("Cannot divide with a bool type in operation.".into()) }, { return Err("Cannot divide with a string type in operation.".into()) });
     }
 
-    pub fn mul_s(&self, other: &Value) -> Value {
-        binary_op_with_value!(self, other, *, { panic!("Cannot multiple with a bool type in operation.") }, { panic!("Cannot multiple with a string type in operation.") });
+    pub fn mul_s(&self, other: &Value) -> Result<Value, RuntimeError> {
+        binary_op_with_value!(self, other, *, checked_mul, { return Err("Cannot multiple with a bool type in operation.".into()) }, { return Err("Cannot multiple with a string type in operation.".into()) });
     }
 
-    pub fn sub_s(&self, other: &Value) -> Value {
-        binary_op_with_value!(self, other, -, { panic!("Cannot subtract with a bool type in operation.") }, { panic!("Cannot subtract with a string type in operation.") });
+    pub fn sub_s(&self, other: &Value) -> Result<Value, RuntimeError> {
+        binary_op_with_value!(self, other, -, checked_sub, { return Err("Cannot subtract with a bool type in operation.".into()) }, { return Err("Cannot subtract with a string type in operation.".into()) });
     }
 
-    pub fn add_s(&self, other: &Value) -> Value {
-        binary_op_with_value!(self, other, +, { panic!("Cannot add with a bool type in operation.") }, {
+    pub fn add_s(&self, other: &Value) -> Result<Value, RuntimeError> {
+        binary_op_with_value!(self, other, +, checked_add, { return Err("Cannot add with a bool type in operation.".into()) }, {
             let mut str = self.string_s();
             str.push_str(&other.string_s());
-            return Value::Str(str);
+            return Ok(Value::Str(str));
         });
     }
 }
\ No newline at end of file
@@ -0,0 +1,217 @@
+use crate::chunk::{Chunk, Function, OpCode};
+use crate::environment::EnvEntry;
+
+// What's known about the vm at an instruction, relative to where the function it's in started.
+#[derive(Clone, PartialEq)]
+struct State {
+    stack: usize,
+    call_stack: usize,
+    scope: usize,
+    // How many exception handlers the function has started and not ended.
+    handlers: usize,
+}
+
+struct Verifier<'a> {
+    chunk: &'a Chunk,
+    states: Vec<Option<State>>,
+    pending: Vec<(usize, State)>,
+}
+
+impl<'a> Verifier<'a> {
+    fn error(&self, at: usize, message: String) -> String {
+        return match self.chunk.lines.get(at) {
+            Some(line) => format!("[Line {}] Instruction {}: {}", line, at, message),
+            None => format!("Instruction {}: {}", at, message),
+        }
+    }
+
+    // Every way of reaching an instruction has to agree on the state there.
+    fn reach(&mut self, from: usize, at: usize, state: State) -> Result<(), String> {
+        if at >= self.chunk.code.len() {
+            return Err(self.error(from, format!("Jumps to {}, past the end of the code", at)));
+        }
+
+        match &self.states[at] {
+            Some(existing) => if *existing != state {
+                return Err(self.error(at, format!(
+                    "Reached with a stack of {}, call stack of {}, scope of {} and {} handler(s), but before with {}, {}, {} and {}",
+                    state.stack, state.call_stack, state.scope, state.handlers, existing.stack, existing.call_stack, existing.scope, existing.handlers
+                )));
+            },
+            None => {
+                self.states[at] = Some(state.clone());
+                self.pending.push((at, state));
+            }
+        }
+
+        return Ok(());
+    }
+
+    fn has_struct(&self, name: &str) -> bool {
+        return self.chunk.structs.entries.iter().any(|entry| match entry {
+            EnvEntry::Structure(structure) => structure.name == name,
+            _ => false,
+        });
+    }
+
+    fn struct_fields(&self, name: &str) -> usize {
+        for entry in &self.chunk.structs.entries {
+            if let EnvEntry::Structure(structure) = entry {
+                if structure.name == name {
+                    return structure.field_names.len();
+                }
+            }
+        }
+
+        return 0;
+    }
+
+    fn run(&mut self, entry: usize, state: State) -> Result<(), String> {
+        self.reach(entry, entry, state)?;
+
+        while let Some((at, state)) = self.pending.pop() {
+            let op = &self.chunk.code[at];
+            let mut state = state;
+
+            // How many values the op takes off of the stack and puts back on.
+            let (pops, pushes) = match op {
+                OpCode::Push(_) | OpCode::Load(_) | OpCode::NewStruct(_) | OpCode::LoadLocal(_) | OpCode::LoadGlobal(_) => (0, 1),
+                OpCode::Pop | OpCode::Store(_, _) | OpCode::InferStore(_) | OpCode::Assign(_) | OpCode::DefineLocal(_, _, _)
+                | OpCode::InferLocal(_, _) | OpCode::StoreLocal(_) | OpCode::DefineGlobal(_, _) | OpCode::InferGlobal(_)
+                | OpCode::StoreGlobal(_) | OpCode::JmpIfFalse(_) | OpCode::PopToCall | OpCode::Throw | OpCode::Return
+                | OpCode::YieldValue => (1, 0),
+                OpCode::Add | OpCode::Sub | OpCode::Mul | OpCode::Div | OpCode::CmpLess | OpCode::CmpLessEqual
+                | OpCode::CmpGreater | OpCode::CmpGreaterEqual | OpCode::CmpEqual | OpCode::CmpNotEqual
+                | OpCode::CmpAnd | OpCode::CmpOr | OpCode::StructSet(_) | OpCode::StructSetByIndex(_) => (2, 1),
+                OpCode::Negate | OpCode::Not | OpCode::TransformToType(_) | OpCode::StructGet(_) | OpCode::Resume
+                | OpCode::IsFinished | OpCode::ForIter(_) => (1, 1),
+                OpCode::Call(_) => (0, 1),
+                _ => (0, 0),
+            };
+
+            if state.stack < pops {
+                return Err(self.error(at, format!("Takes {} value(s) off of a stack with {}", pops, state.stack)));
+            }
+            state.stack = state.stack - pops + pushes;
+
+            match op {
+                OpCode::PopToCall => state.call_stack += 1,
+                OpCode::ArgumentStore(_, _) | OpCode::ArgumentLocal(_, _, _) => {
+                    if state.call_stack == 0 {
+                        return Err(self.error(at, "Takes an argument when there aren't any left".to_string()));
+                    }
+                    state.call_stack -= 1;
+                },
+                OpCode::Call(index) => {
+                    match &self.chunk.functions[*index as usize] {
+                        Function::Script(name, _, count, _) | Function::Native(name, count, _, _) => if state.call_stack != *count {
+                            return Err(self.error(at, format!("Calls '{}' with {} argument(s), but it takes {}", name, state.call_stack, count)));
+                        },
+                        Function::Unbound(name) => return Err(self.error(at, format!("Calls '{}' which was never bound", name))),
+                    }
+                    state.call_stack = 0;
+                },
+                OpCode::NewStruct(name) => {
+                    let name = self.chunk.name(*name);
+                    if !self.has_struct(name) {
+                        return Err(self.error(at, format!("Creates the structure '{}' which was never declared", name)));
+                    }
+                },
+                // Fields are set by index right after the structure is created with 'new'.
+                OpCode::StructSetByIndex(index) => {
+                    let mut created = at;
+                    while created > 0 && matches!(self.chunk.code[created - 1], OpCode::StructSetByIndex(_)) {
+                        created -= 1;
+                    }
+
+                    if created > 0 {
+                        if let OpCode::NewStruct(name) = &self.chunk.code[created - 1] {
+                            let name = self.chunk.name(*name);
+                            if *index >= self.struct_fields(name) {
+                                return Err(self.error(at, format!("Sets field {} of '{}', which only has {}", index, name, self.struct_fields(name))));
+                            }
+                        }
+                    }
+                },
+                OpCode::StartScope => state.scope += 1,
+                OpCode::EndScope => {
+                    if state.scope == 0 {
+                        return Err(self.error(at, "Ends a scope that was never started".to_string()));
+                    }
+                    state.scope -= 1;
+                },
+                OpCode::TryEnd => {
+                    if state.handlers == 0 {
+                        return Err(self.error(at, "Ends an exception handler that was never started".to_string()));
+                    }
+                    state.handlers -= 1;
+                },
+                // Leaving the function or the script has to end every scope and handler it started.
+                OpCode::Return | OpCode::EndScript => {
+                    if state.scope != 0 {
+                        return Err(self.error(at, format!("Leaves with {} scope(s) that were never ended", state.scope)));
+                    }
+
+                    if state.handlers != 0 {
+                        return Err(self.error(at, format!("Leaves with {} exception handler(s) that were never ended", state.handlers)));
+                    }
+                },
+                _ => {},
+            }
+
+            match op {
+                OpCode::Return | OpCode::Throw | OpCode::EndScript => {},
+                OpCode::Jmp(to) => self.reach(at, *to, state)?,
+                OpCode::JmpIfFalse(to) => {
+                    self.reach(at, *to, state.clone())?;
+                    self.reach(at, at + 1, state)?;
+                },
+                // Whatever is thrown is on the stack when the 'catch' block starts, and the handler
+                // is only started for the ops after this one.
+                OpCode::TryStart(catch) => {
+                    let mut caught = state.clone();
+                    caught.stack += 1;
+                    self.reach(at, *catch, caught)?;
+
+                    let mut handled = state;
+                    handled.handlers += 1;
+                    self.reach(at, at + 1, handled)?;
+                },
+                // The coroutine is taken off of the stack without a value put back once it's finished.
+                OpCode::ForIter(exit) => {
+                    let mut finished = state.clone();
+                    finished.stack -= 1;
+                    self.reach(at, *exit, finished)?;
+                    self.reach(at, at + 1, state)?;
+                },
+                _ => self.reach(at, at + 1, state)?,
+            }
+        }
+
+        return Ok(());
+    }
+}
+
+impl Chunk {
+    // Checks that the code can be run without trusting where it came from: jumps land inside
+    // the code, every path into an instruction agrees on the stack, scope and handler depth,
+    // scopes and handlers are ended before leaving, values are never taken off of an empty
+    // stack, and calls and structures refer to things that exist.
+    pub fn verify(&self) -> Result<(), String> {
+        let mut verifier = Verifier { chunk: self, states: vec![None; self.code.len()], pending: Vec::new() };
+
+        if self.code.len() == 0 {
+            return Ok(());
+        }
+
+        verifier.run(0, State { stack: 0, call_stack: 0, scope: 0, handlers: 0 })?;
+
+        for function in &self.functions {
+            if let Function::Script(_, _, count, position) = function {
+                verifier.run(*position, State { stack: 0, call_stack: *count, scope: 0, handlers: 0 })?;
+            }
+        }
+
+        return Ok(());
+    }
+}
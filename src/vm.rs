@@ -1,80 +1,217 @@
-use crate::environment::{EnvEntry, Environment};
+use std::mem;
+use std::rc::Rc;
+use std::panic::{self, AssertUnwindSafe};
+use std::time::Instant;
+
+use crate::coroutine::{Coroutine, CoroutineRef, CoroutineState};
+use crate::environment::{EnvEntry, Environment, Variable};
+use crate::error::RuntimeError;
 use crate::value::{ClassType, Value};
-use crate::chunk::{Chunk, OpCode};
+use crate::bytecode;
+use crate::chunk::{Chunk, Function};
+use crate::trace::{TraceEvent, Tracer};
+use crate::debugger::Debugger;
+
+// Where to continue from when something is thrown inside of a 'try' block, along with
+// the sizes everything has to be unwound back to.
+struct ExceptionHandler {
+    catch_position: usize,
+    stack_size: usize,
+    call_stack_size: usize,
+    locals_size: usize,
+    function_depth: usize,
+    scope: usize,
+}
+
+// A function being run, with where to go back to once it returns and where its variables start.
+#[derive(Clone)]
+pub struct CallFrame {
+    // Index of the function in the chunk's function table.
+    pub function: u32,
+    pub return_position: usize,
+    // The scope the function's body starts in, everything at or above it belongs to the call.
+    pub starting_scope: usize,
+    pub return_type: ClassType,
+    pub locals_start: usize,
+}
+
+// Everything that belongs to one line of execution, the script itself or a coroutine. The one
+// being run is spread out over the vm, and the rest are put aside in one of these.
+#[derive(Default)]
+pub struct Context {
+    idx: usize,
+    stack: Vec<Value>,
+    call_stack: Vec<Value>,
+    frames: Vec<CallFrame>,
+    handlers: Vec<ExceptionHandler>,
+    locals: Vec<Variable>,
+    // Variables found by name that are above the globals' scope.
+    entries: Vec<EnvEntry>,
+    scope: usize,
+}
 
-pub struct VM<'a> {
-    pub chunk: &'a Chunk,
+// A coroutine being run, along with what resumed it.
+struct Resumed {
+    coroutine: CoroutineRef,
+    caller: Context,
+    // Where a 'for' loop goes once the coroutine has finished.
+    for_exit: Option<usize>,
+}
+
+// Why the vm stopped running.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RunState {
+    // The script reached its end.
+    Finished,
+    // The script yielded, called a suspending native or used up its instructions, and can be
+    // carried on by running it again.
+    Yielded,
+    // The debugger stopped the script at a breakpoint or after a step, running it again
+    // carries on from there.
+    Paused,
+}
+
+// A function being run, as shown by the debugger.
+#[derive(Debug, Clone)]
+pub struct FrameInfo {
+    pub name: String,
+    // Where in the function the vm is, the file is none if the chunk wasn't generated from files.
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+pub struct VM {
+    // The program being run, other chunks can be linked into it while the vm is around.
+    pub chunk: Rc<Chunk>,
     pub idx: usize,
     pub stack: Vec<Value>,
     pub call_stack: Vec<Value>,
 
     pub environment: Environment,
 
-    pub function_starting_scope: Vec<usize>,
-    pub function_jump_back: Vec<usize>, 
-    pub function_return_types: Vec<ClassType>,
+    pub frames: Vec<CallFrame>,
+
+    // Going past either of these stops the script with a stack overflow.
+    pub max_call_depth: usize,
+    pub max_stack_size: usize,
+
+    // How many more ops can be run, with none meaning there's no limit. Each op uses one.
+    pub fuel: Option<u64>,
+    // When to stop running, checked every so many ops since getting the time isn't free.
+    pub deadline: Option<Instant>,
+    // How many ops have been run in total.
+    pub instructions: u64,
+    // Told about every op before it's run, see 'trace.rs' for the ones copper comes with.
+    pub tracer: Option<Box<dyn Tracer>>,
+    // Pauses the script at breakpoints and while stepping through it.
+    pub debugger: Option<Debugger>,
+    // How many more ops can be run before yielding, set by 'run_for'.
+    budget: Option<u64>,
+
+    handlers: Vec<ExceptionHandler>,
+    // Coroutines being run, the innermost one is the one running right now.
+    resumed: Vec<Resumed>,
 }
 
 // Set of macros for making the repetitive task of comparing with binary less tedious.
 macro_rules! binary_compare {
     ($self:expr, $op:tt, $type:literal) => {
-        binary_compare!(basic $self, $op, { panic!("Cannot compare for '{}' with 'string' type.", $type) }, { panic!("Cannot compare for '{}' with 'bool' type.", $type) }, $type)
+        binary_compare!(basic $self, $op, { return Err(format!("Cannot compare for '{}' with 'string' type.", $type).into()) }, { return Err(format!("Cannot compare for '{}' with 'bool' type.", $type).into()) }, $type)
     };
 
     (basic $self:expr, $op:tt, $string_block:expr, $bool_block:expr, $type:literal) => { 
-        let (a, b, value_type) = $self.binary_op_vals();
+        let (a, b, value_type) = $self.binary_op_vals()?;
         
         match value_type {
-            Value::Uint(_) => $self.stack.push(Value::Bool(a.uint_s() $op b.uint_s())),
-            Value::Int(_) => $self.stack.push(Value::Bool(a.int_s() $op b.int_s())),
-            Value::Decimal(_) => $self.stack.push(Value::Bool(a.decimal_s() $op b.decimal_s())),
+            Value::Uint(_) => $self.stack.push(Value::Bool(a.uint_s()? $op b.uint_s()?)),
+            Value::Int(_) => $self.stack.push(Value::Bool(a.int_s()? $op b.int_s()?)),
+            Value::Decimal(_) => $self.stack.push(Value::Bool(a.decimal_s()? $op b.decimal_s()?)),
             Value::Str(_) => $string_block,
             Value::Bool(_) => $bool_block,
-            Value::Struct(_) => panic!("Cannot compare for '{}' with structures.", $type),
-            Value::None => panic!("Cannot compare for '{}' with 'none' type.", $type),
+            Value::Struct(_) => return Err(format!("Cannot compare for '{}' with structures.", $type).into()),
+            Value::Coroutine(_) => return Err(format!("Cannot compare for '{}' with coroutines.", $type).into()),
+            Value::None => return Err(format!("Cannot compare for '{}' with 'none' type.", $type).into()),
         }
     };
 
     (string, bool; $self:expr, $op:tt, $type:literal) => {
-        let (a, b, value_type) = $self.binary_op_vals();
+        let (a, b, value_type) = $self.binary_op_vals()?;
         
         match value_type {
-            Value::Uint(_) => $self.stack.push(Value::Bool(a.uint_s() $op b.uint_s())),
-            Value::Int(_) => $self.stack.push(Value::Bool(a.int_s() $op b.int_s())),
-            Value::Decimal(_) => $self.stack.push(Value::Bool(a.decimal_s() $op b.decimal_s())),
+            Value::Uint(_) => $self.stack.push(Value::Bool(a.uint_s()? $op b.uint_s()?)),
+            Value::Int(_) => $self.stack.push(Value::Bool(a.int_s()? $op b.int_s()?)),
+            Value::Decimal(_) => $self.stack.push(Value::Bool(a.decimal_s()? $op b.decimal_s()?)),
             Value::Str(_) => {
                 $self.stack.push(Value::Bool(a.string_s() $op b.string_s()));
             },
             Value::Bool(_) => {
-                $self.stack.push(Value::Bool(a.bool_s() $op b.bool_s()));
+                $self.stack.push(Value::Bool(a.bool_s()? $op b.bool_s()?));
             },
-            Value::Struct(_) => panic!("Cannot compare for '{}' with structures.", $type),
-            Value::None => panic!("Cannot compare for '{}' with 'none' type.", $type),
+            Value::Struct(_) => return Err(format!("Cannot compare for '{}' with structures.", $type).into()),
+            Value::Coroutine(_) => return Err(format!("Cannot compare for '{}' with coroutines.", $type).into()),
+            Value::None => return Err(format!("Cannot compare for '{}' with 'none' type.", $type).into()),
         }
     };
 }
 
-impl<'a> VM<'a> {
-    pub fn new(chunk: &'a Chunk) -> VM {
-        return VM { chunk, idx: 0, environment: Environment::new(), stack: Vec::new(), call_stack: Vec::new(), function_starting_scope: Vec::new(), function_jump_back: Vec::new(), function_return_types: Vec::new() }
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 4096;
+pub const DEFAULT_MAX_STACK_SIZE: usize = 1 << 20;
+
+// Where a function called from the host returns to, running stops there and the host gets the value.
+const HOST_RETURN: usize = usize::MAX;
+
+// How many ops are run between checks of the deadline.
+const DEADLINE_CHECK_INTERVAL: u64 = 1024;
+
+impl VM {
+    pub fn new(chunk: impl Into<Rc<Chunk>>) -> VM {
+        return VM { chunk: chunk.into(), idx: 0, environment: Environment::new(), stack: Vec::new(), call_stack: Vec::new(), frames: Vec::new(), max_call_depth: DEFAULT_MAX_CALL_DEPTH, max_stack_size: DEFAULT_MAX_STACK_SIZE, fuel: None, deadline: None, instructions: 0, tracer: None, debugger: None, budget: None, handlers: Vec::new(), resumed: Vec::new() }
     }
 
-    fn read_op(&mut self) -> OpCode {
-        let result = self.chunk.code[self.idx].clone();
+    fn read_byte(&mut self) -> u8 {
+        let byte = self.chunk.bytecode[self.idx];
         self.idx += 1;
-        return result.clone();
+        return byte;
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let value = bytecode::read_u16(&self.chunk.bytecode, self.idx);
+        self.idx += 2;
+        return value;
+    }
+
+    fn read_u32(&mut self) -> u32 {
+        let value = bytecode::read_u32(&self.chunk.bytecode, self.idx);
+        self.idx += 4;
+        return value;
+    }
+
+    // Where the slots of the function being run start in the locals.
+    fn locals_start(&self) -> usize {
+        return match self.frames.last() {
+            Some(frame) => frame.locals_start,
+            None => 0,
+        }
+    }
+
+    // Variables looked up by name can only be ones from the function being run or globals.
+    fn frame_scope(&self) -> usize {
+        return match self.frames.last() {
+            Some(frame) => frame.starting_scope,
+            None => 0,
+        }
     }
 
-    fn stack_pop(&mut self) -> Value {
+    fn stack_pop(&mut self) -> Result<Value, RuntimeError> {
         return match self.stack.pop() {
-            Some(v) => v,
-            None => panic!("Expected a value when popping from the stack."),
+            Some(v) => Ok(v),
+            None => Err("Expected a value when popping from the stack.".into()),
         }
     }
 
-    fn binary_op_vals(&mut self) -> (Value, Value, Value) {
-        let b = self.stack_pop();
-        let a = self.stack_pop();
+    fn binary_op_vals(&mut self) -> Result<(Value, Value, Value), RuntimeError> {
+        let b = self.stack_pop()?;
+        let a = self.stack_pop()?;
 
         let mut value_type: Value = Value::None;
 
@@ -86,323 +223,921 @@ impl<'a> VM<'a> {
             value_type = b.clone();
         }
 
-        return (a, b, value_type);
+        return Ok((a, b, value_type));
     }
 
-    pub fn interpret(&mut self) {
-        // These are set for debugging the internal process of the interpeter
-        const DEBUGGING: bool = false;
+    // Natives report errors by panicking, so they're caught here and turned into runtime errors.
+    // The panic isn't printed, since the script can catch the error.
+    fn call_native(&mut self, func: &'static dyn Fn(Vec<Value>) -> Value, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        let hook = panic::take_hook();
+        panic::set_hook(Box::new(|_| {}));
+        let result = panic::catch_unwind(AssertUnwindSafe(|| func(arguments)));
+        panic::set_hook(hook);
 
-        // This is set for debugging current amount of values used in memory.
-        const DEBUG_MEMORY: bool = false;
+        return match result {
+            Ok(value) => Ok(value),
+            Err(payload) => {
+                if let Some(message) = payload.downcast_ref::<&str>() {
+                    Err(message.to_string().into())
+                } else if let Some(message) = payload.downcast_ref::<String>() {
+                    Err(message.clone().into())
+                } else {
+                    Err("A native function failed.".into())
+                }
+            }
+        }
+    }
 
-        // If set to true, then it means it will print amount of values on the stack every 1000 objects.
-        // Turn to false if you want to see the amount of values for every op in the vm.
-        const DEBUG_MEMORY_LEN_1000: bool = false;
+    // Every function being run with where it's at, starting with the innermost one. The script
+    // itself comes last, unless it was called from the host.
+    pub fn stack_frames(&self) -> Vec<FrameInfo> {
+        let mut frames = Vec::new();
+        let mut at = self.idx;
 
-        // Debug what the current scope of the vm enviroment is.
-        const DEBUG_SCOPE: bool = false;
-        
-        loop {
-            if DEBUG_MEMORY && (!DEBUG_MEMORY_LEN_1000 || self.stack.len() % 1000 == 0) {
-                println!("Current amount of values on stack: {}", self.stack.len());
+        for frame in self.frames.iter().rev() {
+            let name = self.chunk.functions[frame.function as usize].name().to_string();
+            frames.push(FrameInfo { name, file: self.chunk.file_at(at).map(str::to_string), line: self.chunk.line_at(at) });
+
+            if frame.return_position == HOST_RETURN {
+                return frames;
             }
 
-            if DEBUG_SCOPE {
-                println!("Current Scope: {}", self.environment.current_scope);
+            at = frame.return_position - 1;
+        }
+
+        frames.push(FrameInfo { name: "script".to_string(), file: self.chunk.file_at(at).map(str::to_string), line: self.chunk.line_at(at) });
+        return frames;
+    }
+
+    // The variables belonging to a frame from 'stack_frames', leaving out the globals.
+    pub fn frame_locals(&self, frame: usize) -> Vec<(String, Value)> {
+        let count = self.frames.len();
+        if frame > count {
+            return Vec::new();
+        }
+
+        // The slots and scopes of a call go up to where the call it made starts.
+        let (locals_start, scope_start) = match count.checked_sub(frame + 1) {
+            Some(index) => (self.frames[index].locals_start, self.frames[index].starting_scope),
+            None => (0, 1),
+        };
+
+        let (locals_end, scope_end) = match frame {
+            0 => (self.environment.locals.len(), usize::MAX),
+            _ => {
+                let inner = &self.frames[count - frame];
+                (inner.locals_start, inner.starting_scope)
             }
+        };
 
-            // This is the full debugging experience.
-            if DEBUGGING {
-                println!("====");
-                println!("| Current Scope '{}'", self.environment.current_scope);
-                print!("| Stack [ ");
+        let mut locals: Vec<(String, Value)> = self.environment.locals[locals_start..locals_end.max(locals_start)]
+            .iter()
+            .map(|variable| (variable.name.clone(), variable.value.clone()))
+            .collect();
 
-                for i in &self.stack {
-                    i.print();
-                    print!(", ");
+        for entry in &self.environment.entries {
+            if let EnvEntry::Variable(name, value, _, scope) = entry {
+                if *scope >= scope_start && *scope < scope_end {
+                    locals.push((name.clone(), value.clone()));
                 }
+            }
+        }
+
+        return locals;
+    }
+
+    // Every call being made, innermost first. Calls repeated from the same place, like from
+    // runaway recursion, are folded into one line.
+    pub fn backtrace(&self) -> Vec<String> {
+        let mut backtrace: Vec<String> = Vec::new();
+        let mut repeated = 0;
+
+        for (i, frame) in self.frames.iter().enumerate().rev() {
+            let name = self.chunk.functions[frame.function as usize].name();
+            let call = if frame.return_position == HOST_RETURN {
+                format!("in '{}' called from the host", name)
+            } else {
+                format!("in '{}' called from line {}", name, self.chunk.line_at(frame.return_position - 1))
+            };
+
+            let next_is_same = i > 0 && {
+                let next = &self.frames[i - 1];
+                next.function == frame.function && next.return_position == frame.return_position
+            };
+
+            if next_is_same {
+                repeated += 1;
+                continue;
+            }
+
+            if repeated > 0 {
+                backtrace.push(format!("{} ({} more times)", call, repeated));
+                repeated = 0;
+            } else {
+                backtrace.push(call);
+            }
+        }
+
+        return backtrace;
+    }
+
+    // Unwinds everything back to the innermost handler and jumps to its 'catch' block,
+    // giving back the error if there isn't a handler to catch it.
+    fn throw(&mut self, error: RuntimeError) -> Result<(), RuntimeError> {
+        if !error.is_catchable() {
+            return Err(error);
+        }
+
+        // A coroutine that doesn't catch what's thrown is finished by it, and it carries on to
+        // whatever resumed the coroutine.
+        let handler = loop {
+            match self.handlers.pop() {
+                Some(handler) => break handler,
+                None if !self.resumed.is_empty() => {
+                    self.leave_coroutine(CoroutineState::Finished)?;
+                },
+                None => return Err(error),
+            }
+        };
+
+        self.frames.truncate(handler.function_depth);
+        self.environment.locals.truncate(handler.locals_size);
+
+        self.environment.remove_from_scope(handler.scope + 1);
+        self.environment.current_scope = handler.scope;
+
+        self.stack.truncate(handler.stack_size);
+        self.call_stack.truncate(handler.call_stack_size);
+        self.stack.push(error.to_value());
+
+        self.idx = handler.catch_position;
+        return Ok(());
+    }
+
+    // Swaps everything belonging to whatever's being run with what's in the context.
+    fn swap_context(&mut self, context: &mut Context) {
+        mem::swap(&mut self.idx, &mut context.idx);
+        mem::swap(&mut self.stack, &mut context.stack);
+        mem::swap(&mut self.call_stack, &mut context.call_stack);
+        mem::swap(&mut self.frames, &mut context.frames);
+        mem::swap(&mut self.handlers, &mut context.handlers);
+        mem::swap(&mut self.environment.locals, &mut context.locals);
+        mem::swap(&mut self.environment.current_scope, &mut context.scope);
+
+        // Globals in scope 0 and structures are shared by everything.
+        let (scoped, shared): (Vec<EnvEntry>, Vec<EnvEntry>) = mem::take(&mut self.environment.entries)
+            .into_iter()
+            .partition(|entry| matches!(entry, EnvEntry::Variable(_, _, _, scope) if *scope > 0));
+
+        self.environment.entries = shared;
+        self.environment.entries.append(&mut context.entries);
+        context.entries = scoped;
+    }
+
+    // Switches to the coroutine, it switches back once it yields or finishes.
+    fn resume_coroutine(&mut self, coroutine: CoroutineRef, for_exit: Option<usize>) -> Result<(), RuntimeError> {
+        if self.resumed.len() >= self.max_call_depth {
+            let message = format!("Went past the maximum call depth of {}", self.max_call_depth);
+            return Err(RuntimeError::stack_overflow(message, self.backtrace()));
+        }
+
+        let mut context = {
+            let mut inner = coroutine.0.borrow_mut();
+
+            match inner.state {
+                CoroutineState::Suspended => {},
+                CoroutineState::Running => return Err(format!("Cannot resume the coroutine '{}' while it's running.", inner.name).into()),
+                CoroutineState::Finished => return Err(format!("Cannot resume the coroutine '{}' as it has finished.", inner.name).into()),
+            }
+
+            inner.state = CoroutineState::Running;
+            mem::take(&mut inner.context)
+        };
+
+        self.swap_context(&mut context);
+        self.resumed.push(Resumed { coroutine, caller: context, for_exit });
+        return Ok(());
+    }
+
+    // Switches back to whatever resumed the coroutine being run. A suspended coroutine keeps its
+    // context to carry on from later, while a finished one lets go of it.
+    fn leave_coroutine(&mut self, state: CoroutineState) -> Result<Option<usize>, RuntimeError> {
+        let resumed = match self.resumed.pop() {
+            Some(resumed) => resumed,
+            None => return Err("Can only yield a value inside of a coroutine.".into()),
+        };
+
+        let mut context = resumed.caller;
+        self.swap_context(&mut context);
+
+        let mut coroutine = resumed.coroutine.0.borrow_mut();
+        coroutine.state = state;
+
+        if state == CoroutineState::Suspended {
+            coroutine.context = context;
+        }
 
-                println!("]");
+        return Ok(resumed.for_exit);
+    }
+
+    fn pop_coroutine(&mut self) -> Result<CoroutineRef, RuntimeError> {
+        return match self.stack_pop()? {
+            Value::Coroutine(coroutine) => Ok(coroutine),
+            value => Err(format!("Expected a coroutine, but got a value of '{}'.", value.type_to_string()).into()),
+        }
+    }
+
+    // Runs the script to its end, carrying on through anything that yields. A debugger pausing
+    // it stops it early.
+    pub fn interpret(&mut self) -> Result<(), RuntimeError> {
+        self.budget = None;
+
+        while self.resume()? == RunState::Yielded {}
+
+        return Ok(());
+    }
+
+    // Calls a function in the chunk with arguments from rust, giving back what it returns. It's
+    // run on its own, with only the globals from the script, so call it once the script has run
+    // the declarations it needs. An error ends the call, leaving the script as it was.
+    pub fn call(&mut self, name: &str, arguments: Vec<Value>) -> Result<Value, RuntimeError> {
+        let chunk = Rc::clone(&self.chunk);
 
-                print!("| Call Stack [ ");
+        let index = match chunk.find_function(name) {
+            Some(index) => index,
+            None => return Err(format!("Cannot find a function by the name of '{}'.", name).into()),
+        };
 
-                for i in &self.call_stack {
-                    i.print();
-                    print!(", ");
+        let (ctype, count, position) = match &chunk.functions[index as usize] {
+            Function::Script(_, ctype, count, position) => (ctype.clone(), *count, *position),
+            Function::Native(_, count, func, _) => {
+                if arguments.len() != *count {
+                    return Err(format!("Expected {} argument(s) for '{}', but got {}", count, name, arguments.len()).into());
                 }
 
-                println!("]");
+                return self.call_native(*func, arguments);
+            },
+            Function::Unbound(_) => return Err(format!("The function '{}' was never bound!", name).into()),
+        };
+
+        if arguments.len() != count {
+            return Err(format!("Expected {} argument(s) for '{}', but got {}", count, name, arguments.len()).into());
+        }
+
+        // Arguments are taken off of the end of the call stack, first argument first.
+        let mut call_stack = arguments;
+        call_stack.reverse();
+
+        let mut context = Context {
+            idx: chunk.offsets[position],
+            call_stack,
+            frames: vec![CallFrame { function: index, return_position: HOST_RETURN, starting_scope: 1, return_type: ctype, locals_start: 0 }],
+            ..Context::default()
+        };
 
-                println!("| Variables: ");
+        self.swap_context(&mut context);
+        let resumed = mem::take(&mut self.resumed);
+        let budget = self.budget.take();
+        // Pausing part way through would leave the host without a value, so calls aren't debugged.
+        let debugger = self.debugger.take();
 
-                for i in &self.environment.entries {
-                    print!("| >{}", i);
+        let mut result = Ok(RunState::Yielded);
+        while let Ok(RunState::Yielded) = result {
+            result = self.resume();
+        }
+
+        let value = match result {
+            Ok(_) => self.stack_pop(),
+            Err(error) => Err(error),
+        };
+
+        self.swap_context(&mut context);
+        self.resumed = resumed;
+        self.budget = budget;
+        self.debugger = debugger;
+
+        return value;
+    }
+
+    // Globals can be read and set by the host at any point, including before the script runs so
+    // it starts off with values from the host. Setting one the script declares converts the value
+    // to its declared type, and when it's set before the declaration runs the declaration keeps
+    // the host's value instead of its own. Any other global takes the type of its value.
+    pub fn get_global(&self, name: &str) -> Option<Value> {
+        if let Some(index) = self.chunk.global_index(name) {
+            if let Some(Some(variable)) = self.environment.globals.get(index) {
+                return Some(variable.value.clone());
+            }
+        }
+
+        return self.environment.get_named_global(name).cloned();
+    }
+
+    pub fn set_global(&mut self, name: &str, value: Value) -> Result<(), RuntimeError> {
+        if let Some(index) = self.chunk.global_index(name) {
+            return match self.environment.globals.get(index) {
+                Some(Some(_)) => self.environment.assign_global(index, name, value),
+                _ => {
+                    let ctype = self.chunk.global_type(index).cloned().unwrap_or_else(|| value.class_type());
+                    self.environment.seed_global(index, name.to_string(), ctype, value)
+                },
+            }
+        }
+
+        return self.environment.set_named_global(name, value);
+    }
+
+    // Every global that has a value, by name.
+    pub fn globals(&self) -> impl Iterator<Item = (&str, &Value)> {
+        let indexed = self.environment.globals.iter().flatten().map(|variable| (variable.name.as_str(), &variable.value));
+        let named = self.environment.entries.iter().filter_map(|entry| match entry {
+            EnvEntry::Variable(name, value, _, 0) => Some((name.as_str(), value)),
+            _ => None,
+        });
+
+        return indexed.chain(named);
+    }
+
+    // Adds another chunk to the program. Its top level is run from where the program ended the
+    // next time the vm is run, and what it declares can be used from then on.
+    pub fn link(&mut self, chunk: &Chunk) -> Result<(), String> {
+        return Rc::make_mut(&mut self.chunk).link(chunk);
+    }
+
+    // Swaps the program for a newer one from 'CopperGen::generate_more', which has the same code
+    // with more after it. The vm carries on from where it was into the new code, keeping the
+    // globals it already has.
+    pub fn update(&mut self, chunk: impl Into<Rc<Chunk>>) -> Result<(), String> {
+        let chunk = chunk.into();
+
+        if !chunk.bytecode.starts_with(&self.chunk.bytecode) {
+            return Err("Cannot update the program with a chunk that doesn't carry on from it.".to_string());
+        }
+
+        self.chunk = chunk;
+        return Ok(());
+    }
+
+    // Gives up on whatever the vm was part way through, like after an error, so the next run
+    // starts from the end of the program. Globals are kept like they are when a script ends.
+    pub fn skip_to_end(&mut self) {
+        self.idx = self.chunk.bytecode.len();
+        self.stack.clear();
+        self.call_stack.clear();
+        self.frames.clear();
+        self.handlers.clear();
+        self.resumed.clear();
+
+        self.environment.remove_from_scope(1);
+        self.environment.current_scope = 0;
+    }
+
+    // Runs the script until it finishes or the debugger pauses it, carrying on through anything
+    // that yields.
+    pub fn continue_running(&mut self) -> Result<RunState, RuntimeError> {
+        loop {
+            let state = self.resume()?;
+
+            if state != RunState::Yielded {
+                return Ok(state);
+            }
+        }
+    }
+
+    // How many calls deep the vm is, counting the coroutines being run.
+    pub fn call_depth(&self) -> usize {
+        return self.frames.len() + self.resumed.len();
+    }
+
+    // Runs at most the given amount of ops, stopping early if the script yields or finishes.
+    pub fn run_for(&mut self, instructions: u64) -> Result<RunState, RuntimeError> {
+        self.budget = Some(instructions);
+        let state = self.resume();
+        self.budget = None;
+
+        return state;
+    }
+
+    fn resume(&mut self) -> Result<RunState, RuntimeError> {
+        loop {
+            match self.run() {
+                Ok(state) => return Ok(state),
+                Err(mut error) => {
+                    if error.line == 0 && self.idx > 0 {
+                        error.line = self.chunk.line_at(self.idx - 1);
+                    }
+
+                    self.throw(error)?;
                 }
-                println!("====\n\n");
             }
+        }
+    }
 
-            match self.read_op() {
-                OpCode::Return => {
-                    if self.function_jump_back.len() != 0 {
-                        self.idx = self.function_jump_back.pop().unwrap();
-                        let jmp_back = self.function_starting_scope.pop().unwrap();
-                        self.environment.remove_from_scope(jmp_back);
-                        self.environment.current_scope = jmp_back - 1;
-                        
-                        let ctype = self.function_return_types.pop().unwrap();
+    // Checked before every op, so nothing has been run yet when either limit stops the vm.
+    fn check_limits(&mut self) -> Result<(), RuntimeError> {
+        if let Some(fuel) = self.fuel {
+            if fuel == 0 {
+                let mut error = RuntimeError::out_of_fuel(format!("Ran out of fuel after {} instructions", self.instructions));
+                error.line = self.chunk.line_at(self.idx);
+                return Err(error);
+            }
+
+            self.fuel = Some(fuel - 1);
+        }
+
+        if let Some(deadline) = self.deadline {
+            if self.instructions.is_multiple_of(DEADLINE_CHECK_INTERVAL) && Instant::now() >= deadline {
+                let mut error = RuntimeError::timeout(format!("Went past the deadline after {} instructions", self.instructions));
+                error.line = self.chunk.line_at(self.idx);
+                return Err(error);
+            }
+        }
+
+        self.instructions += 1;
+        return Ok(());
+    }
+
+    fn run(&mut self) -> Result<RunState, RuntimeError> {
+        // Held onto while running so ops can read the chunk while the vm changes.
+        let program = Rc::clone(&self.chunk);
+        let chunk: &Chunk = &program;
+        
+        if self.idx >= self.chunk.bytecode.len() {
+            return Ok(RunState::Finished);
+        }
+
+        loop {
+            if let Some(debugger) = &mut self.debugger {
+                let depth = self.frames.len() + self.resumed.len();
+
+                if debugger.should_pause(chunk, self.idx, depth) {
+                    return Ok(RunState::Paused);
+                }
+            }
+
+            if let Some(budget) = self.budget {
+                if budget == 0 {
+                    return Ok(RunState::Yielded);
+                }
+
+                self.budget = Some(budget - 1);
+            }
+
+            self.check_limits()?;
+
+            if let Some(tracer) = &mut self.tracer {
+                let (op, _) = chunk.decode(self.idx);
+
+                tracer.trace(chunk, &TraceEvent {
+                    offset: self.idx,
+                    line: chunk.line_at(self.idx),
+                    op,
+                    stack: &self.stack,
+                    call_stack: &self.call_stack,
+                    environment: &self.environment,
+                    call_depth: self.frames.len(),
+                });
+            }
+
+            match self.read_byte() {
+                bytecode::RETURN => {
+                    if let Some(frame) = self.frames.pop() {
+                        self.idx = frame.return_position;
+                        self.environment.remove_from_scope(frame.starting_scope);
+                        self.environment.current_scope = frame.starting_scope - 1;
+                        self.environment.locals.truncate(frame.locals_start);
+
+                        let ctype = frame.return_type;
+
+                        // Handlers from the function being returned out of don't apply anymore.
+                        while self.handlers.last().is_some_and(|handler| handler.function_depth > self.frames.len()) {
+                            self.handlers.pop();
+                        }
                         
                         match ctype {
                             ClassType::Any => {},
                             ClassType::Uint => {
-                                let value = self.stack_pop();
-                                self.stack.push(Value::Uint(value.uint_s()));
+                                let value = self.stack_pop()?;
+                                self.stack.push(Value::Uint(value.uint_s()?));
                             },
                             ClassType::Int => {
-                                let value = self.stack_pop();
-                                self.stack.push(Value::Int(value.int_s()));
+                                let value = self.stack_pop()?;
+                                self.stack.push(Value::Int(value.int_s()?));
                             },
                             ClassType::Decimal => {
-                                let value = self.stack_pop();
-                                self.stack.push(Value::Decimal(value.decimal_s()));
+                                let value = self.stack_pop()?;
+                                self.stack.push(Value::Decimal(value.decimal_s()?));
                             },
                             ClassType::Str => {
-                                let value = self.stack_pop();
+                                let value = self.stack_pop()?;
                                 self.stack.push(Value::Str(value.string_s()));
                             },
                             ClassType::Bool => {
-                                let value = self.stack_pop();
-                                self.stack.push(Value::Bool(value.bool_s()));
+                                let value = self.stack_pop()?;
+                                self.stack.push(Value::Bool(value.bool_s()?));
                             },
                             ClassType::Struct(x) => {
-                                let value = self.stack_pop();
-                                self.stack.push(Value::Struct(value.struct_s(x)));
+                                let value = self.stack_pop()?;
+                                self.stack.push(Value::Struct(value.struct_s(x)?));
+                            }
+                        }
+
+                        // Running stops once a call from the host returns.
+                        if frame.return_position == HOST_RETURN {
+                            return Ok(RunState::Finished);
+                        }
+
+                        // Returning out of the call a coroutine started with finishes it.
+                        if self.frames.is_empty() && !self.resumed.is_empty() {
+                            let value = self.stack_pop()?;
+
+                            match self.leave_coroutine(CoroutineState::Finished)? {
+                                Some(exit) => self.idx = exit,
+                                None => self.stack.push(value),
                             }
                         }
                     } else {
-                        panic!("Cannot return out of the script, only in function.");
+                        return Err("Cannot return out of the script, only in function.".into());
                     }
                 },
-                OpCode::StructSet(name, sets) => {
-                    let mut variable = self.environment.get_variable(name.clone());
-                    let mut value = self.stack_pop();
+                bytecode::STRUCT_SET => {
+                    let sets = self.read_u32();
+                    let structure = self.stack_pop()?;
+                    let value = self.stack_pop()?;
                     
-                    if let EnvEntry::Variable(_, val, _, _) = &mut variable {
-                        if let Value::Struct(cs) = val {
-                            cs.set(sets, value);
-                            value = Value::Struct(cs.clone());
-                        } else {
-                            panic!("Expected to set a struct");
+                    match structure {
+                        Value::Struct(mut cs) => {
+                            cs.set(&chunk.paths[sets as usize], value)?;
+                            self.stack.push(Value::Struct(cs));
                         }
+                        _ => return Err(format!("Expected a struct to set '{:?}' on", chunk.paths[sets as usize]).into()),
                     }
-
-                    self.environment.assign_variable(name, value);
                 }
-                OpCode::StructSetByIndex(index) => {
-                    let structure = self.stack_pop();
-                    let value = self.stack_pop();
+                bytecode::STRUCT_SET_BY_INDEX => {
+                    let index = self.read_u32() as usize;
+                    let structure = self.stack_pop()?;
+                    let value = self.stack_pop()?;
 
                     let structure = match structure {
                         Value::Struct(cs) => {
                             let mut cs = cs;
-                            if !(cs.field_values.len() <= index) {
-                                cs.field_values[index] = value;
-                            } else {
-                                self.stack.pop();
+                            if cs.field_values.len() <= index {
+                                return Err(format!("Cannot set field {} of structure '{}' as it only has {}", index, cs.name, cs.field_values.len()).into());
                             }
 
+                            cs.field_values[index] = value;
                             cs
                         }
-                        _ => panic!("Cannot assign by index as not a struct"),
+                        _ => return Err("Cannot assign by index as not a struct".into()),
                     };
 
                     self.stack.push(Value::Struct(structure));
                 }
-                OpCode::StructGet(name) => {
-                    let value = self.stack_pop();
+                bytecode::STRUCT_GET => {
+                    let name = self.read_u32();
+                    let value = self.stack_pop()?;
                     //println!("name: {}, val: {:?}", name.clone(), value.clone());
                     match value {
                         Value::Struct(cs) => {
                             //println!("Struct: "); cs.get(name.clone()).println();
-                            self.stack.push(cs.get(name));
+                            self.stack.push(cs.get(chunk.name(name))?);
                         }
-                        _ => panic!("Expected a struct!"),
+                        _ => return Err(format!("Expected a struct to get '{}' from!", chunk.name(name)).into()),
                     }
                 }
-                OpCode::NewStruct(name) => {
+                bytecode::NEW_STRUCT => {
+                    let name = self.read_u32();
                     self.stack.push(Value::Struct(
-                        self.chunk.functions.get_struct(name) 
+                        chunk.structs.get_struct(chunk.name(name))?
                     ));
                 }
-                OpCode::TransformToType(ctype) => {
-                    let val = self.stack_pop();
+                bytecode::TRANSFORM_TO_TYPE => {
+                    let ctype = &chunk.types[self.read_u32() as usize];
+                    let val = self.stack_pop()?;
                     match ctype {
                         ClassType::Any => self.stack.push(val),
-                        ClassType::Uint => self.stack.push(Value::Uint(val.uint_s())),
-                        ClassType::Int => self.stack.push(Value::Int(val.int_s())),
-                        ClassType::Decimal => self.stack.push(Value::Decimal(val.decimal_s())),
+                        ClassType::Uint => self.stack.push(Value::Uint(val.uint_s()?)),
+                        ClassType::Int => self.stack.push(Value::Int(val.int_s()?)),
+                        ClassType::Decimal => self.stack.push(Value::Decimal(val.decimal_s()?)),
                         ClassType::Str => self.stack.push(Value::Str(val.string_s())),
-                        ClassType::Bool => self.stack.push(Value::Bool(val.bool_s())),
-                        ClassType::Struct(x) => self.stack.push(Value::Struct(val.struct_s(x))),
+                        ClassType::Bool => self.stack.push(Value::Bool(val.bool_s()?)),
+                        ClassType::Struct(x) => self.stack.push(Value::Struct(val.struct_s(x.clone())?)),
                     }
                 }
-                OpCode::EndScript => {
-                    self.environment.entries.clear();
-                    return;
-                },
-                OpCode::Push(value) => { 
-                    self.stack.push(value.clone());
-                },
-                OpCode::Pop => match self.stack.pop() { _ => {} },
-                OpCode::Add => { 
-                    let (a, b, _) = self.binary_op_vals();
-                    self.stack.push(a.add_s(&b));  
-                },
-                OpCode::Sub => { 
-                    let (a, b, _) = self.binary_op_vals();
-                    self.stack.push(a.sub_s(&b));  
-                },
-                OpCode::Mul => { 
-                    let (a, b, _) = self.binary_op_vals();
-                    self.stack.push(a.mul_s(&b));  
-                },
-                OpCode::Div => { 
-                    let (a, b, _) = self.binary_op_vals();
-                    self.stack.push(a.div_s(&b));  
-                },
-                OpCode::CallFunc(func_name) => {
-                    let func = self.chunk.functions.get_function(func_name.clone());
-
-                    match func {
-                        EnvEntry::Function(_, ctype, count, bytecode_pos) => {
-                            if self.call_stack.len() != count {
-                                for i in &self.call_stack {
-                                    i.println();
-                                }
-                                panic!("Expected {} argument(s), but got {}", count, self.call_stack.len());
+                bytecode::END_SCRIPT => {
+                    // Globals are kept so the host can still call functions that use them.
+                    self.environment.remove_from_scope(1);
+                    return Ok(RunState::Finished);
+                },
+                bytecode::PUSH => {
+                    let index = self.read_u32(); 
+                    self.stack.push(chunk.constants[index as usize].clone());
+                },
+                bytecode::POP => { self.stack.pop(); },
+                bytecode::ADD => { 
+                    let (a, b, _) = self.binary_op_vals()?;
+                    self.stack.push(a.add_s(&b)?);  
+                },
+                bytecode::SUB => { 
+                    let (a, b, _) = self.binary_op_vals()?;
+                    self.stack.push(a.sub_s(&b)?);  
+                },
+                bytecode::MUL => { 
+                    let (a, b, _) = self.binary_op_vals()?;
+                    self.stack.push(a.mul_s(&b)?);  
+                },
+                bytecode::DIV => { 
+                    let (a, b, _) = self.binary_op_vals()?;
+                    self.stack.push(a.div_s(&b)?);  
+                },
+                bytecode::CALL => {
+                    let index = self.read_u32();
+                    match &chunk.functions[index as usize] {
+                        Function::Script(_, ctype, count, bytecode_pos) => {
+                            if self.call_stack.len() != *count {
+                                return Err(format!("Expected {} argument(s), but got {}", count, self.call_stack.len()).into());
+                            }
+
+                            if self.frames.len() >= self.max_call_depth {
+                                let message = format!("Went past the maximum call depth of {}", self.max_call_depth);
+                                return Err(RuntimeError::stack_overflow(message, self.backtrace()));
+                            }
+
+                            if self.stack.len() + self.call_stack.len() >= self.max_stack_size {
+                                let message = format!("Went past the maximum stack size of {}", self.max_stack_size);
+                                return Err(RuntimeError::stack_overflow(message, self.backtrace()));
                             }
 
-                            self.function_jump_back.push(self.idx);
-                            self.function_starting_scope.push(self.environment.current_scope+1);
-                            self.function_return_types.push(ctype);
+                            self.frames.push(CallFrame {
+                                function: index,
+                                return_position: self.idx,
+                                starting_scope: self.environment.current_scope + 1,
+                                return_type: ctype.clone(),
+                                locals_start: self.environment.locals.len(),
+                            });
 
-                            self.idx = bytecode_pos;
+                            // Functions are bound by where their op is, not their byte offset.
+                            self.idx = chunk.offsets[*bytecode_pos];
                         },
-                        EnvEntry::NativeFunction(_, count, func) => {
-                            if self.call_stack.len() != count {
-                                for i in &self.call_stack {
-                                    i.println();
-                                }
-                                panic!("Expected {} argument(s), but got {}", count, self.call_stack.len());
+                        Function::Native(_, count, func, suspends) => {
+                            if self.call_stack.len() != *count {
+                                return Err(format!("Expected {} argument(s), but got {}", count, self.call_stack.len()).into());
                             }
                             self.call_stack.reverse();
                             
-                            let return_value = func(self.call_stack.clone());
-                            self.call_stack.clear();
+                            let arguments = std::mem::take(&mut self.call_stack);
+                            let return_value = self.call_native(*func, arguments)?;
                             self.stack.push(return_value);
+
+                            if *suspends {
+                                return Ok(RunState::Yielded);
+                            }
                         },
-                        _ => panic!("Expected to get a function named '{}'!", func_name),
+                        Function::Unbound(name) => return Err(format!("The function '{}' was never bound!", name).into()),
                     }
                 },
-                OpCode::CmpLess => { 
+                bytecode::CMP_LESS => { 
                     binary_compare!(self, <, "less");
                 },
-                OpCode::CmpLessEqual => { 
+                bytecode::CMP_LESS_EQUAL => { 
                     binary_compare!(self, <=, "less or equal");
                 },
-                OpCode::CmpGreater => { 
+                bytecode::CMP_GREATER => { 
                     binary_compare!(self, >, "greater");
                 },
-                OpCode::CmpGreaterEqual => {
+                bytecode::CMP_GREATER_EQUAL => {
                     binary_compare!(self, >=, "greater or equal");
                 },
-                OpCode::CmpEqual => {
+                bytecode::CMP_EQUAL => {
                     binary_compare!(string, bool; self, ==, "equal");
                 },
-                OpCode::CmpNotEqual => {
+                bytecode::CMP_NOT_EQUAL => {
                     binary_compare!(string, bool; self, !=, "not equal");
                 },
-                OpCode::CmpAnd => {
-                    let (a, b, _) = self.binary_op_vals();
+                bytecode::CMP_AND => {
+                    let (a, b, _) = self.binary_op_vals()?;
 
-                    self.stack.push(Value::Bool(a.bool_s() && b.bool_s()));
+                    self.stack.push(Value::Bool(a.bool_s()? && b.bool_s()?));
                     
                 },
-                OpCode::CmpOr => {
-                    let (a, b, _) = self.binary_op_vals();
+                bytecode::CMP_OR => {
+                    let (a, b, _) = self.binary_op_vals()?;
 
-                    self.stack.push(Value::Bool(a.bool_s() || b.bool_s()));
+                    self.stack.push(Value::Bool(a.bool_s()? || b.bool_s()?));
                 },
-                OpCode::Jmp(idx) => {
+                bytecode::JMP => {
+                    let idx = self.read_u32() as usize;
                     self.idx = idx;
                 },
-                OpCode::JmpIfFalse(idx) => {
-                    if !self.stack_pop().bool_s() {
+                bytecode::JMP_IF_FALSE => {
+                    let idx = self.read_u32() as usize;
+                    if !self.stack_pop()?.bool_s()? {
                         self.idx = idx;
                     }
                 },
-                OpCode::Negate => {
-                    let val = self.stack_pop();
+                bytecode::NEGATE => {
+                    let val = self.stack_pop()?;
 
                     match val {
-                        Value::Uint(_) => panic!("Cannot negate a value under the type 'uint'."),
-                        Value::Int(x) => self.stack.push(Value::Int(-x)),
+                        Value::Uint(_) => return Err("Cannot negate a value under the type 'uint'.".into()),
+                        Value::Int(x) => match x.checked_neg() {
+                            Some(x) => self.stack.push(Value::Int(x)),
+                            None => return Err(format!("Cannot negate '{}' as the result doesn't fit in an 'int'.", x).into()),
+                        },
                         Value::Decimal(x) => self.stack.push(Value::Decimal(-x)),
-                        Value::Str(_) => panic!("Cannot negate a value under the type 'string'."),
-                        Value::Bool(_) => panic!("Cannot negate a value under the type 'bool'."),
-                        Value::Struct(x) => panic!("Cannot negate a value under the type 'struct {}'.", x.name),
-                        Value::None => panic!("Cannot negate a value under the type 'none'."),
+                        Value::Str(_) => return Err("Cannot negate a value under the type 'string'.".into()),
+                        Value::Bool(_) => return Err("Cannot negate a value under the type 'bool'.".into()),
+                        Value::Struct(x) => return Err(format!("Cannot negate a value under the type 'struct {}'.", x.name).into()),
+                        Value::Coroutine(_) => return Err("Cannot negate a value under the type 'coroutine'.".into()),
+                        Value::None => return Err("Cannot negate a value under the type 'none'.".into()),
                     }
                 },
-                OpCode::Not => {
-                    let val = self.stack_pop();
+                bytecode::NOT => {
+                    let val = self.stack_pop()?;
 
                     match val {
-                        Value::None => panic!("Cannot 'not' a value under the type 'none'."),
-                        Value::Uint(_) => self.stack.push(Value::Bool(!val.bool_s())),
-                        Value::Int(_) => self.stack.push(Value::Bool(!val.bool_s())),
-                        Value::Decimal(_) => self.stack.push(Value::Bool(!val.bool_s())),
-                        Value::Bool(_) => self.stack.push(Value::Bool(!val.bool_s())),
-                        Value::Struct(x) => panic!("Cannot 'not' a value under the type 'struct {}'.", x.name),
-                        Value::Str(_) => panic!("Cannot 'not' a value under the type 'string'."),
+                        Value::None => return Err("Cannot 'not' a value under the type 'none'.".into()),
+                        Value::Uint(_) => self.stack.push(Value::Bool(!val.bool_s()?)),
+                        Value::Int(_) => self.stack.push(Value::Bool(!val.bool_s()?)),
+                        Value::Decimal(_) => self.stack.push(Value::Bool(!val.bool_s()?)),
+                        Value::Bool(_) => self.stack.push(Value::Bool(!val.bool_s()?)),
+                        Value::Struct(x) => return Err(format!("Cannot 'not' a value under the type 'struct {}'.", x.name).into()),
+                        Value::Coroutine(_) => return Err("Cannot 'not' a value under the type 'coroutine'.".into()),
+                        Value::Str(_) => return Err("Cannot 'not' a value under the type 'string'.".into()),
                     }
                 }
-                OpCode::ArgumentStore(name, ctype) => {
-                    self.environment.add_variable(name, ctype, self.call_stack.pop().unwrap());
+                bytecode::ARGUMENT_STORE => {
+                    let name = self.read_u32();
+                    let ctype = &chunk.types[self.read_u32() as usize];
+                    let val = match self.call_stack.pop() {
+                        Some(val) => val,
+                        None => return Err(format!("Expected an argument for '{}'.", chunk.name(name)).into()),
+                    };
+
+                    self.environment.add_variable(chunk.name(name).to_string(), ctype.clone(), val)?;
                 },
-                OpCode::Store(name, ctype) => {
-                    let val = self.stack_pop();
-                    self.environment.add_variable(name, ctype, val);
+                bytecode::STORE => {
+                    let name = self.read_u32();
+                    let ctype = &chunk.types[self.read_u32() as usize];
+                    let val = self.stack_pop()?;
+                    self.environment.add_variable(chunk.name(name).to_string(), ctype.clone(), val)?;
                 },
-                OpCode::InferStore(name) => {
-                    let val = self.stack_pop();
-                    self.environment.add_infer_variable(name, val);
+                bytecode::INFER_STORE => {
+                    let name = self.read_u32();
+                    let val = self.stack_pop()?;
+                    self.environment.add_infer_variable(chunk.name(name).to_string(), val);
                 }
-                OpCode::Load(name) => {
-                    let entry = self.environment.get_variable(name);
+                bytecode::LOAD => {
+                    let name = self.read_u32();
+                    let entry = self.environment.get_variable(chunk.name(name), self.locals_start(), self.frame_scope())?;
                     
                     if let EnvEntry::Variable(_, value, _, _) = entry {
                         self.stack.push(value);
                     }
                 },
-                OpCode::Assign(name) => {
-                    let val = self.stack_pop();
-                    self.environment.assign_variable(name, val);
+                bytecode::ASSIGN => {
+                    let name = self.read_u32();
+                    let val = self.stack_pop()?;
+                    self.environment.assign_variable(chunk.name(name), val, self.locals_start(), self.frame_scope())?;
                 },
-                OpCode::PopToCall => {
-                    let value = self.stack_pop();
+                bytecode::POP_TO_CALL => {
+                    let value = self.stack_pop()?;
                     self.call_stack.push(value);
                 },
-                OpCode::StartScope => {
+                bytecode::START_SCOPE => {
                     self.environment.current_scope += 1;
                 },
-                OpCode::EndScope => {
+                bytecode::END_SCOPE => {
                     self.environment.remove_from_scope(self.environment.current_scope);
 
                     if self.environment.current_scope != 0 {
                         self.environment.current_scope -= 1;
                     }
                 },
+                bytecode::ARGUMENT_LOCAL => {
+                    let slot = self.read_u16();
+                    let name = self.read_u32();
+                    let ctype = &chunk.types[self.read_u32() as usize];
+                    let val = match self.call_stack.pop() {
+                        Some(val) => val,
+                        None => return Err(format!("Expected an argument for slot {}.", slot).into()),
+                    };
+
+                    let index = self.locals_start() + slot as usize;
+                    self.environment.define_local(index, chunk.name(name).to_string(), ctype.clone(), val)?;
+                },
+                bytecode::DEFINE_LOCAL => {
+                    let slot = self.read_u16();
+                    let name = self.read_u32();
+                    let ctype = &chunk.types[self.read_u32() as usize];
+                    let val = self.stack_pop()?;
+                    let index = self.locals_start() + slot as usize;
+                    self.environment.define_local(index, chunk.name(name).to_string(), ctype.clone(), val)?;
+                },
+                bytecode::INFER_LOCAL => {
+                    let slot = self.read_u16();
+                    let name = self.read_u32();
+                    let val = self.stack_pop()?;
+                    let index = self.locals_start() + slot as usize;
+                    self.environment.infer_local(index, chunk.name(name).to_string(), val)?;
+                },
+                bytecode::LOAD_LOCAL => {
+                    let slot = self.read_u16();
+                    let val = self.environment.load_local(self.locals_start() + slot as usize)?;
+                    self.stack.push(val);
+                },
+                bytecode::STORE_LOCAL => {
+                    let slot = self.read_u16();
+                    let val = self.stack_pop()?;
+                    self.environment.assign_local(self.locals_start() + slot as usize, val)?;
+                },
+                bytecode::DEFINE_GLOBAL => {
+                    let index = self.read_u32();
+                    let ctype = &chunk.types[self.read_u32() as usize];
+                    let val = self.stack_pop()?;
+                    self.environment.define_global(index as usize, chunk.globals[index as usize].clone(), ctype.clone(), val)?;
+                },
+                bytecode::INFER_GLOBAL => {
+                    let index = self.read_u32();
+                    let val = self.stack_pop()?;
+                    self.environment.infer_global(index as usize, chunk.globals[index as usize].clone(), val)?;
+                },
+                bytecode::LOAD_GLOBAL => {
+                    let index = self.read_u32();
+                    let val = self.environment.load_global(index as usize, &chunk.globals[index as usize])?;
+                    self.stack.push(val);
+                },
+                bytecode::STORE_GLOBAL => {
+                    let index = self.read_u32();
+                    let val = self.stack_pop()?;
+                    self.environment.assign_global(index as usize, &chunk.globals[index as usize], val)?;
+                },
+                bytecode::TRY_START => {
+                    let catch_position = self.read_u32() as usize;
+                    self.handlers.push(ExceptionHandler {
+                        catch_position,
+                        stack_size: self.stack.len(),
+                        call_stack_size: self.call_stack.len(),
+                        locals_size: self.environment.locals.len(),
+                        function_depth: self.frames.len(),
+                        scope: self.environment.current_scope,
+                    });
+                },
+                bytecode::TRY_END => {
+                    self.handlers.pop();
+                },
+                bytecode::THROW => {
+                    let value = self.stack_pop()?;
+                    return Err(RuntimeError::thrown(value));
+                },
+                bytecode::YIELD => return Ok(RunState::Yielded),
+                bytecode::MAKE_COROUTINE => {
+                    let mut frame = match self.frames.pop() {
+                        Some(frame) => frame,
+                        None => return Err("Can only start a coroutine by calling a generator.".into()),
+                    };
+
+                    let name = chunk.functions[frame.function as usize].name().to_string();
+                    let return_position = frame.return_position;
+
+                    // The call starts off on its own, with nothing under it in its context.
+                    frame.starting_scope = 1;
+                    frame.locals_start = 0;
+
+                    let context = Context {
+                        idx: self.idx,
+                        call_stack: mem::take(&mut self.call_stack),
+                        frames: vec![frame],
+                        ..Context::default()
+                    };
+
+                    self.idx = return_position;
+                    self.stack.push(Value::Coroutine(CoroutineRef::new(Coroutine { name, state: CoroutineState::Suspended, context })));
+                },
+                bytecode::RESUME => {
+                    let coroutine = self.pop_coroutine()?;
+                    self.resume_coroutine(coroutine, None)?;
+                },
+                bytecode::YIELD_VALUE => {
+                    let value = self.stack_pop()?;
+                    self.leave_coroutine(CoroutineState::Suspended)?;
+                    self.stack.push(value);
+                },
+                bytecode::FOR_ITER => {
+                    let exit = self.read_u32() as usize;
+                    let coroutine = self.pop_coroutine()?;
+
+                    if coroutine.state() == CoroutineState::Finished {
+                        self.idx = exit;
+                    } else {
+                        self.resume_coroutine(coroutine, Some(exit))?;
+                    }
+                },
+                bytecode::IS_FINISHED => {
+                    let coroutine = self.pop_coroutine()?;
+                    self.stack.push(Value::Bool(coroutine.state() == CoroutineState::Finished));
+                },
+                byte => return Err(format!("Unknown opcode byte '{}'.", byte).into()),
             }
         }
     }
@@ -0,0 +1,65 @@
+#![allow(clippy::needless_return)]
+
+use copper::chunk::{Chunk, OpCode};
+use copper::value::{ClassType, Value};
+
+fn chunk(code: Vec<OpCode>) -> Chunk {
+    let mut chunk = Chunk::new();
+    for op in code {
+        chunk.write(op, 1);
+    }
+
+    return chunk;
+}
+
+fn rejected(chunk: &Chunk, message: &str) {
+    match chunk.verify() {
+        Ok(_) => panic!("Expected the chunk to be rejected with '{}'", message),
+        Err(err) => assert!(err.contains(message), "Expected '{}' in '{}'", message, err),
+    }
+}
+
+#[test]
+fn accepts_balanced_scopes_and_handlers() {
+    let chunk = chunk(vec![
+        OpCode::StartScope, OpCode::TryStart(4), OpCode::TryEnd, OpCode::Jmp(5),
+        OpCode::Pop, OpCode::EndScope, OpCode::EndScript,
+    ]);
+    assert_eq!(chunk.verify(), Ok(()));
+}
+
+#[test]
+fn rejects_scope_left_open_at_end_of_script() {
+    let chunk = chunk(vec![OpCode::StartScope, OpCode::EndScript]);
+    rejected(&chunk, "1 scope(s) that were never ended");
+}
+
+#[test]
+fn rejects_scope_left_open_at_return() {
+    let mut chunk = chunk(vec![OpCode::EndScript, OpCode::StartScope, OpCode::Push(0), OpCode::Return]);
+    chunk.add_constant(Value::None);
+    chunk.bind_function("leaky".to_string(), ClassType::Any, 0, 1);
+
+    rejected(&chunk, "1 scope(s) that were never ended");
+}
+
+#[test]
+fn rejects_handler_left_open_at_end_of_script() {
+    let chunk = chunk(vec![OpCode::TryStart(2), OpCode::EndScript, OpCode::Pop, OpCode::EndScript]);
+    rejected(&chunk, "1 exception handler(s) that were never ended");
+}
+
+#[test]
+fn rejects_handler_left_open_at_return() {
+    let mut chunk = chunk(vec![OpCode::EndScript, OpCode::TryStart(4), OpCode::Push(0), OpCode::Return, OpCode::Pop, OpCode::Push(0), OpCode::Return]);
+    chunk.add_constant(Value::None);
+    chunk.bind_function("leaky".to_string(), ClassType::Any, 0, 1);
+
+    rejected(&chunk, "1 exception handler(s) that were never ended");
+}
+
+#[test]
+fn rejects_handler_ended_without_being_started() {
+    let chunk = chunk(vec![OpCode::TryEnd, OpCode::EndScript]);
+    rejected(&chunk, "Ends an exception handler that was never started");
+}